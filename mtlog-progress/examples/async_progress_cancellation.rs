@@ -0,0 +1,33 @@
+use mtlog_tokio::logger_config;
+use mtlog_progress::LogProgressBar;
+
+#[tokio::main]
+async fn main() {
+    logger_config()
+        .with_log_file("/tmp/log_progress_cancellation.log").unwrap()
+        .scope_global(async move {
+            let work = |name: &'static str, stalled_at: Option<u64>| logger_config()
+                .scope_local(async move {
+                    let pb = LogProgressBar::new(100, name).guard();
+                    for i in 0..100 {
+                        if Some(i) == stalled_at {
+                            // Never resumed, until cancelled
+                            std::future::pending::<()>().await;
+                        }
+                        pb.inc(1);
+                        tokio::task::yield_now().await;
+                    }
+                    pb.finish();
+                });
+            work("Completed", None).await;
+            let cancelled = tokio::time::timeout(std::time::Duration::from_millis(20), work("Cancelled", Some(50))).await;
+            assert!(cancelled.is_err());
+            log::info!("Cancelled work was abandoned");
+        }).await;
+    let content = std::fs::read_to_string("/tmp/log_progress_cancellation.log").unwrap();
+    let lines = content.trim_end().lines().collect::<Vec<&str>>();
+    let lines = &lines[lines.len()-4..];
+    assert!(lines[0].ends_with("100/100 100%"));
+    assert!(lines[2].contains("Progress Cancelled: ABANDONED at "));
+    assert!(lines[3].ends_with("Cancelled work was abandoned"));
+}
@@ -0,0 +1,43 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use mtlog::logger_config;
+use mtlog_progress::{LogProgressBar, MtlogDrawTarget};
+
+
+fn main() {
+    mtlog::test_mode();
+    let path = "/tmp/log_indicatif_bridge.log";
+    let handle = logger_config()
+        .with_log_file_truncate(path).unwrap()
+        .init_global_with_handle();
+    log::info!("Hello, Top !");
+    let indicatif_thread = std::thread::spawn(move || {
+        let pb = ProgressBar::hidden();
+        pb.set_length(100);
+        pb.set_style(ProgressStyle::with_template("Indicatif: [{bar:20}] {pos}/{len}").unwrap().progress_chars("#."));
+        MtlogDrawTarget::attach(&pb);
+        for _ in 0..100 {
+            pb.inc(1);
+        }
+        pb.finish();
+    });
+    indicatif_thread.join().unwrap();
+    log::info!("Hello, Middle !");
+    let mtlog_thread = std::thread::spawn(move || {
+        let pb = LogProgressBar::new(100, "Mtlog");
+        for _ in 0..100 {
+            pb.inc(1);
+        }
+        pb.finish();
+    });
+    mtlog_thread.join().unwrap();
+    log::info!("Hello, Bottom !");
+    handle.flush();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines = content.lines().map(str::trim_end).collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].ends_with("Hello, Top !"));
+    assert!(lines[1].ends_with("Indicatif: [####################] 100/100"));
+    assert!(lines[2].ends_with("Hello, Middle !"));
+    assert!(lines[3].ends_with("100/100 100%"));
+    assert!(lines[4].ends_with("Hello, Bottom !"));
+}
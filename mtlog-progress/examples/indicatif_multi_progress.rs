@@ -0,0 +1,42 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use mtlog::logger_config;
+use mtlog_progress::LogProgressBar;
+
+
+fn main() {
+    let path = "/tmp/log_indicatif_multi_progress.log";
+    let multi = MultiProgress::new();
+    let handle = logger_config()
+        .with_multi_progress(multi.clone())
+        .with_log_file_truncate(path).unwrap()
+        .init_global_with_handle();
+    let indicatif_bar = multi.add(ProgressBar::new(100));
+    indicatif_bar.set_style(ProgressStyle::with_template("Indicatif: [{bar:20}] {pos}/{len}").unwrap().progress_chars("#."));
+    let indicatif_thread = std::thread::spawn(move || {
+        for _ in 0..100 {
+            indicatif_bar.inc(1);
+        }
+        indicatif_bar.finish();
+    });
+    log::info!("Hello, Top !");
+    let mtlog_thread = std::thread::spawn(move || {
+        let pb = LogProgressBar::new(100, "Mtlog");
+        for i in 0..100 {
+            pb.inc(1);
+            if i % 25 == 0 {
+                log::info!("Step {i}");
+            }
+        }
+        pb.finish();
+    });
+    indicatif_thread.join().unwrap();
+    mtlog_thread.join().unwrap();
+    log::info!("Hello, Bottom !");
+    handle.flush();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines = content.lines().map(str::trim_end).collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 7);
+    assert!(lines[0].ends_with("Hello, Top !"));
+    assert!(lines[1].ends_with("100/100 100%"));
+    assert!(lines[6].ends_with("Hello, Bottom !"));
+}
@@ -3,30 +3,26 @@ use mtlog_progress::LogProgressBar;
 
 
 fn main() {
-    logger_config()
+    let handle = logger_config()
         .with_log_file("/tmp/log_with_progress.log").unwrap()
-        .init_global();
+        .init_global_with_handle();
     log::info!("Hello, Top !");
     std::thread::spawn(move || {
         let pb = LogProgressBar::new(100, "Thread1");
         for _ in 0..50 {
             pb.inc(1);
-            std::thread::sleep(std::time::Duration::from_millis(1));
         }
-    });
-    std::thread::sleep(std::time::Duration::from_millis(2));
+    }).join().unwrap();
     log::info!("Hello, Middle !");
     std::thread::spawn(move || {
         let pb = LogProgressBar::new(100, "Thread1");
         for _ in 0..100 {
             pb.inc(1);
-            std::thread::sleep(std::time::Duration::from_millis(1));
         }
         pb.finish();
-    });
-    std::thread::sleep(std::time::Duration::from_millis(2));
+    }).join().unwrap();
     log::info!("Hello, Bottom !");
-    std::thread::sleep(std::time::Duration::from_millis(200));
+    handle.flush();
     let content = std::fs::read_to_string("/tmp/log_with_progress.log").unwrap();
     let mut lines = content.trim_end().lines().collect::<Vec<&str>>();
     lines = lines[lines.len()-5..].to_vec();
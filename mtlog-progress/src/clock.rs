@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+/// Monotonic time source of a progress bar, see [`LogProgressBar::with_clock`](crate::LogProgressBar::with_clock).
+pub trait ProgressClock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl ProgressClock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Time constant of the rate smoothing: older samples weigh `1/e` after this long.
+const RATE_SMOOTHING: Duration = Duration::from_secs(5);
+
+/// Smoothed progress rate, as an exponential moving average weighted by the time between samples.
+#[derive(Debug, Clone)]
+pub(crate) struct RateEstimator {
+    start: Instant,
+    last_time: Instant,
+    last_position: usize,
+    rate: Option<f64>,
+}
+
+impl RateEstimator {
+    /// Starts measuring from `position` at `now`.
+    pub(crate) fn new(position: usize, now: Instant) -> Self {
+        Self { start: now, last_time: now, last_position: position, rate: None }
+    }
+
+    pub(crate) fn elapsed(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.start)
+    }
+
+    /// Records the position reached at `now`.
+    ///
+    /// Updates closer than the resolution of the clock are merged into the next sample, and a
+    /// position moving backwards restarts the measure from it.
+    pub(crate) fn update(&mut self, position: usize, now: Instant) {
+        if position < self.last_position {
+            self.last_position = position;
+            self.last_time = now;
+            return
+        }
+        let dt = now.saturating_duration_since(self.last_time).as_secs_f64();
+        if dt == 0.0 {
+            return
+        }
+        let sample = (position - self.last_position) as f64 / dt;
+        self.rate = Some(match self.rate {
+            None => sample,
+            Some(rate) => {
+                let weight = 1.0 - (-dt / RATE_SMOOTHING.as_secs_f64()).exp();
+                rate + weight * (sample - rate)
+            }
+        });
+        self.last_position = position;
+        self.last_time = now;
+    }
+
+    /// Smoothed rate in iterations per second, `None` before any time has elapsed.
+    ///
+    /// The time since the last sample counts as a sample without progress, so that the rate
+    /// decays during a stall instead of keeping its last value.
+    pub(crate) fn rate(&self, now: Instant) -> Option<f64> {
+        let idle = now.saturating_duration_since(self.last_time).as_secs_f64();
+        self.rate.map(|rate| rate * (-idle / RATE_SMOOTHING.as_secs_f64()).exp())
+    }
+
+    /// Estimated time to reach `n_iter` from `position`, `None` while the rate is unknown or zero.
+    pub(crate) fn eta(&self, position: usize, n_iter: usize, now: Instant) -> Option<Duration> {
+        let remaining = n_iter.saturating_sub(position);
+        if remaining == 0 {
+            return Some(Duration::ZERO)
+        }
+        let rate = self.rate(now).filter(|rate| *rate > 0.0)?;
+        Duration::try_from_secs_f64(remaining as f64 / rate).ok()
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct FakeClock(std::sync::Mutex<Instant>);
+
+#[cfg(test)]
+impl FakeClock {
+    pub(crate) fn new() -> Self {
+        Self(std::sync::Mutex::new(Instant::now()))
+    }
+    pub(crate) fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl ProgressClock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn test_rate_edge_cases() {
+    let clock = FakeClock::new();
+    let mut estimator = RateEstimator::new(0, clock.now());
+    // Zero elapsed: a burst faster than the clock resolution gives no rate yet
+    estimator.update(5, clock.now());
+    estimator.update(10, clock.now());
+    assert_eq!(estimator.rate(clock.now()), None);
+    assert_eq!(estimator.eta(10, 100, clock.now()), None);
+    // The burst is accounted for at the next tick
+    clock.advance(Duration::from_secs(1));
+    estimator.update(10, clock.now());
+    assert_eq!(estimator.rate(clock.now()), Some(10.0));
+    assert_eq!(estimator.eta(100, 100, clock.now()), Some(Duration::ZERO));
+    // Zero progress
+    let mut estimator = RateEstimator::new(0, clock.now());
+    clock.advance(Duration::from_secs(1));
+    estimator.update(0, clock.now());
+    assert_eq!(estimator.rate(clock.now()), Some(0.0));
+    assert_eq!(estimator.eta(0, 100, clock.now()), None);
+    assert_eq!(estimator.elapsed(clock.now()), Duration::from_secs(1));
+}
+
+#[test]
+fn test_rate_constant() {
+    let clock = FakeClock::new();
+    let mut estimator = RateEstimator::new(0, clock.now());
+    for step in 1..=10 {
+        clock.advance(Duration::from_secs(1));
+        estimator.update(10 * step, clock.now());
+    }
+    assert!((estimator.rate(clock.now()).unwrap() - 10.0).abs() < 1e-9);
+    let eta = estimator.eta(100, 150, clock.now()).unwrap();
+    assert!((eta.as_secs_f64() - 5.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_rate_accelerating() {
+    let clock = FakeClock::new();
+    let mut estimator = RateEstimator::new(0, clock.now());
+    let mut position = 0;
+    for _ in 0..5 {
+        clock.advance(Duration::from_secs(1));
+        position += 10;
+        estimator.update(position, clock.now());
+    }
+    // The rate follows the acceleration smoothly, without overshooting it
+    let mut previous = estimator.rate(clock.now()).unwrap();
+    for _ in 0..20 {
+        clock.advance(Duration::from_secs(1));
+        position += 20;
+        estimator.update(position, clock.now());
+        let rate = estimator.rate(clock.now()).unwrap();
+        assert!(rate > previous && rate < 20.0);
+        previous = rate;
+    }
+    assert!(previous > 19.0);
+}
+
+#[test]
+fn test_rate_stall_and_resume() {
+    let clock = FakeClock::new();
+    let mut estimator = RateEstimator::new(0, clock.now());
+    for step in 1..=10 {
+        clock.advance(Duration::from_secs(1));
+        estimator.update(10 * step, clock.now());
+    }
+    // Long stall: the rate decays and the ETA grows without any update
+    clock.advance(Duration::from_secs(60));
+    assert!(estimator.rate(clock.now()).unwrap() < 0.01);
+    assert!(estimator.eta(100, 200, clock.now()).unwrap() > Duration::from_secs(3600));
+    // Resumed progress: the rate recovers its previous value
+    clock.advance(Duration::from_secs(1));
+    estimator.update(110, clock.now());
+    let resumed = estimator.rate(clock.now()).unwrap();
+    for step in 2..=30 {
+        clock.advance(Duration::from_secs(1));
+        estimator.update(100 + 10 * step, clock.now());
+    }
+    let recovered = estimator.rate(clock.now()).unwrap();
+    assert!(resumed < recovered && recovered > 9.5);
+}
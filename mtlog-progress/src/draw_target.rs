@@ -0,0 +1,222 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, LazyLock, Mutex, OnceLock};
+use std::io;
+use indicatif::{ProgressBar, ProgressDrawTarget, TermLike, WeakProgressBar};
+use uuid::Uuid;
+
+use crate::senders::Senders;
+
+/// Width reported to indicatif, used to lay out templates with a `wide_bar` or `wide_msg`.
+const WIDTH: u16 = 80;
+
+/// Terminal forwarding the frames drawn by an indicatif bar to mtlog's progress protocol, so that
+/// indicatif bars share the line management of mtlog's terminal writer and are written to log files.
+///
+/// Each target is keyed by its own id: use one target per indicatif bar. A bar given to
+/// [`MtlogDrawTarget::attach`] is marked as finished as soon as indicatif draws its finished state,
+/// otherwise when the target is dropped with it. The last frame drawn stays as is.
+///
+/// ```rust
+/// use indicatif::ProgressBar;
+/// use mtlog_progress::MtlogDrawTarget;
+///
+/// let pb = ProgressBar::hidden();
+/// pb.set_length(100);
+/// MtlogDrawTarget::attach(&pb);
+/// pb.inc(100);
+/// pb.finish();
+/// ```
+pub struct MtlogDrawTarget {
+    id: Uuid,
+    /// Lines written since the last flush, the last one being open.
+    frame: Mutex<Vec<String>>,
+    /// Bar drawn by the target when attached, checked for the finished state after each frame.
+    bar: OnceLock<WeakProgressBar>,
+    /// Senders of the last frame, which the finished state is sent to.
+    senders: Mutex<Option<Senders>>,
+    /// Whether FINISHED was sent, by the watcher or on drop.
+    finished: Arc<AtomicBool>,
+}
+
+/// Finished state check of an attached bar, run by the watcher once the draw that queued it has
+/// released the state of the bar.
+struct Check {
+    bar: WeakProgressBar,
+    id: Uuid,
+    senders: Senders,
+    finished: Arc<AtomicBool>,
+}
+
+/// Thread checking attached bars after their frames: indicatif draws with the state of the bar
+/// locked, so the target cannot query it from `flush`.
+static WATCHER: LazyLock<Mutex<mpsc::Sender<Check>>> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::channel::<Check>();
+    std::thread::spawn(move || {
+        for check in receiver {
+            let finished = check.bar.upgrade().is_some_and(|bar| bar.is_finished());
+            if finished && !check.finished.swap(true, Ordering::SeqCst) {
+                check.senders.finish_progress(check.id);
+            }
+        }
+    });
+    Mutex::new(sender)
+});
+
+impl std::fmt::Debug for MtlogDrawTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MtlogDrawTarget").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+impl Default for MtlogDrawTarget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MtlogDrawTarget {
+    pub fn new() -> Self {
+        Self {
+            id: crate::new_id(),
+            frame: Mutex::new(vec![String::new()]),
+            bar: OnceLock::new(),
+            senders: Mutex::new(None),
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets a new target as the draw target of `bar`, marking the bar as finished as soon as
+    /// indicatif draws its finished state, after `finish`, `abandon` and their variants.
+    pub fn attach(bar: &ProgressBar) {
+        let target = Self::new();
+        let _ = target.bar.set(bar.downgrade());
+        bar.set_draw_target(target.into_draw_target());
+    }
+
+    /// Wraps the target for `ProgressBar::with_draw_target` or `ProgressBar::set_draw_target`. The
+    /// bar is then only marked as finished when dropped, see [`MtlogDrawTarget::attach`].
+    pub fn into_draw_target(self) -> ProgressDrawTarget {
+        ProgressDrawTarget::term_like(Box::new(self))
+    }
+}
+
+impl TermLike for MtlogDrawTarget {
+    fn width(&self) -> u16 {
+        WIDTH
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        let mut frame = self.frame.lock().unwrap();
+        frame.last_mut().unwrap().push_str(s);
+        frame.push(String::new());
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        let mut frame = self.frame.lock().unwrap();
+        let line = frame.last_mut().unwrap();
+        match s.rsplit_once('\r') {
+            Some((_, s)) => *line = s.into(),
+            None => line.push_str(s),
+        }
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        self.frame.lock().unwrap().last_mut().unwrap().clear();
+        Ok(())
+    }
+
+    /// Sends the lines drawn since the last flush as a frame, joined on a single line and padded to
+    /// the width as indicatif does on a terminal, so that each frame fully covers the previous one
+    /// when rewritten in place.
+    fn flush(&self) -> io::Result<()> {
+        let frame = std::mem::replace(&mut *self.frame.lock().unwrap(), vec![String::new()]);
+        let text = frame.iter().map(|line| line.trim_end()).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" ");
+        let Some(senders) = Senders::current().filter(|_| !text.is_empty()) else {
+            return Ok(());
+        };
+        senders.send_progress(self.id, &format!("{text:<width$}", width = WIDTH as usize));
+        if let Some(bar) = self.bar.get() {
+            let check = Check { bar: bar.clone(), id: self.id, senders: senders.clone(), finished: self.finished.clone() };
+            let _ = WATCHER.lock().unwrap().send(check);
+        }
+        *self.senders.lock().unwrap() = Some(senders);
+        Ok(())
+    }
+}
+
+impl Drop for MtlogDrawTarget {
+    fn drop(&mut self) {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(senders) = self.senders.lock().unwrap().take().or_else(Senders::current) {
+            senders.finish_progress(self.id);
+        }
+    }
+}
+
+#[test]
+fn test_mtlog_draw_target() {
+    use indicatif::{ProgressBar, ProgressStyle};
+    let lines = mtlog::test_util::capture_logs(|| {
+        let pb = ProgressBar::with_draw_target(Some(4), MtlogDrawTarget::new().into_draw_target());
+        pb.set_style(ProgressStyle::with_template("indicatif {pos}/{len}").unwrap());
+        pb.inc(2);
+        log::info!("between");
+        pb.inc(2);
+        pb.finish();
+    });
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].trim_end().ends_with("indicatif 4/4"));
+    assert!(lines[1].ends_with("] between"));
+}
+
+#[test]
+fn test_attached_draw_target_finished() {
+    use indicatif::ProgressStyle;
+    use mtlog::{logger_config, LogWriter};
+    struct Finished(mpsc::Sender<Uuid>);
+    impl LogWriter for Finished {
+        fn regular(&mut self, _: &str) {
+            self.0.send(Uuid::nil()).unwrap();
+        }
+        fn progress(&mut self, _: &str, _: Uuid) {}
+        fn finished(&mut self, id: Uuid) {
+            self.0.send(id).unwrap();
+        }
+    }
+    let (sender, receiver) = mpsc::channel();
+    logger_config()
+        .no_file()
+        .no_stdout()
+        .with_writer(Finished(sender))
+        .init_local();
+    let pb = ProgressBar::hidden();
+    pb.set_length(4);
+    pb.set_style(ProgressStyle::with_template("indicatif {pos}/{len}").unwrap());
+    MtlogDrawTarget::attach(&pb);
+    pb.inc(2);
+    pb.finish();
+    // Marked as finished while the bar is still alive, and not again when dropped
+    assert_ne!(receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), Uuid::nil());
+    drop(pb);
+    log::info!("dropped");
+    assert_eq!(receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), Uuid::nil());
+}
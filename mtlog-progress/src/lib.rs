@@ -39,7 +39,7 @@
 //! // Cargo.toml
 //! ...
 //! [dependencies]
-//! mtlog-progress = "0.1.0"
+//! mtlog-progress = { version = "0.1.0", default-features = false, features = ["tokio"] }
 //! mtlog-tokio = "0.1.0"
 //! tokio = { version = "1.40.0", features = ["full"] }
 //! ```
@@ -70,86 +70,600 @@
 //!         }).await;
 //! }
 //! ```
+//! ## Bars drawn by indicatif
+//! With the `indicatif` feature, `MtlogDrawTarget::attach` forwards the frames of an indicatif bar to
+//! the logger, so that indicatif bars and [`LogProgressBar`]s can be mixed.
+//! Applications keeping an indicatif `MultiProgress` for their UI can instead enable the `indicatif`
+//! feature of mtlog and pass it to `ConfigBuilder::with_multi_progress`: logs are then printed with the
+//! indicatif bars suspended, and [`LogProgressBar`]s are drawn as bars of the `MultiProgress`.
+
 
+mod clock;
+#[cfg(feature = "indicatif")]
+mod draw_target;
+mod senders;
 
-use std::sync::{Arc, Mutex};
+#[cfg(feature = "indicatif")]
+pub use draw_target::MtlogDrawTarget;
+use std::{cell::{Cell, RefCell}, ops::Deref, sync::{atomic::{AtomicUsize, Ordering}, mpsc::Sender, Arc, Mutex, Once}, time::Duration};
 use colored::Colorize;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use uuid::Uuid;
 
+use clock::RateEstimator;
+pub use clock::{ProgressClock, SystemClock};
+use senders::Senders;
+
+/// Guards the one-time diagnostic emitted when a bar is created before any logger is installed.
+static NO_LOGGER_NOTICE: Once = Once::new();
+
+thread_local! {
+    /// Generator of the ids of the bars created by the thread, see [`with_id_generator`].
+    static ID_GENERATOR: RefCell<Option<Box<dyn FnMut() -> Uuid>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with the bars created by the current thread taking their ids from `generator`
+/// instead of random ones, e.g. [`Uuid::from_u128`] over a counter, so that snapshots of the raw
+/// message stream are stable. The previous generator is restored afterwards, even if `f` panics.
+pub fn with_id_generator<R>(generator: impl FnMut() -> Uuid + 'static, f: impl FnOnce() -> R) -> R {
+    /// Generator of the thread before the call.
+    struct RestoreGenerator(Option<Box<dyn FnMut() -> Uuid>>);
+    impl Drop for RestoreGenerator {
+        fn drop(&mut self) {
+            ID_GENERATOR.with(|id_generator| *id_generator.borrow_mut() = self.0.take());
+        }
+    }
+    let previous = ID_GENERATOR.with(|id_generator| id_generator.borrow_mut().replace(Box::new(generator)));
+    let _restore = RestoreGenerator(previous);
+    f()
+}
+
+/// Id of a new bar, from the generator of the thread if any.
+pub(crate) fn new_id() -> Uuid {
+    ID_GENERATOR.with(|id_generator| id_generator.borrow_mut().as_mut().map_or_else(Uuid::new_v4, |generate| generate()))
+}
+
+/// Partial cells of smooth bars, from 1/8 to 7/8 of a cell.
+const EIGHTHS: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+/// Characters drawing the bar of a [`LogProgressBar`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarStyle {
+    pub filled: char,
+    pub empty: char,
+    /// Drawn at the head of an incomplete bar, e.g. `>` for `###>....`.
+    pub edge: Option<char>,
+    /// Draw the head as an eighth-block (`▏` to `▉`), for a resolution of 1/8 of a cell.
+    pub smooth: bool,
+}
+
+impl BarStyle {
+    /// `[#####...............]`, the default, fit for any terminal or viewer.
+    pub fn ascii() -> Self {
+        Self { filled: '#', empty: '.', edge: None, smooth: false }
+    }
 
+    /// `[████▌               ]`, with block characters and eighth-block smoothing. Empty cells are
+    /// figure spaces (U+2007), as wide as a space but encoded on as many bytes as a block, so that
+    /// every frame has the same size when rewritten in place in log files.
+    pub fn unicode() -> Self {
+        Self { filled: '█', empty: '\u{2007}', edge: None, smooth: true }
+    }
+
+    /// Number of bytes of the longest bar of `length` cells.
+    fn max_len(&self, length: usize) -> usize {
+        let heads = self.edge.into_iter().chain(self.smooth.then_some(EIGHTHS).into_iter().flatten());
+        let cell = [self.filled, self.empty].into_iter().chain(heads).map(char::len_utf8).max().unwrap_or(1);
+        cell * length
+    }
+
+    /// Bar of `length` cells at `current` out of `n_iter`.
+    fn render(&self, current: usize, n_iter: usize, length: usize) -> String {
+        let eighths = (8 * length * current / n_iter).min(8 * length);
+        let (filled, partial) = if self.smooth { (eighths / 8, eighths % 8) } else { (eighths / 8, 0) };
+        let mut bar = self.filled.to_string().repeat(filled);
+        if filled < length {
+            match self.edge {
+                Some(edge) => bar.push(edge),
+                None if partial > 0 => bar.push(EIGHTHS[partial - 1]),
+                None => bar.push(self.empty),
+            }
+            bar.push_str(&self.empty.to_string().repeat(length - filled - 1));
+        }
+        bar
+    }
+}
+
+impl Default for BarStyle {
+    fn default() -> Self {
+        Self::ascii()
+    }
+}
+
+/// State of a bar sent at each update to the channel set by [`LogProgressBar::with_event_channel`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub id: Uuid,
+    pub name: String,
+    pub current: usize,
+    pub total: usize,
+    pub finished: bool,
+}
+
+/// Number of live clones of a bar, shared by them.
+struct Clones(Arc<AtomicUsize>);
+
+impl Clone for Clones {
+    fn clone(&self) -> Self {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Self(self.0.clone())
+    }
+}
+
+impl Clones {
+    /// Releases a clone, returning whether it was the last one.
+    fn release(&self) -> bool {
+        self.0.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+}
+
+/// A progress bar rendered through the `log` facade.
+///
+/// Frames are sent at each update to the sinks of the configuration of mtlog or mtlog-tokio active
+/// at the time of the update, so a bar created before `init_global` (or in another scope) is
+/// written to the sinks configured afterwards.
 #[derive(Clone)]
 pub struct LogProgressBar {
-    n_iter: Arc<usize>,
+    n_iter: Arc<Mutex<usize>>,
     name: Arc<str>,
     current_iter: Arc<Mutex<usize>>,
     id: Arc<Uuid>,
-    finished: Arc<Mutex<bool>>
+    finished: Arc<Mutex<bool>>,
+    clock: Arc<dyn ProgressClock>,
+    rate: Arc<Mutex<RateEstimator>>,
+    name_width: usize,
+    /// Number of ancestors of the bar, its frames being indented by two spaces per ancestor.
+    depth: usize,
+    /// Byte length of the longest frame sent, shorter frames being padded to it.
+    frame_len: Arc<Mutex<usize>>,
+    style: BarStyle,
+    events: Option<Sender<ProgressEvent>>,
+    /// Whether frames are logged, or only sent as events.
+    log_frames: bool,
+    /// Whether logged frames are prefixed with the name of the logger they are sent to.
+    scope_prefix: bool,
+    clones: Clones,
 }
 
 impl LogProgressBar {
+    /// Creates a bar of `n_iter` iterations. A bar of 0 iterations has nothing to do: it is rendered
+    /// complete, as `0/0 100% (nothing to do)`, and finished right away.
     pub fn new(n_iter: usize, name: &str) -> Self {
+        Self::new_aligned(n_iter, name, 0)
+    }
+
+    /// Like [`LogProgressBar::new`], the name taking exactly `name_width` terminal columns so that
+    /// the bars created with the same width are aligned, including names with wide characters
+    /// (e.g. CJK or emoji) taking two columns each. Longer names are truncated with `…`.
+    pub fn new_aligned(n_iter: usize, name: &str, name_width: usize) -> Self {
+        Self::new_nested(n_iter, name, name_width, 0, BarStyle::default(), false)
+    }
+
+    /// Starts a bar of `n_iter` iterations whose options are set before its first frame is sent.
+    ///
+    /// ```rust
+    /// use mtlog_progress::{BarStyle, LogProgressBar};
+    ///
+    /// let pb = LogProgressBar::builder(100, "upload").style(BarStyle::unicode()).build();
+    /// pb.inc(1);
+    /// ```
+    pub fn builder(n_iter: usize, name: &str) -> LogProgressBarBuilder {
+        LogProgressBarBuilder { n_iter, name: name.into(), name_width: 0, style: BarStyle::default(), scope_prefix: false }
+    }
+
+    /// Creates a bar nested in `parent`, e.g. for the inner loop of nested loops: its frames are
+    /// indented one level deeper than the parent's, with the name width, style and scope prefix
+    /// of the parent.
+    pub fn child_of(parent: &LogProgressBar, n_iter: usize, name: &str) -> Self {
+        Self::new_nested(n_iter, name, parent.name_width, parent.depth + 1, parent.style, parent.scope_prefix)
+    }
+
+    fn new_nested(n_iter: usize, name: &str, name_width: usize, depth: usize, style: BarStyle, scope_prefix: bool) -> Self {
+        if !Senders::installed() {
+            NO_LOGGER_NOTICE.call_once(|| {
+                eprintln!("mtlog-progress: progress bar \"{name}\" created before any logger was initialized, frames are dropped until one is configured");
+            });
+        }
         let pb = Self {
-            n_iter: Arc::new(n_iter.max(1)),
+            n_iter: Arc::new(Mutex::new(n_iter)),
             name: name.into(),
             current_iter: Arc::new(Mutex::new(0usize)),
-            id: Arc::new(Uuid::new_v4()),
-            finished: Arc::new(Mutex::new(false))
+            id: Arc::new(new_id()),
+            finished: Arc::new(Mutex::new(false)),
+            clock: Arc::new(SystemClock),
+            rate: Arc::new(Mutex::new(RateEstimator::new(0, SystemClock.now()))),
+            name_width,
+            depth,
+            frame_len: Arc::new(Mutex::new(0)),
+            style,
+            events: None,
+            log_frames: true,
+            scope_prefix,
+            clones: Clones(Arc::new(AtomicUsize::new(1))),
         };
-        pb.send();
+        if n_iter == 0 {
+            pb.close();
+        } else {
+            pb.send();
+        }
         pb
     }
 
+    /// Draws the bar with the characters of `style`, e.g. [`BarStyle::unicode`]. The first frame
+    /// was already sent with the previous style: log files rewriting the line in place move it to
+    /// the end of the file if the new frames are longer. Prefer [`LogProgressBarBuilder::style`].
+    pub fn with_style(mut self, style: BarStyle) -> Self {
+        self.style = style;
+        self.send();
+        self
+    }
+
+    /// Also sends a [`ProgressEvent`] to `events` at each update, e.g. for a custom UI.
+    /// A receiver dropped meanwhile is ignored.
+    pub fn with_event_channel(mut self, events: Sender<ProgressEvent>) -> Self {
+        self.events = Some(events);
+        self.emit(*self.finished.lock().unwrap());
+        self
+    }
+
+    /// Whether frames are logged (the default), or only sent as events. The bar is still marked
+    /// as finished in the logs, so that a frame logged before this call is not left open.
+    pub fn with_log_frames(mut self, yes: bool) -> Self {
+        self.log_frames = yes;
+        self
+    }
+
     pub fn send(&self) {
         if *self.finished.lock().unwrap() {
-            log::info!("___PROGRESS___{}___FINISHED",self.id)
+            self.send_finished();
         } else {
-            log::info!("___PROGRESS___{}___{}",self.id,self.format())
+            self.send_frame();
+        }
+    }
+
+    fn send_frame(&self) {
+        if self.log_frames {
+            if let Some(senders) = Senders::current() {
+                let name = senders.name().filter(|_| self.scope_prefix);
+                senders.send_progress(*self.id, &self.frame(name));
+            }
+        }
+        self.emit(false);
+    }
+
+    fn send_finished(&self) {
+        if let Some(senders) = Senders::current() {
+            senders.finish_progress(*self.id);
+        }
+        self.emit(true);
+    }
+
+    fn emit(&self, finished: bool) {
+        if let Some(events) = &self.events {
+            let event = ProgressEvent {
+                id: *self.id,
+                name: self.name.to_string(),
+                current: *self.current_iter.lock().unwrap(),
+                total: *self.n_iter.lock().unwrap(),
+                finished,
+            };
+            events.send(event).ok();
         }
     }
 
+    /// Replaces the time source of the elapsed time, rate and ETA, which start over from the
+    /// clock's current time.
+    pub fn with_clock(mut self, clock: Arc<dyn ProgressClock>) -> Self {
+        *self.rate.lock().unwrap() = RateEstimator::new(*self.current_iter.lock().unwrap(), clock.now());
+        self.clock = clock;
+        self
+    }
+
     pub fn set_progress(&self, n: usize) {
         *self.current_iter.lock().unwrap() = n;
+        self.rate.lock().unwrap().update(n, self.clock.now());
         self.send();
     }
 
     pub fn inc(&self, n: usize) {
-        *self.current_iter.lock().unwrap() += n;
+        let position = {
+            let mut current_iter = self.current_iter.lock().unwrap();
+            *current_iter += n;
+            *current_iter
+        };
+        self.rate.lock().unwrap().update(position, self.clock.now());
+        self.send();
+    }
+
+    /// Time elapsed since the bar was created.
+    pub fn elapsed(&self) -> Duration {
+        self.rate.lock().unwrap().elapsed(self.clock.now())
+    }
+
+    /// Smoothed number of iterations per second, `None` until some time has elapsed.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate.lock().unwrap().rate(self.clock.now())
+    }
+
+    /// Estimated time left before completion at the current rate, `None` while no progress is
+    /// being made.
+    pub fn eta(&self) -> Option<Duration> {
+        let current_iter = *self.current_iter.lock().unwrap();
+        let n_iter = *self.n_iter.lock().unwrap();
+        self.rate.lock().unwrap().eta(current_iter, n_iter, self.clock.now())
+    }
+
+    /// Returns a batcher accumulating the increments of a hot loop locally, the bar being updated
+    /// only every `batch_size` iterations and when the batcher is dropped, so that totals stay exact.
+    /// Batchers are cheap: each worker thread can create its own.
+    ///
+    /// ```rust
+    /// use mtlog_progress::LogProgressBar;
+    ///
+    /// let pb = LogProgressBar::new(1_000_000, "Hot loop");
+    /// std::thread::scope(|s| {
+    ///     for _ in 0..4 {
+    ///         s.spawn(|| {
+    ///             let batcher = pb.batcher(1000);
+    ///             for _ in 0..250_000 {
+    ///                 batcher.inc(1);
+    ///             }
+    ///         });
+    ///     }
+    /// });
+    /// pb.finish();
+    /// ```
+    pub fn batcher(&self, batch_size: usize) -> ProgressBatcher<'_> {
+        ProgressBatcher { bar: self, batch_size: batch_size.max(1), pending: Cell::new(0) }
+    }
+
+    /// Logs a regular line through the same pipeline as the bar frames, so it is ordered with
+    /// them in every sink. Terminals clear the bar, print the line and draw the bar again below
+    /// it, while files append the line and keep updating the bar at its position.
+    pub fn println(&self, msg: &str) {
+        if let Some(senders) = Senders::current() {
+            senders.send_println(*self.id, msg);
+        }
+    }
+
+    /// Updates the total number of iterations.
+    ///
+    /// If the new total is below the current progress, the current progress is clamped down
+    /// to the new total: the bar renders as complete, with nothing remaining.
+    pub fn set_length(&self, n_iter: usize) {
+        *self.n_iter.lock().unwrap() = n_iter;
+        {
+            let mut current_iter = self.current_iter.lock().unwrap();
+            *current_iter = (*current_iter).min(n_iter);
+        }
         self.send();
     }
 
     fn format(&self) -> String {
         let current_iter = *self.current_iter.lock().unwrap();
-        let percentage = (current_iter as f64 / *self.n_iter as f64 * 100.0) as usize;
+        let n_iter = *self.n_iter.lock().unwrap();
+        // A bar without iterations is complete
+        let (done, total) = if n_iter == 0 { (1, 1) } else { (current_iter, n_iter) };
+        let percentage = (done as f64 / total as f64 * 100.0) as usize;
         let bar_length = 20; // Length of the progress bar
-        let filled_length = (bar_length * current_iter / *self.n_iter).min(bar_length);
-        let bar = "#".repeat(filled_length) + &".".repeat(bar_length - filled_length);
-        let n_iter_str = self.n_iter.to_string();
+        let bar = self.style.render(done, total, bar_length);
+        let n_iter_str = n_iter.to_string();
+        let nothing_to_do = if n_iter == 0 { " (nothing to do)" } else { "" };
+        // Bars whose characters differ in size are padded to a constant number of bytes
+        let padding = " ".repeat(self.style.max_len(bar_length) - bar.len());
         format!(
-            "Progress {name}: [{bar}] {current:>len$}/{n_iter_str} {percentage:>3}%",
-            name=self.name.cyan(), 
+            "{indent}Progress {name}: [{bar}] {current:>len$}/{n_iter_str} {percentage:>3}%{nothing_to_do}{padding}",
+            indent="  ".repeat(self.depth),
+            name=self.padded_name().cyan(),
             bar=bar.cyan(),
             current=current_iter,
             len=n_iter_str.len(),
         )
     }
     
+    /// Line sent for the current position, prefixed with `name` if any: log files rewrite progress
+    /// lines in place, so a frame shorter than a previous one (e.g. after `set_length`) is padded
+    /// with spaces to cover it.
+    fn frame(&self, name: Option<&str>) -> String {
+        let mut frame = match name {
+            Some(name) => format!("[{name}] {}", self.format()),
+            None => self.format(),
+        };
+        let mut frame_len = self.frame_len.lock().unwrap();
+        *frame_len = (*frame_len).max(frame.len());
+        let padding = *frame_len - frame.len();
+        frame.push_str(&" ".repeat(padding));
+        frame
+    }
+
+    /// Name fitted to the name width, in terminal columns: padded with spaces, or truncated with `…`.
+    /// Left as is without a name width.
+    fn padded_name(&self) -> String {
+        if self.name_width == 0 || self.name.width() <= self.name_width {
+            let padding = self.name_width.saturating_sub(self.name.width());
+            return format!("{}{}", self.name, " ".repeat(padding));
+        }
+        let mut name = String::new();
+        let mut width = 0;
+        for c in self.name.chars() {
+            let char_width = c.width().unwrap_or(0);
+            if width + char_width + 1 > self.name_width {
+                break;
+            }
+            name.push(c);
+            width += char_width;
+        }
+        name.push('…');
+        name.push_str(&" ".repeat(self.name_width - width - 1));
+        name
+    }
+
+    /// Sends a final frame at the current position, then marks the bar as finished.
+    /// Only the first call has an effect.
+    fn close(&self) {
+        let mut finished = self.finished.lock().unwrap();
+        if *finished {
+            return
+        }
+        self.send_frame();
+        *finished = true;
+        self.send_finished();
+    }
+
+    /// Stops the bar before completion: the bar is closed at the current position, then an
+    /// `ABANDONED` warning is logged below it.
+    pub fn abandon(&self) {
+        if *self.finished.lock().unwrap() {
+            return
+        }
+        self.close();
+        if let Some(senders) = Senders::current() {
+            senders.send_regular(log::Level::Warn, &format!("Progress {}: ABANDONED at {}/{}", self.name, self.current_iter.lock().unwrap(), self.n_iter.lock().unwrap()));
+        }
+    }
+
+    /// Wraps the bar in a guard abandoning it when dropped before [`ProgressGuard::finish`],
+    /// e.g. when the future owning it is cancelled.
+    pub fn guard(self) -> ProgressGuard {
+        ProgressGuard { bar: self }
+    }
+
+    /// Completes the bar: a final frame at 100% is sent before the bar is marked as finished.
     pub fn finish(&self) {
         if *self.finished.lock().unwrap() {
             return
         }
-        *self.finished.lock().unwrap() = true;    
-        *self.current_iter.lock().unwrap() = *self.n_iter;
-        self.send();
+        *self.current_iter.lock().unwrap() = *self.n_iter.lock().unwrap();
+        self.close();
+    }
+}
+
+/// Builder of a [`LogProgressBar`], see [`LogProgressBar::builder`].
+#[derive(Debug, Clone)]
+pub struct LogProgressBarBuilder {
+    n_iter: usize,
+    name: String,
+    name_width: usize,
+    style: BarStyle,
+    scope_prefix: bool,
+}
+
+impl LogProgressBarBuilder {
+    /// Width of the name in terminal columns, see [`LogProgressBar::new_aligned`].
+    pub fn name_width(self, name_width: usize) -> Self {
+        Self { name_width, ..self }
+    }
+
+    /// Characters of the bar, e.g. [`BarStyle::unicode`].
+    pub fn style(self, style: BarStyle) -> Self {
+        Self { style, ..self }
+    }
+
+    /// Whether frames are prefixed with the name of the logger of the thread or task updating the
+    /// bar, e.g. `[worker-3] Progress upload: ...`, off by default. The name is resolved at each
+    /// update, not when the bar is created.
+    pub fn scope_prefix(self, scope_prefix: bool) -> Self {
+        Self { scope_prefix, ..self }
+    }
+
+    /// Creates the bar, sending its first frame.
+    pub fn build(self) -> LogProgressBar {
+        LogProgressBar::new_nested(self.n_iter, &self.name, self.name_width, 0, self.style, self.scope_prefix)
     }
 }
 
 impl Drop for LogProgressBar {
+    /// Marks the bar as finished when its last clone is dropped, after a final frame showing the
+    /// position reached.
     fn drop(&mut self) {
-        *self.finished.lock().unwrap() = true;
-        self.send();
+        if self.clones.release() {
+            self.close();
+        }
     }
 }
+/// Increments of a [`LogProgressBar`] accumulated locally, see [`LogProgressBar::batcher`].
+/// The remaining increments are flushed to the bar on drop.
+pub struct ProgressBatcher<'a> {
+    bar: &'a LogProgressBar,
+    batch_size: usize,
+    /// Increments not sent to the bar yet, owned by the loop: the batcher is not `Sync`.
+    pending: Cell<usize>,
+}
 
+impl ProgressBatcher<'_> {
+    pub fn inc(&self, n: usize) {
+        let pending = self.pending.get() + n;
+        if pending >= self.batch_size {
+            self.pending.set(0);
+            self.bar.inc(pending);
+        } else {
+            self.pending.set(pending);
+        }
+    }
+
+    /// Sends the accumulated increments to the bar.
+    pub fn flush(&self) {
+        let pending = self.pending.replace(0);
+        if pending > 0 {
+            self.bar.inc(pending);
+        }
+    }
+}
+
+impl Drop for ProgressBatcher<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A progress bar abandoned on drop unless finished, distinguishing completed work from
+/// cancelled work in async code.
+///
+/// ```rust
+/// use mtlog_progress::LogProgressBar;
+///
+/// async fn work() {
+///     let pb = LogProgressBar::new(100, "Work").guard();
+///     for _ in 0..100 {
+///         pb.inc(1);
+///         // cancelling the future at an await point abandons the bar
+///     }
+///     pb.finish();
+/// }
+/// ```
+pub struct ProgressGuard {
+    bar: LogProgressBar,
+}
+
+impl ProgressGuard {
+    /// Completes the bar, see [`LogProgressBar::finish`].
+    pub fn finish(self) {
+        self.bar.finish();
+    }
+}
+
+impl Deref for ProgressGuard {
+    type Target = LogProgressBar;
+    fn deref(&self) -> &Self::Target {
+        &self.bar
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        self.bar.abandon();
+    }
+}
 
 #[test]
 fn test_progress_bar() {
@@ -163,3 +677,440 @@ fn test_progress_bar() {
     pb.finish();
     std::thread::sleep(std::time::Duration::from_millis(1));
 }
+
+#[test]
+fn test_progress_bar_created_before_init() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_created_before_init.log";
+    std::fs::remove_file(path).ok();
+    let pb = LogProgressBar::new(10, "Pre-init");
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    for _ in 0..10 {
+        pb.inc(1);
+    }
+    pb.finish();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert!(content.ends_with("10/10 100%\n"));
+}
+
+#[test]
+fn test_progress_bar_length_below_current() {
+    let pb = LogProgressBar::new(100, "Shrink");
+    pb.set_progress(80);
+    pb.set_length(50);
+    let line = pb.format();
+    assert!(line.contains("####################"));
+    assert!(line.ends_with(" 50/50 100%"));
+}
+
+#[test]
+fn test_progress_bar_empty() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_empty.log";
+    logger_config()
+        .with_log_file_truncate(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let pb = LogProgressBar::new(0, "Empty");
+    assert!(*pb.finished.lock().unwrap());
+    log::info!("below the bar");
+    pb.finish();
+    drop(pb);
+    let shrunk = LogProgressBar::new(10, "Shrunk");
+    shrunk.set_length(0);
+    assert!(shrunk.format().ends_with(" 0/0 100% (nothing to do)"));
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].contains("[####################]"));
+    assert!(lines[0].ends_with(" 0/0 100% (nothing to do)"));
+    assert!(lines[1].ends_with("] below the bar"));
+}
+
+#[test]
+fn test_progress_bar_events() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pb = LogProgressBar::new(4, "Events").with_event_channel(sender).with_log_frames(false);
+    pb.inc(3);
+    pb.set_length(3);
+    pb.finish();
+    drop(pb);
+    let events = receiver.iter().collect::<Vec<_>>();
+    let states = events.iter().map(|event| (event.current, event.total, event.finished)).collect::<Vec<_>>();
+    assert_eq!(states, [(0, 4, false), (3, 4, false), (3, 3, false), (3, 3, false), (3, 3, true)]);
+    assert!(events.iter().all(|event| event.name == "Events" && event.id == events[0].id));
+    let lines = mtlog::test_util::capture_logs(|| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        LogProgressBar::new(2, "Quiet").with_log_frames(false).with_event_channel(sender).inc(1);
+        assert!(receiver.iter().last().unwrap().finished);
+    });
+    assert_eq!(lines.len(), 1);
+}
+
+#[test]
+fn test_progress_bar_name_width() {
+    let names = ["データ処理", "data", "混合mix"];
+    let bars = names.map(|name| LogProgressBar::new_aligned(10, name, 12));
+    for pb in &bars {
+        assert_eq!(pb.padded_name().width(), 12);
+    }
+    assert_eq!(bars[0].padded_name(), "データ処理  ");
+    assert_eq!(LogProgressBar::new_aligned(10, "長い名前のバー", 4).padded_name(), "長… ");
+    assert_eq!(LogProgressBar::new_aligned(10, "数据处理 🚀", 12).padded_name(), "数据处理 🚀 ");
+    assert_eq!(LogProgressBar::new_aligned(10, "数据处理 🚀", 10).padded_name(), "数据处理 …");
+    assert_eq!(LogProgressBar::new_aligned(10, "数据处理 🚀", 0).padded_name(), "数据处理 🚀");
+}
+
+#[test]
+fn test_progress_bar_stable_frames() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_stable_frames.log";
+    logger_config()
+        .with_log_file_truncate(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let pb = LogProgressBar::new_aligned(100, "数据处理 🚀", 8);
+    let first = pb.frame(None);
+    pb.inc(40);
+    log::info!("below the bar");
+    pb.set_length(50);
+    let shrunk = pb.frame(None);
+    assert_eq!(shrunk.len(), first.len());
+    pb.inc(10);
+    pb.finish();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("数据处… :"));
+    assert!(lines[0].trim_end().ends_with(" 50/50 100%"));
+    assert!(lines[1].ends_with("] below the bar"));
+}
+
+#[test]
+fn test_progress_bar_dropped_unfinished() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_dropped_unfinished.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    drop(LogProgressBar::new(10, "Untouched"));
+    let pb = LogProgressBar::new(10, "Partial");
+    pb.inc(3);
+    drop(pb);
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" 0/10   0%"));
+    assert!(lines[1].ends_with(" 3/10  30%"));
+}
+
+#[test]
+fn test_progress_bar_println() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_println.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let pb = LogProgressBar::new(4, "Println");
+    pb.inc(2);
+    pb.println("halfway");
+    pb.inc(2);
+    pb.println("done");
+    pb.finish();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(" 4/4 100%"));
+    assert!(lines[1].ends_with("] halfway"));
+    assert!(lines[2].ends_with("] done"));
+}
+
+#[test]
+fn test_progress_bar_println_stdout() {
+    use mtlog::{logger_config, LogStdout};
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    mtlog::test_mode();
+    let output = Buffer::default();
+    logger_config()
+        .no_file()
+        .no_stdout()
+        .with_writer(LogStdout::new(output.clone()))
+        .init_local();
+    let pb = LogProgressBar::new(4, "Println");
+    pb.inc(2);
+    pb.println("halfway");
+    pb.finish();
+    drop(pb);
+    let content = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    // The bar is cleared, the line printed in its place and the bar drawn below it
+    let (before, after) = content.split_once("\x1B[1A\r\x1B[2K[").unwrap();
+    assert!(before.ends_with(" 2/4  50%\x1B[1B\r"));
+    let (line, bar) = after.split_once('\n').unwrap();
+    assert!(line.ends_with(" INFO] halfway"));
+    assert!(bar.starts_with("Progress "));
+    assert!(bar.contains(" 2/4  50%\n"));
+    assert!(bar.ends_with(" 4/4 100%\x1B[1B\r"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_progress_guard_cancelled() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_guard_cancelled.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let work = |name: &'static str| async move {
+        let pb = LogProgressBar::new(10, name).guard();
+        for _ in 0..10 {
+            pb.inc(1);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        pb.finish();
+    };
+    work("Completed").await;
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(35), work("Cancelled")).await.is_err());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with("10/10 100%"));
+    assert!(lines[2].contains("Progress Cancelled: ABANDONED at "));
+}
+
+#[test]
+fn test_progress_bar_clock() {
+    use clock::FakeClock;
+    let clock = Arc::new(FakeClock::new());
+    let pb = LogProgressBar::new(100, "Clock").with_clock(clock.clone());
+    assert_eq!(pb.rate(), None);
+    assert_eq!(pb.eta(), None);
+    for _ in 0..4 {
+        clock.advance(Duration::from_millis(500));
+        pb.inc(5);
+    }
+    assert_eq!(pb.elapsed(), Duration::from_secs(2));
+    assert!((pb.rate().unwrap() - 10.0).abs() < 1e-9);
+    assert!((pb.eta().unwrap().as_secs_f64() - 8.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_progress_bar_captured() {
+    let lines = mtlog::test_util::capture_logs(|| {
+        let pb = LogProgressBar::new(2, "Captured");
+        pb.inc(1);
+        pb.println("between");
+        pb.abandon();
+    });
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(" 1/2  50%"));
+    assert!(lines[1].ends_with("] between"));
+    assert!(lines[2].ends_with("Progress Captured: ABANDONED at 1/2"));
+}
+
+#[test]
+fn test_bar_styles() {
+    let arrow = BarStyle { filled: '=', empty: ' ', edge: Some('>'), smooth: false };
+    let render = |style: BarStyle| [0, 1, 33, 50, 99, 100].map(|current| style.render(current, 100, 10));
+    assert_eq!(render(BarStyle::ascii()), ["..........", "..........", "###.......", "#####.....", "#########.", "##########"]);
+    let unicode = render(BarStyle::unicode()).map(|bar| bar.replace('\u{2007}', " "));
+    assert_eq!(unicode, ["          ", "          ", "███▎      ", "█████     ", "█████████▉", "██████████"]);
+    assert!(render(BarStyle::unicode()).iter().all(|bar| bar.len() == BarStyle::unicode().max_len(10)));
+    assert_eq!(render(arrow), [">         ", ">         ", "===>      ", "=====>    ", "=========>", "=========="]);
+    let pb = LogProgressBar::new(8, "Style").with_style(BarStyle::unicode());
+    pb.set_progress(3);
+    assert!(pb.format().contains("███████▌"));
+}
+
+#[test]
+fn test_bar_styles_file() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_bar_styles_file.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let mixed = BarStyle { filled: '█', empty: '.', edge: None, smooth: false };
+    for style in [BarStyle::unicode(), mixed] {
+        let pb = LogProgressBar::builder(4, "Style").style(style).build();
+        log::info!("after the bar");
+        pb.inc(4);
+        pb.finish();
+        log::info!("after the end");
+    }
+    // Frames rewritten in place never spill over the lines logged after them
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 6);
+    for chunk in lines.chunks(3) {
+        assert!(chunk[0].trim_end().ends_with("] 4/4 100%"));
+        assert!(chunk[1].ends_with(" INFO] after the bar"));
+        assert!(chunk[2].ends_with(" INFO] after the end"));
+    }
+}
+
+#[test]
+fn test_progress_bar_scope_prefix() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_scope_prefix.log";
+    let _ = std::fs::remove_file(path);
+    for i in 1..=2 {
+        std::thread::spawn(move || {
+            logger_config()
+                .with_log_file(path).unwrap()
+                .no_stdout()
+                .with_name(format!("worker-{i}"))
+                .init_local();
+            let pb = LogProgressBar::builder(2, "upload").scope_prefix(true).build();
+            pb.inc(1);
+            pb.finish();
+            LogProgressBar::new(1, "unprefixed").finish();
+        }).join().unwrap();
+    }
+    let content = std::fs::read_to_string(path).unwrap();
+    for i in 1..=2 {
+        assert!(content.lines().any(|line| line.starts_with(&format!("[worker-{i}] Progress upload: ")) && line.ends_with("2/2 100%")));
+    }
+    assert_eq!(content.lines().filter(|line| line.starts_with("Progress unprefixed: ")).count(), 2);
+}
+
+#[test]
+fn test_id_generator() {
+    let (events, received) = std::sync::mpsc::channel();
+    let mut next = 0;
+    let ids = with_id_generator(move || { next += 1; Uuid::from_u128(next) }, || {
+        [LogProgressBar::new(2, "first"), LogProgressBar::new(2, "second").with_event_channel(events)].map(|pb| *pb.id)
+    });
+    assert_eq!(ids, [Uuid::from_u128(1), Uuid::from_u128(2)]);
+    assert_eq!(received.recv().unwrap().id, Uuid::from_u128(2));
+    assert_ne!(*LogProgressBar::new(2, "random").id, Uuid::from_u128(3));
+}
+
+#[test]
+fn test_progress_batcher() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pb = LogProgressBar::new(40_000, "Batched").with_log_frames(false).with_event_channel(sender);
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let batcher = pb.batcher(1000);
+                for _ in 0..9_999 {
+                    batcher.inc(1);
+                }
+            });
+        }
+    });
+    assert_eq!(*pb.current_iter.lock().unwrap(), 4 * 9_999);
+    drop(pb);
+    // 1 event at creation, 9 batches and 1 flush per worker, 2 on close, instead of 40_000
+    let events = receiver.iter().collect::<Vec<_>>();
+    assert_eq!(events.len(), 1 + 4 * 10 + 2);
+    assert_eq!(events.last().unwrap().current, 4 * 9_999);
+}
+
+#[test]
+fn test_progress_bar_child_of() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let path = "/tmp/test_progress_bar_child_of.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let outer = LogProgressBar::new(2, "outer");
+    for _ in 0..2 {
+        let inner = LogProgressBar::child_of(&outer, 3, "inner");
+        let innermost = LogProgressBar::child_of(&inner, 1, "innermost");
+        innermost.finish();
+        inner.inc(3);
+        inner.finish();
+        outer.inc(1);
+    }
+    outer.finish();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], "Progress outer: [####################] 2/2 100%");
+    for pair in lines[1..].chunks(2) {
+        assert_eq!(pair[0], "  Progress inner: [####################] 3/3 100%");
+        assert_eq!(pair[1], "    Progress innermost: [####################] 1/1 100%");
+    }
+}
+
+#[test]
+fn test_test_mode_reproducible() {
+    use mtlog::logger_config;
+    mtlog::test_mode();
+    let run = |path: &str| {
+        logger_config()
+            .with_log_file_truncate(path).unwrap()
+            .no_stdout()
+            .with_clock(|| chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap())
+            .init_local();
+        let outer = LogProgressBar::new(3, "outer");
+        for i in 0..3 {
+            let inner = LogProgressBar::child_of(&outer, 4, "inner");
+            for _ in 0..4 {
+                inner.inc(1);
+            }
+            inner.finish();
+            outer.println(&format!("step {i} done"));
+            outer.inc(1);
+        }
+        outer.finish();
+        std::fs::read_to_string(path).unwrap()
+    };
+    let first = run("/tmp/test_test_mode_reproducible_1.log");
+    assert_eq!(first.lines().count(), 7);
+    for i in 2..=3 {
+        assert_eq!(run(&format!("/tmp/test_test_mode_reproducible_{i}.log")), first);
+    }
+}
+
+#[test]
+fn test_progress_bar_clones() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let pb = LogProgressBar::new(8, "Shared").with_log_frames(false).with_event_channel(sender);
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            let pb = pb.clone();
+            s.spawn(move || {
+                pb.inc(1);
+                drop(pb);
+            });
+        }
+    });
+    pb.inc(4);
+    assert!(receiver.try_iter().all(|event| !event.finished));
+    drop(pb);
+    let finished = receiver.try_iter().filter(|event| event.finished).collect::<Vec<_>>();
+    assert_eq!(finished.len(), 1);
+    assert_eq!(finished[0].current, 8);
+}
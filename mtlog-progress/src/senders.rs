@@ -0,0 +1,88 @@
+use log::Level;
+use uuid::Uuid;
+
+#[cfg(not(any(feature = "std", feature = "tokio")))]
+compile_error!("mtlog-progress needs the `std` feature, the `tokio` one, or both");
+
+/// Handles on the sinks of the configuration active for the caller, in whichever of mtlog and
+/// mtlog-tokio installed its logger, among those enabled by the `std` and `tokio` features.
+#[derive(Clone)]
+pub(crate) enum Senders {
+    #[cfg(feature = "std")]
+    Std(mtlog::ScopeSenders),
+    #[cfg(feature = "tokio")]
+    Tokio(mtlog_tokio::ScopeSenders),
+}
+
+impl Senders {
+    /// Senders of the current thread or task, `None` while no logger is installed.
+    pub(crate) fn current() -> Option<Self> {
+        #[cfg(feature = "tokio")]
+        if mtlog_tokio::is_installed() {
+            return Some(Self::Tokio(mtlog_tokio::current_senders()));
+        }
+        #[cfg(feature = "std")]
+        if mtlog::is_installed() {
+            return Some(Self::Std(mtlog::current_senders()));
+        }
+        None
+    }
+
+    /// Whether the logger of mtlog or mtlog-tokio is installed.
+    pub(crate) fn installed() -> bool {
+        #[cfg(feature = "tokio")]
+        if mtlog_tokio::is_installed() {
+            return true;
+        }
+        #[cfg(feature = "std")]
+        if mtlog::is_installed() {
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Std(senders) => senders.name(),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(senders) => senders.name(),
+        }
+    }
+
+    pub(crate) fn send_regular(&self, level: Level, message: &str) {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Std(senders) => senders.send_regular(level, None, message),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(senders) => senders.send_regular(level, None, message),
+        }
+    }
+
+    pub(crate) fn send_progress(&self, id: Uuid, text: &str) {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Std(senders) => senders.send_progress(id, text),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(senders) => senders.send_progress(id, text),
+        }
+    }
+
+    pub(crate) fn finish_progress(&self, id: Uuid) {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Std(senders) => senders.finish_progress(id),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(senders) => senders.finish_progress(id),
+        }
+    }
+
+    pub(crate) fn send_println(&self, id: Uuid, message: &str) {
+        match self {
+            #[cfg(feature = "std")]
+            Self::Std(senders) => senders.send_println(id, message),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(senders) => senders.send_println(id, message),
+        }
+    }
+}
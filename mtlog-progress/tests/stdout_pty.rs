@@ -0,0 +1,135 @@
+//! Real stdout of a child process writing logs and two concurrent progress bars, captured through
+//! a pseudo-terminal and through a file.
+#![cfg(unix)]
+
+use std::fs::File;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use mtlog::logger_config;
+use mtlog_progress::LogProgressBar;
+
+const CHILD_ENV: &str = "MTLOG_TEST_PTY_CHILD";
+
+/// Writes the logs captured by the tests, when run as the child process.
+#[test]
+fn pty_child() {
+    if std::env::var_os(CHILD_ENV).is_none() {
+        return;
+    }
+    let handle = logger_config().init_global_with_handle();
+    log::info!("starting uploads");
+    std::thread::scope(|s| {
+        for name in ["alpha", "beta"] {
+            s.spawn(move || {
+                let pb = LogProgressBar::new(5, name);
+                for _ in 0..5 {
+                    std::thread::sleep(Duration::from_millis(30));
+                    pb.inc(1);
+                }
+                pb.finish();
+            });
+        }
+    });
+    log::warn!("uploads done");
+    handle.flush();
+    std::process::exit(0);
+}
+
+fn child() -> Command {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.args(["--exact", "pty_child", "--nocapture", "--test-threads=1"]).env(CHILD_ENV, "1").stdin(Stdio::null()).stderr(Stdio::null());
+    command
+}
+
+/// Rows of a terminal after writing `output`, applying the cursor movements and line clears and
+/// dropping the colors.
+fn render(output: &str) -> Vec<String> {
+    let mut rows = vec![Vec::new()];
+    let (mut row, mut col) = (0usize, 0usize);
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                row += 1;
+                col = 0;
+            }
+            '\r' => col = 0,
+            '\x1B' => {
+                assert_eq!(chars.next(), Some('['));
+                let mut n = String::new();
+                let command = chars.by_ref().find(|c| {
+                    n.push(*c);
+                    c.is_ascii_alphabetic()
+                }).unwrap();
+                let n: usize = n.trim_end_matches(command).parse().unwrap_or(1);
+                match command {
+                    'A' => row -= n,
+                    'B' => row += n,
+                    'K' => rows[row].clear(),
+                    'm' => {}
+                    _ => panic!("unexpected escape code {n}{command}"),
+                }
+            }
+            c => {
+                if rows[row].len() <= col {
+                    rows[row].resize(col + 1, ' ');
+                }
+                rows[row][col] = c;
+                col += 1;
+            }
+        }
+        if rows.len() <= row {
+            rows.resize(row + 1, Vec::new());
+        }
+    }
+    rows.into_iter().map(|row| row.into_iter().collect::<String>()).filter(|row| !row.is_empty()).collect()
+}
+
+/// Checks the rows written by the child, below the lines of the test harness.
+fn check_screen(rows: &[String]) {
+    let rows = &rows[rows.len() - 4..];
+    assert!(rows[0].ends_with(" INFO] starting uploads"), "{rows:?}");
+    assert_eq!(rows[1], "Progress alpha: [####################] 5/5 100%");
+    assert_eq!(rows[2], "Progress beta: [####################] 5/5 100%");
+    assert!(rows[3].starts_with('[') && rows[3].ends_with(" WARN] uploads done"), "{rows:?}");
+}
+
+#[test]
+fn test_stdout_pty() {
+    let pty = nix::pty::openpty(None, None).unwrap();
+    let mut child = child().stdout(Stdio::from(pty.slave)).spawn().unwrap();
+    // The only slave left is the child's: reading fails with EIO once it exits
+    let mut master = File::from(pty.master);
+    let mut output = Vec::new();
+    let mut buffer = [0; 4096];
+    while let Ok(n @ 1..) = master.read(&mut buffer) {
+        output.extend_from_slice(&buffer[..n]);
+    }
+    assert!(child.wait().unwrap().success());
+    // The terminal translates line feeds to CRLF
+    let output = String::from_utf8(output).unwrap().replace("\r\n", "\n");
+    // Colored levels and bars, bars redrawn in place above the cursor
+    assert!(output.contains("\x1B[32mINFO\x1B[0m"));
+    assert!(output.contains("Progress \x1B[36malpha\x1B[0m"));
+    assert!(output.contains("\x1B[2A\r"));
+    check_screen(&render(&output));
+}
+
+#[test]
+fn test_stdout_file() {
+    let path = "/tmp/test_stdout_pty_file.log";
+    let mut child = child().stdout(File::create(path).unwrap()).spawn().unwrap();
+    assert!(child.wait().unwrap().success());
+    // Not a terminal: no cursor movement, each bar being written once finished, in any order
+    let output = std::fs::read_to_string(path).unwrap();
+    assert!(!output.contains('\r'));
+    let lines: Vec<_> = output.lines().collect();
+    let lines = &lines[lines.len() - 4..];
+    assert!(lines[0].ends_with(" INFO] starting uploads"), "{lines:?}");
+    let mut bars = lines[1..3].to_vec();
+    bars.sort();
+    assert_eq!(bars, ["Progress alpha: [####################] 5/5 100%", "Progress beta: [####################] 5/5 100%"]);
+    assert!(lines[3].ends_with(" WARN] uploads done"), "{lines:?}");
+}
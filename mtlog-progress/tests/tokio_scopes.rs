@@ -0,0 +1,26 @@
+use mtlog_progress::LogProgressBar;
+use mtlog_tokio::logger_config;
+
+#[tokio::test]
+async fn test_progress_bar_tokio_scope() {
+    let path = "/tmp/test_progress_bar_tokio_scope.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_name("worker")
+        .scope_local(async move {
+            let pb = LogProgressBar::builder(4, "upload").scope_prefix(true).build();
+            pb.inc(2);
+            pb.println("halfway");
+            pb.finish();
+            LogProgressBar::new(2, "cancelled").abandon();
+        }).await;
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("[worker] Progress upload: ") && lines[0].ends_with(" 4/4 100%"));
+    assert!(lines[1].ends_with(" worker INFO] halfway"));
+    assert!(lines[2].starts_with("Progress cancelled: ") && lines[2].ends_with(" 0/2   0%"));
+    assert!(lines[3].ends_with(" worker WARN] Progress cancelled: ABANDONED at 0/2"));
+}
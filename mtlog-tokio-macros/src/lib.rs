@@ -0,0 +1,33 @@
+//! # mtlog-tokio-macros
+//! Attribute macros of mtlog-tokio, re-exported by it: depend on mtlog-tokio rather than on this crate.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Expr, ItemFn};
+
+/// Runs the body of an async fn with its own configuration, as `scope_local` does.
+///
+/// Without argument, the configuration is `logger_config().with_name("<fn name>")`. The argument,
+/// if any, is the expression of the configuration, evaluated on each call and able to use the
+/// arguments of the fn, e.g. `#[instrument(logger_config().with_name(format!("job {id}")))]`.
+#[proc_macro_attribute]
+pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let ItemFn { attrs, vis, sig, block } = parse_macro_input!(item as ItemFn);
+    if sig.asyncness.is_none() {
+        return syn::Error::new_spanned(sig.fn_token, "`instrument` only applies to async fns").to_compile_error().into();
+    }
+    let config = if attr.is_empty() {
+        let name = sig.ident.to_string();
+        quote! { ::mtlog_tokio::logger_config().with_name(#name) }
+    } else {
+        let config = parse_macro_input!(attr as Expr);
+        quote! { #config }
+    };
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            ::mtlog_tokio::ConfigBuilder::scope_local(#config, async move #block).await
+        }
+    }
+    .into()
+}
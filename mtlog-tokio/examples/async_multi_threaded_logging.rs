@@ -15,7 +15,7 @@ async fn main() {
                 let (sender, receiver) = channel::<&'static str>();
                 (tokio::spawn(async move {
                     logger_config()
-                        .with_name(&format!("thread {i}"))
+                        .with_name(format!("thread {i}"))
                         .with_log_file(format!("/tmp/thread_{i}.log"))
                         .unwrap()
                         .scope_local( async move {
@@ -34,6 +34,5 @@ async fn main() {
             for i in 0..5 {
                 log::info!("last line of /tmp/thread_{i}.log is:\n\t{}",std::fs::read_to_string(format!("/tmp/thread_{i}.log")).unwrap().trim_end().lines().last().unwrap());
             }
-        }).await;
-    tokio::time::sleep(std::time::Duration::from_millis(1)).await; // wait for the last log to be writtens
+        }).await; // the last log is written once the scope completes
 }
\ No newline at end of file
@@ -0,0 +1,25 @@
+use mtlog_tokio::{instrument, logger_config};
+
+/// Every log of the fn body is written to the file of the job, across its `.await` points.
+#[instrument(logger_config().with_name(format!("job {id}")).with_log_file_truncate(format!("/tmp/log_instrument_{id}.log")).unwrap().no_stdout())]
+async fn job(id: u32) {
+    log::info!("started");
+    tokio::task::yield_now().await;
+    log::info!("done");
+}
+
+#[tokio::main]
+async fn main() {
+    mtlog_tokio::test_mode();
+    let handles = (0..3).map(|id| tokio::spawn(job(id))).collect::<Vec<_>>();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    for id in 0..3 {
+        let content = std::fs::read_to_string(format!("/tmp/log_instrument_{id}.log")).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!(" job {id} INFO] started")));
+        assert!(lines[1].ends_with(&format!(" job {id} INFO] done")));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fmt, path::PathBuf};
+
+/// Error returned when configuring the logger.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The log file at `path` could not be opened.
+    Io { source: std::io::Error, path: PathBuf },
+    /// The log file at this path already exists, with [`FileMode::CreateNew`](crate::FileMode::CreateNew).
+    FileExists(PathBuf),
+    /// A level name could not be parsed.
+    InvalidLevel(String),
+    /// A filter directive could not be parsed.
+    InvalidFilter(String),
+    /// Two options cannot be used together.
+    Conflict(&'static str),
+    /// A format string could not be parsed.
+    InvalidFormat(String),
+    /// Stdout is already written by a log thread with another format.
+    StdoutInUse,
+    /// A variable of a name template is neither bound nor built in.
+    UnboundVariable(String),
+    /// A name template has an unclosed `{` or an unmatched `}`.
+    InvalidTemplate(String),
+    /// A logger name has control characters or `]`, with [`ConfigBuilder::strict_names`](crate::ConfigBuilder::strict_names).
+    InvalidName(String),
+}
+
+impl ConfigError {
+    pub(crate) fn io(source: std::io::Error, path: impl Into<PathBuf>) -> Self {
+        Self::Io { source, path: path.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { source, path } => write!(f, "unable to open log file {}: {source}", path.display()),
+            Self::FileExists(path) => write!(f, "log file {} already exists", path.display()),
+            Self::InvalidLevel(level) => write!(f, "invalid log level: {level:?}, expected one of off, error, warn, info, debug, trace"),
+            Self::InvalidFilter(filter) => write!(f, "invalid log filter: {filter:?}"),
+            Self::Conflict(options) => write!(f, "conflicting logger options: {options}"),
+            Self::InvalidFormat(format) => write!(f, "invalid log format: {format:?}"),
+            Self::StdoutInUse => write!(f, "stdout is already written by a logger with another format"),
+            Self::UnboundVariable(variable) => write!(f, "unbound variable in name template: {variable:?}"),
+            Self::InvalidTemplate(template) => write!(f, "invalid name template: {template:?}, braces must be paired or doubled"),
+            Self::InvalidName(name) => write!(f, "invalid logger name: {name:?}, control characters and `]` are not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::Io { source, .. } => source,
+            ConfigError::FileExists(_) | ConfigError::StdoutInUse => std::io::Error::new(std::io::ErrorKind::AlreadyExists, err),
+            err => std::io::Error::new(std::io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+#[test]
+fn test_config_error_messages() {
+    let err = ConfigError::io(std::io::ErrorKind::NotFound.into(), "/missing/app.log");
+    assert!(err.to_string().starts_with("unable to open log file /missing/app.log: "));
+    assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(ConfigError::FileExists("/var/log/app.log".into()).to_string(), "log file /var/log/app.log already exists");
+    assert_eq!(ConfigError::InvalidLevel("loud".into()).to_string(), r#"invalid log level: "loud", expected one of off, error, warn, info, debug, trace"#);
+    assert_eq!(ConfigError::InvalidFilter("mod=loud".into()).to_string(), r#"invalid log filter: "mod=loud""#);
+    assert_eq!(ConfigError::Conflict("no_file and with_log_file").to_string(), "conflicting logger options: no_file and with_log_file");
+    assert_eq!(ConfigError::InvalidFormat("{time".into()).to_string(), r#"invalid log format: "{time""#);
+    assert_eq!(ConfigError::StdoutInUse.to_string(), "stdout is already written by a logger with another format");
+    assert_eq!(ConfigError::UnboundVariable("index".into()).to_string(), r#"unbound variable in name template: "index""#);
+    assert_eq!(ConfigError::InvalidTemplate("worker-{index".into()).to_string(), r#"invalid name template: "worker-{index", braces must be paired or doubled"#);
+    assert_eq!(ConfigError::InvalidName("a]b".into()).to_string(), r#"invalid logger name: "a]b", control characters and `]` are not allowed"#);
+    assert_eq!(std::io::Error::from(ConfigError::Conflict("a and b")).kind(), std::io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,111 @@
+use std::{future::Future, pin::Pin, sync::Arc, task::{Context, Poll}};
+use tower_layer::Layer;
+use tower_service::Service;
+use crate::{ConfigBuilder, LOG_CONFIG};
+
+/// Tower layer running each request in its own log scope, configured by `make_config` from the
+/// request, e.g. to name the scope after a request id header.
+///
+/// The handler and everything it awaits log with the configuration of the request. A builder
+/// only setting a name is cheap: the scope shares the log threads of the global configuration.
+///
+/// ```rust
+/// use mtlog_tokio::{logger_config, MtlogLayer};
+///
+/// let layer = MtlogLayer::new(|request: &http::Request<()>| {
+///     let id = request.headers().get("x-request-id").and_then(|id| id.to_str().ok());
+///     logger_config().maybe_with_name(id)
+/// });
+/// ```
+pub struct MtlogLayer<F> {
+    make_config: Arc<F>,
+}
+
+impl<F> MtlogLayer<F> {
+    pub fn new(make_config: F) -> Self {
+        Self { make_config: Arc::new(make_config) }
+    }
+}
+
+impl<F> Clone for MtlogLayer<F> {
+    fn clone(&self) -> Self {
+        Self { make_config: self.make_config.clone() }
+    }
+}
+
+impl<S, F> Layer<S> for MtlogLayer<F> {
+    type Service = MtlogService<S, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MtlogService { inner, make_config: self.make_config.clone() }
+    }
+}
+
+/// Service wrapped by [`MtlogLayer`].
+pub struct MtlogService<S, F> {
+    inner: S,
+    make_config: Arc<F>,
+}
+
+impl<S: Clone, F> Clone for MtlogService<S, F> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), make_config: self.make_config.clone() }
+    }
+}
+
+impl<S, F, Request> Service<Request> for MtlogService<S, F>
+where
+    S: Service<Request>,
+    S::Future: Send + 'static,
+    F: Fn(&Request) -> ConfigBuilder,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let config = (self.make_config)(&request).build();
+        let future = LOG_CONFIG.sync_scope(config.clone(), || self.inner.call(request));
+        Box::pin(LOG_CONFIG.scope(config, future))
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_mtlog_layer() {
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+    let _lock = crate::GLOBAL_TEST_LOCK.lock().await;
+    let path = "/tmp/test_mtlog_layer.log";
+    std::fs::remove_file(path).ok();
+    crate::logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .scope_global(async move {
+            let service = ServiceBuilder::new()
+                .layer(MtlogLayer::new(|request: &http::Request<()>| {
+                    let id = request.headers().get("x-request-id").and_then(|id| id.to_str().ok());
+                    crate::logger_config().maybe_with_name(id)
+                }))
+                .service(service_fn(|request: http::Request<()>| async move {
+                    let id = request.headers()["x-request-id"].to_str().unwrap().to_string();
+                    log::info!("start {id}");
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    log::info!("end {id}");
+                    Ok::<_, std::convert::Infallible>(())
+                }));
+            let request = |id: &str| http::Request::builder().header("x-request-id", id).body(()).unwrap();
+            let (first, second) = tokio::join!(service.clone().oneshot(request("req-1")), service.oneshot(request("req-2")));
+            first.unwrap();
+            second.unwrap();
+        }).await;
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 4);
+    for line in content.lines() {
+        let id = line.rsplit(' ').next().unwrap();
+        assert!(line.contains(&format!(" {id} INFO] ")));
+    }
+}
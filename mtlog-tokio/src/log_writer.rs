@@ -1,52 +1,657 @@
-use std::{collections::HashMap, fs::File, io::{Seek, SeekFrom, Write}};
+use std::{collections::{HashMap, VecDeque}, fs::File, io::{ErrorKind, IsTerminal, Read, Seek, SeekFrom, Stderr, Stdout, Write}, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 
 use uuid::Uuid;
 
+use crate::utils::{in_test_mode, LogMessage};
+
+/// Destination of the log lines rendered by a log thread.
 pub trait LogWriter {
     fn regular(&mut self, line: &str);
+    /// Writes the regular line rendered from `log_message`, for writers needing the record itself,
+    /// e.g. its level. Writes `line` with [`LogWriter::regular`] by default.
+    fn regular_message(&mut self, line: &str, log_message: &LogMessage) {
+        let _ = log_message;
+        self.regular(line);
+    }
     fn progress(&mut self, line: &str, id: Uuid);
     fn finished(&mut self, id: Uuid);
+    /// Writes a progress line, prefixed by `header` when it first appears.
+    /// Writers updating lines in place keep the original header on updates.
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        self.progress(&format!("{header} {line}"), id);
+    }
+    /// Writes a regular line on behalf of the progress bar `id`, above it if the writer draws the
+    /// bar on a terminal. Writes it with [`LogWriter::regular_message`] by default.
+    fn println(&mut self, line: &str, log_message: &LogMessage, id: Uuid) {
+        let _ = id;
+        self.regular_message(line, log_message);
+    }
+    /// Flushes any buffered output.
+    fn flush(&mut self) {}
+    /// Writes the output held back by the writer whose time has come, e.g. progress frames held
+    /// back by a redraw cap, returning the delay after which to call it again, if anything is
+    /// still held back. The log thread calls it again after that delay, even if no message arrives.
+    fn write_pending(&mut self) -> Option<Duration> {
+        None
+    }
+    /// Reopens the destination, e.g. to start a new file after the current one was moved away.
+    fn reopen(&mut self) {}
+}
+
+impl<W: LogWriter + ?Sized> LogWriter for Box<W> {
+    fn regular(&mut self, line: &str) {
+        (**self).regular(line);
+    }
+    fn regular_message(&mut self, line: &str, log_message: &LogMessage) {
+        (**self).regular_message(line, log_message);
+    }
+    fn progress(&mut self, line: &str, id: Uuid) {
+        (**self).progress(line, id);
+    }
+    fn finished(&mut self, id: Uuid) {
+        (**self).finished(id);
+    }
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        (**self).progress_with_header(header, line, id);
+    }
+    fn println(&mut self, line: &str, log_message: &LogMessage, id: Uuid) {
+        (**self).println(line, log_message, id);
+    }
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+    fn write_pending(&mut self) -> Option<Duration> {
+        (**self).write_pending()
+    }
+    fn reopen(&mut self) {
+        (**self).reopen();
+    }
 }
 
-fn replace_line_in_file(file:&mut File,line: &str, pos: u64) {
-    file.seek(SeekFrom::Start(pos)).unwrap();
-    write!(file,"{line}").unwrap();
-    file.seek(SeekFrom::End(0)).unwrap();
+fn replace_line_in_file(file:&mut File,line: &str, pos: u64) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(pos))?;
+    write!(file,"{line}")?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
 }
 
+/// Number of written bytes after which a [`LogFile`] verifying its writes reads them back,
+/// without waiting for the next flush.
+const MAX_UNVERIFIED_BYTES: usize = 64 * 1024;
+
 pub struct LogFile {
     file: File,
-    progress_positions: HashMap<Uuid,u64>
+    path: PathBuf,
+    /// Position and length in bytes of the line of each progress bar, rewritten in place.
+    progress_positions: HashMap<Uuid,(u64,usize)>,
+    /// Read back the written regions on flush and drop, rewriting them on mismatch.
+    pub verify_writes: bool,
+    /// Regions written since the last verification, with their expected content.
+    unverified: Vec<(u64,Vec<u8>)>,
+    /// Bytes written since the last verification.
+    unverified_bytes: usize,
+    /// Handle reading the regions back, opened on the first verification.
+    reader: Option<File>,
+    /// Number of regions rewritten after a failed verification.
+    pub repaired_writes: usize,
+    mode: FileMode,
+}
+
+/// How a log file is opened when it already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FileMode {
+    /// Write after the existing content.
+    #[default]
+    Append,
+    /// Discard the existing content.
+    Truncate,
+    /// Fail with `ErrorKind::AlreadyExists`.
+    CreateNew,
 }
 
 impl LogFile {
     pub fn new<P:AsRef<std::path::Path>>(path: P) -> Result<Self,std::io::Error> {
-        let mut file = File::options().create(true).truncate(false).write(true).open(&path)?;
+        Self::open(path, FileMode::Append)
+    }
+
+    /// Opens the log file at `path`, created if missing, the existing content being handled according to `mode`.
+    pub fn open<P:AsRef<std::path::Path>>(path: P, mode: FileMode) -> Result<Self,std::io::Error> {
+        let mut options = File::options();
+        match mode {
+            FileMode::Append => options.create(true).truncate(false),
+            FileMode::Truncate => options.create(true).truncate(true),
+            FileMode::CreateNew => options.create_new(true),
+        };
+        let mut file = options.write(true).open(&path)?;
         file.seek(SeekFrom::End(0)).unwrap();
-        Ok(Self{file,progress_positions: HashMap::new()})
+        Ok(Self{
+            file,
+            path: path.as_ref().to_path_buf(),
+            progress_positions: HashMap::new(),
+            verify_writes: false,
+            unverified: Vec::new(),
+            unverified_bytes: 0,
+            reader: None,
+            repaired_writes: 0,
+            mode,
+        })
+    }
+
+    /// Wraps a file already opened for writing, e.g. received from a parent process dropping
+    /// privileges, writing after its existing content. Having no path, it is not reopened on
+    /// request and its writes are not verified.
+    pub fn from_file(mut file: File) -> Result<Self,std::io::Error> {
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self{
+            file,
+            path: PathBuf::new(),
+            progress_positions: HashMap::new(),
+            verify_writes: false,
+            unverified: Vec::new(),
+            unverified_bytes: 0,
+            reader: None,
+            repaired_writes: 0,
+            mode: FileMode::Append,
+        })
+    }
+
+    /// Whether the file was opened from a path, rather than from a handle.
+    pub(crate) fn has_path(&self) -> bool {
+        !self.path.as_os_str().is_empty()
+    }
+
+    /// How the file was opened.
+    pub fn mode(&self) -> FileMode {
+        self.mode
+    }
+
+    /// Checks that the regions written since the last verification still hold the expected bytes,
+    /// and rewrites those that do not.
+    fn verify(&mut self) {
+        let regions = std::mem::take(&mut self.unverified);
+        self.unverified_bytes = 0;
+        if regions.is_empty() {
+            return;
+        }
+        if self.reader.is_none() {
+            self.reader = File::open(&self.path).ok();
+        }
+        for (pos, expected) in regions {
+            let mut actual = vec![0; expected.len()];
+            let read = self.reader.as_mut().is_some_and(|reader| reader.seek(SeekFrom::Start(pos)).and_then(|_| reader.read_exact(&mut actual)).is_ok());
+            if read && actual == expected {
+                continue;
+            }
+            eprintln!("mtlog: verification of {} failed at offset {pos}, rewriting {} bytes", self.path.display(), expected.len());
+            let rewritten = self.file.seek(SeekFrom::Start(pos)).and_then(|_| self.file.write_all(&expected)).and_then(|()| self.file.seek(SeekFrom::End(0)));
+            match rewritten {
+                Ok(_) => self.repaired_writes += 1,
+                Err(err) => eprintln!("mtlog: unable to rewrite {}: {err}", self.path.display()),
+            }
+        }
+    }
+
+    /// Records a written region to verify. Earlier regions it overwrites are updated, so that
+    /// rewritten progress lines are not restored to a previous frame.
+    fn record_write(&mut self, pos: u64, content: &str) {
+        if !self.verify_writes || !self.has_path() {
+            return;
+        }
+        let (content, end) = (content.as_bytes(), pos + content.len() as u64);
+        let mut covered = false;
+        for (start, expected) in &mut self.unverified {
+            let (from, to) = (pos.max(*start), end.min(*start + expected.len() as u64));
+            if from < to {
+                expected[(from - *start) as usize..(to - *start) as usize].copy_from_slice(&content[(from - pos) as usize..(to - pos) as usize]);
+            }
+            covered |= from == pos && to == end;
+        }
+        match self.unverified.last_mut() {
+            Some((start, expected)) if *start + expected.len() as u64 == pos => expected.extend_from_slice(content),
+            _ if !covered => self.unverified.push((pos, content.into())),
+            _ => (),
+        }
+        self.unverified_bytes += content.len();
+        if self.unverified_bytes >= MAX_UNVERIFIED_BYTES {
+            self.verify();
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        self.verify();
+    }
+}
+
+impl LogFile {
+    fn try_regular(&mut self, line: &str) -> std::io::Result<()> {
+        let pos = self.file.stream_position()?;
+        writeln!(self.file,"{line}")?;
+        self.record_write(pos, &format!("{line}\n"));
+        Ok(())
+    }
+
+    /// Rewrites the line of the bar in place, padded with spaces to cover the previous frame. A frame
+    /// longer than the line, which would spill over the next lines, blanks it and is written as a
+    /// new line at the end of the file instead, unless the line is the last one.
+    fn try_progress(&mut self, line: &str, id: Uuid) -> std::io::Result<()> {
+        match self.progress_positions.get(&id).copied() {
+            Some((pos, len)) if line.len() <= len => {
+                let line = format!("{line}{}", " ".repeat(len - line.len()));
+                replace_line_in_file(&mut self.file,&line,pos)?;
+                self.record_write(pos, &line);
+            }
+            Some((pos, len)) if self.file.metadata()?.len() == pos + len as u64 + 1 => {
+                let line = format!("{line}\n");
+                replace_line_in_file(&mut self.file,&line,pos)?;
+                self.progress_positions.insert(id, (pos, line.len() - 1));
+                self.record_write(pos, &line);
+            }
+            slot => {
+                if let Some((pos, len)) = slot {
+                    let blank = " ".repeat(len);
+                    replace_line_in_file(&mut self.file,&blank,pos)?;
+                    self.record_write(pos, &blank);
+                }
+                let pos = self.file.metadata()?.len();
+                writeln!(self.file,"{line}")?;
+                self.progress_positions.insert(id, (pos, line.len()));
+                self.record_write(pos, &format!("{line}\n"));
+            }
+        }
+        Ok(())
+    }
+
+    fn try_progress_with_header(&mut self, header: &str, line: &str, id: Uuid) -> std::io::Result<()> {
+        if self.progress_positions.contains_key(&id) {
+            return self.try_progress(line, id);
+        }
+        let pos = self.file.metadata()?.len();
+        let line = format!("{header} {line}");
+        writeln!(self.file,"{line}")?;
+        self.progress_positions.insert(id, (pos + header.len() as u64 + 1, line.len() - header.len() - 1));
+        self.record_write(pos, &format!("{line}\n"));
+        Ok(())
     }
 }
 
 impl LogWriter for LogFile {
     fn regular(&mut self, line: &str) {
-        writeln!(self.file,"{line}").unwrap()
+        self.try_regular(line).unwrap();
     }
 
     fn progress(&mut self, line: &str, id: Uuid) {
-        if let Some(pos) = self.progress_positions.get(&id) {
-            replace_line_in_file(&mut self.file,line,*pos);
+        self.try_progress(line, id).unwrap();
+    }
+
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        self.try_progress_with_header(header, line, id).unwrap();
+    }
+
+    fn finished(&mut self, id: Uuid) {
+        self.progress_positions.remove(&id);
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().unwrap();
+        self.verify();
+    }
+
+    /// Reopens the path, creating a new file if the current one was moved away.
+    /// Progress bars of the previous file are no longer updated.
+    fn reopen(&mut self) {
+        self.file.flush().ok();
+        if !self.has_path() {
+            return;
+        }
+        match LogFile::new(&self.path) {
+            Ok(mut file) => {
+                file.verify_writes = self.verify_writes;
+                self.verify();
+                file.repaired_writes = self.repaired_writes;
+                *self = file;
+            },
+            Err(err) => eprintln!("mtlog: unable to reopen {}: {err}", self.path.display()),
+        }
+    }
+}
+
+/// Maximum number of writes buffered by a [`PendingLogFile`] until its file is opened.
+const MAX_PENDING_WRITES: usize = 10_000;
+
+/// Delay between two checks of a [`PendingLogFile`] with buffered writes for its opened file.
+const PENDING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A write received before the file of a [`PendingLogFile`] was opened.
+enum PendingWrite {
+    Regular(String),
+    Progress { header: Option<String>, line: String, id: Uuid },
+    Finished(Uuid),
+}
+
+impl PendingWrite {
+    fn write_to(&self, file: &mut LogFile) -> std::io::Result<()> {
+        match self {
+            PendingWrite::Regular(line) => file.try_regular(line),
+            PendingWrite::Progress { header: Some(header), line, id } => file.try_progress_with_header(header, line, *id),
+            PendingWrite::Progress { header: None, line, id } => file.try_progress(line, *id),
+            PendingWrite::Finished(id) => {
+                file.finished(*id);
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Log file whose path is not available yet (e.g. a volume mounted after startup), opened by a
+/// background thread retrying with an exponential backoff.
+///
+/// Writes are buffered in memory until the file is opened, then written at its end in order,
+/// without waiting for the next write. They are dropped once every attempt failed.
+pub struct PendingLogFile {
+    /// File opened by the retry thread, taken on the next write.
+    opened: Arc<Mutex<Option<LogFile>>>,
+    /// Set by the retry thread once every attempt failed.
+    gave_up: Arc<AtomicBool>,
+    file: Option<LogFile>,
+    path: PathBuf,
+    pending: VecDeque<PendingWrite>,
+    /// Number of writes dropped while the buffer was full.
+    dropped_writes: usize,
+    /// Passed on to the file once opened.
+    pub verify_writes: bool,
+}
+
+impl PendingLogFile {
+    /// Opens the file, or starts retrying in the background if the path is not found or not
+    /// accessible yet: `attempts` more times, waiting `backoff` then doubling it between attempts.
+    /// Other errors are returned.
+    pub fn new<P:AsRef<std::path::Path>>(path: P, attempts: usize, backoff: Duration) -> Result<Self,std::io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let (file, opened, gave_up) = match LogFile::new(&path) {
+            Ok(file) => (Some(file), Arc::default(), Arc::default()),
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) => {
+                let opened: Arc<Mutex<Option<LogFile>>> = Arc::default();
+                let gave_up: Arc<AtomicBool> = Arc::default();
+                let (thread_opened, thread_gave_up, thread_path) = (opened.clone(), gave_up.clone(), path.clone());
+                std::thread::spawn(move || {
+                    let mut backoff = backoff;
+                    for _ in 0..attempts {
+                        std::thread::sleep(backoff);
+                        if let Ok(file) = LogFile::new(&thread_path) {
+                            *thread_opened.lock().unwrap() = Some(file);
+                            return;
+                        }
+                        backoff = backoff.saturating_mul(2);
+                    }
+                    thread_gave_up.store(true, Ordering::SeqCst);
+                });
+                (None, opened, gave_up)
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Self { opened, gave_up, file, path, pending: VecDeque::new(), dropped_writes: 0, verify_writes: false })
+    }
+
+    /// Returns the file if opened, writing the buffered writes first. They are dropped from the
+    /// first one failing.
+    fn poll(&mut self) -> Option<&mut LogFile> {
+        if self.file.is_none() {
+            if let Some(mut file) = self.opened.lock().unwrap().take() {
+                file.verify_writes = self.verify_writes;
+                if self.dropped_writes > 0 {
+                    eprintln!("mtlog: {} writes to {} were dropped while it could not be opened", self.dropped_writes, self.path.display());
+                }
+                let mut pending = self.pending.drain(..);
+                while let Some(write) = pending.next() {
+                    if let Err(err) = write.write_to(&mut file) {
+                        eprintln!("mtlog: unable to write to {}: {err}, dropping {} buffered writes", self.path.display(), pending.len() + 1);
+                        break;
+                    }
+                }
+                self.file = Some(file);
+            } else if self.gave_up.load(Ordering::SeqCst) && !self.pending.is_empty() {
+                eprintln!("mtlog: unable to open {}, dropping {} writes", self.path.display(), self.pending.len() + self.dropped_writes);
+                self.pending.clear();
+            }
+        }
+        self.file.as_mut()
+    }
+
+    fn buffer(&mut self, write: PendingWrite) {
+        if self.gave_up.load(Ordering::SeqCst) {
+            return;
+        }
+        if self.pending.len() < MAX_PENDING_WRITES {
+            self.pending.push_back(write);
         } else {
-            let pos = self.file.metadata().unwrap().len();
-            self.progress_positions.insert(id, pos);
-            writeln!(self.file,"{line}").unwrap();
+            self.dropped_writes += 1;
+        }
+    }
+}
+
+impl LogWriter for PendingLogFile {
+    fn regular(&mut self, line: &str) {
+        if let Some(file) = self.poll() {
+            file.regular(line);
+        } else {
+            self.buffer(PendingWrite::Regular(line.into()));
+        }
+    }
+
+    fn progress(&mut self, line: &str, id: Uuid) {
+        if let Some(file) = self.poll() {
+            file.progress(line, id);
+        } else {
+            self.buffer(PendingWrite::Progress { header: None, line: line.into(), id });
+        }
+    }
+
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        if let Some(file) = self.poll() {
+            file.progress_with_header(header, line, id);
+        } else {
+            self.buffer(PendingWrite::Progress { header: Some(header.into()), line: line.into(), id });
         }
     }
 
     fn finished(&mut self, id: Uuid) {
-        self.progress_positions.remove(&id);
+        if let Some(file) = self.poll() {
+            file.finished(id);
+        } else {
+            self.buffer(PendingWrite::Finished(id));
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(file) = self.poll() {
+            file.flush();
+        }
+    }
+
+    fn reopen(&mut self) {
+        if let Some(file) = self.poll() {
+            file.reopen();
+        }
+    }
+
+    fn write_pending(&mut self) -> Option<Duration> {
+        (self.poll().is_none() && !self.pending.is_empty()).then_some(PENDING_POLL_INTERVAL)
+    }
+}
+
+impl Drop for PendingLogFile {
+    fn drop(&mut self) {
+        self.poll();
+    }
+}
+
+/// Number of consecutive failed writes after which a [`FallbackLogFile`] switches to its fallback.
+const MAX_FAILED_WRITES: usize = 3;
+
+/// Delay between two attempts of a [`FallbackLogFile`] to write to its primary file again.
+const PRIMARY_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Log file switching to a fallback path when writes to the primary file keep failing, e.g. on a
+/// volume gone read-only, and back to the primary once it is writable again.
+///
+/// Each switch is marked by a line in the fallback file. The failed writes that triggered the
+/// switch are written to the fallback, and progress bars start on a new line after each switch.
+pub struct FallbackLogFile {
+    primary: LogFile,
+    fallback_path: PathBuf,
+    /// Open while the primary is failing.
+    fallback: Option<LogFile>,
+    /// Writes that failed on the primary since the last successful one.
+    failed: Vec<PendingWrite>,
+    last_probe: Instant,
+    probe_interval: Duration,
+}
+
+impl FallbackLogFile {
+    pub fn new<P:AsRef<std::path::Path>>(primary: LogFile, fallback_path: P) -> Self {
+        Self { primary, fallback_path: fallback_path.as_ref().into(), fallback: None, failed: Vec::new(), last_probe: Instant::now(), probe_interval: PRIMARY_PROBE_INTERVAL }
+    }
+
+    fn write(&mut self, write: PendingWrite) {
+        if self.fallback.is_some() && self.last_probe.elapsed() >= self.probe_interval {
+            self.probe_primary();
+        }
+        if let Some(fallback) = &mut self.fallback {
+            if let Err(err) = write.write_to(fallback) {
+                eprintln!("mtlog: unable to write to {}: {err}", self.fallback_path.display());
+            }
+            return;
+        }
+        match write.write_to(&mut self.primary) {
+            Ok(()) => self.failed.clear(),
+            Err(err) => {
+                if self.failed.len() < MAX_PENDING_WRITES {
+                    self.failed.push(write);
+                }
+                if self.failed.len() >= MAX_FAILED_WRITES {
+                    self.switch_to_fallback(err);
+                }
+            },
+        }
+    }
+
+    fn switch_to_fallback(&mut self, err: std::io::Error) {
+        let mut fallback = match LogFile::new(&self.fallback_path) {
+            Ok(fallback) => fallback,
+            Err(fallback_err) => {
+                eprintln!("mtlog: unable to write to {} ({err}) nor to open {}: {fallback_err}", self.primary.path.display(), self.fallback_path.display());
+                return;
+            },
+        };
+        fallback.verify_writes = self.primary.verify_writes;
+        let marker = format!("===== mtlog: {} failed {} times in a row ({err}), writing here =====", self.primary.path.display(), self.failed.len());
+        let written = std::iter::once(PendingWrite::Regular(marker)).chain(self.failed.drain(..)).try_for_each(|write| write.write_to(&mut fallback));
+        if let Err(err) = written {
+            eprintln!("mtlog: unable to write to {}: {err}", self.fallback_path.display());
+        }
+        self.fallback = Some(fallback);
+        self.last_probe = Instant::now();
+    }
+
+    /// Switches back to the primary file if a line can be written to it again.
+    fn probe_primary(&mut self) {
+        self.last_probe = Instant::now();
+        let Ok(mut primary) = LogFile::new(&self.primary.path) else {
+            return;
+        };
+        if primary.try_regular(&format!("===== mtlog: resuming after writing to {} =====", self.fallback_path.display())).is_err() {
+            return;
+        }
+        primary.verify_writes = self.primary.verify_writes;
+        if let Some(mut fallback) = self.fallback.take() {
+            fallback.try_regular(&format!("===== mtlog: {} is writable again, writing there =====", primary.path.display())).ok();
+        }
+        self.primary = primary;
+    }
+
+    fn current(&mut self) -> &mut LogFile {
+        self.fallback.as_mut().unwrap_or(&mut self.primary)
     }
 }
 
+impl LogWriter for FallbackLogFile {
+    fn regular(&mut self, line: &str) {
+        self.write(PendingWrite::Regular(line.into()));
+    }
+
+    fn progress(&mut self, line: &str, id: Uuid) {
+        self.write(PendingWrite::Progress { header: None, line: line.into(), id });
+    }
+
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        self.write(PendingWrite::Progress { header: Some(header.into()), line: line.into(), id });
+    }
+
+    fn finished(&mut self, id: Uuid) {
+        self.current().finished(id);
+    }
+
+    fn flush(&mut self) {
+        self.current().file.flush().ok();
+    }
+
+    fn reopen(&mut self) {
+        self.current().reopen();
+    }
+}
+
+/// Writer re-emitting each regular record as a `tracing` event of the matching level, with the
+/// logger name as the `name` field, e.g. to keep a `tracing` subscriber in sync during a migration.
+/// Progress bars are not forwarded.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default)]
+pub struct TracingForwardWriter;
+
+#[cfg(feature = "tracing")]
+impl LogWriter for TracingForwardWriter {
+    fn regular(&mut self, line: &str) {
+        tracing::info!(target: "mtlog", "{line}");
+    }
+
+    fn regular_message(&mut self, _line: &str, log_message: &LogMessage) {
+        let LogMessage { message, level, name, fields } = log_message;
+        let mut message = message.clone();
+        for (key, value) in fields {
+            message.push_str(&format!(" {key}={value}"));
+        }
+        let name = name.as_deref();
+        match level {
+            log::Level::Error => tracing::error!(target: "mtlog", name, "{message}"),
+            log::Level::Warn => tracing::warn!(target: "mtlog", name, "{message}"),
+            log::Level::Info => tracing::info!(target: "mtlog", name, "{message}"),
+            log::Level::Debug => tracing::debug!(target: "mtlog", name, "{message}"),
+            log::Level::Trace => tracing::trace!(target: "mtlog", name, "{message}"),
+        }
+    }
+
+    fn progress(&mut self, _line: &str, _id: Uuid) {}
+
+    fn finished(&mut self, _id: Uuid) {}
+}
+
+#[test]
+fn test_log_file_from_handle() {
+    let path = "/tmp/test_log_file_from_handle.log";
+    std::fs::write(path, "existing\n").unwrap();
+    let file = File::options().write(true).open(path).unwrap();
+    let mut log_file = LogFile::from_file(file).unwrap();
+    log_file.verify_writes = true;
+    let id = Uuid::new_v4();
+    log_file.progress("frame 1", id);
+    log_file.regular("line");
+    log_file.progress("frame 2", id);
+    log_file.reopen();
+    log_file.regular("after reopen");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "existing\nframe 2\nline\nafter reopen\n");
+}
+
 #[test]
 fn test_log_file() {
     std::fs::remove_file("/tmp/test_log_file.log").ok();
@@ -61,41 +666,505 @@ fn test_log_file() {
     assert_eq!(std::fs::read_to_string("/tmp/test_log_file.log").unwrap(),"Hello, world!\nLOREM IPSUM\nrust is awesome !\ntest\n");
 }
 
+#[test]
+fn test_log_file_frame_sizes() {
+    let path = "/tmp/test_log_file_frame_sizes.log";
+    let mut log_file = LogFile::open(path, FileMode::Truncate).unwrap();
+    let uuid = Uuid::new_v4();
+    log_file.progress("[..] 1/2", uuid);
+    log_file.regular("after");
+    // A shorter frame covers the previous one
+    log_file.progress("[.] 1/2", uuid);
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "[.] 1/2 \nafter\n");
+    // A frame longer in bytes moves the bar to a new line instead of spilling over the next ones
+    log_file.progress("[██] 2/2", uuid);
+    log_file.progress("[█] 2/2", uuid);
+    log_file.finished(uuid);
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "        \nafter\n[█] 2/2   \n");
+}
+
+#[test]
+fn test_log_file_modes() {
+    let path = "/tmp/test_log_file_modes.log";
+    std::fs::write(path, "previous run\n").unwrap();
+    LogFile::open(path, FileMode::Append).unwrap().regular("appended");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "previous run\nappended\n");
+    LogFile::open(path, FileMode::Truncate).unwrap().regular("truncated");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "truncated\n");
+    let err = LogFile::open(path, FileMode::CreateNew).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    std::fs::remove_file(path).unwrap();
+    LogFile::open(path, FileMode::CreateNew).unwrap().regular("created");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "created\n");
+}
+
+#[test]
+fn test_log_file_reopen() {
+    let (path, rotated) = ("/tmp/test_log_file_reopen.log", "/tmp/test_log_file_reopen.log.1");
+    std::fs::remove_file(path).ok();
+    let mut log_file = LogFile::new(path).unwrap();
+    log_file.regular("before");
+    std::fs::rename(path, rotated).unwrap();
+    log_file.reopen();
+    log_file.regular("after");
+    assert_eq!(std::fs::read_to_string(rotated).unwrap(), "before\n");
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "after\n");
+}
+
+#[test]
+fn test_log_file_progress_headers() {
+    let path = "/tmp/test_log_file_progress_headers.log";
+    std::fs::remove_file(path).ok();
+    let mut log_file = LogFile::new(path).unwrap();
+    let uuid = Uuid::new_v4();
+    log_file.regular("[2024-01-01T00:00:00.000 INFO] Hello, world!");
+    log_file.progress_with_header("[2024-01-01T00:00:01.000 INFO]", "lorem ipsum", uuid);
+    log_file.regular("[2024-01-01T00:00:02.000 INFO] rust is awesome !");
+    log_file.progress_with_header("[2024-01-01T00:00:03.000 INFO]", "LOREM IPSUM", uuid);
+    log_file.finished(uuid);
+    let content = std::fs::read_to_string(path).unwrap();
+    let (header, bar) = content.lines().nth(1).unwrap().split_once("] ").unwrap();
+    assert_eq!(header, "[2024-01-01T00:00:01.000 INFO");
+    assert_eq!(bar, "LOREM IPSUM");
+}
+
+#[test]
+fn test_log_file_verify_writes() {
+    let path = "/tmp/test_log_file_verify_writes.log";
+    std::fs::remove_file(path).ok();
+    let mut log_file = LogFile::new(path).unwrap();
+    log_file.verify_writes = true;
+    let uuid = Uuid::new_v4();
+    log_file.regular("first line");
+    log_file.progress("frame 1", uuid);
+    log_file.progress("frame 2", uuid);
+    File::options().write(true).open(path).unwrap().write_all(b"XXXXX").unwrap();
+    log_file.flush();
+    assert_eq!(log_file.repaired_writes, 1);
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "first line\nframe 2\n");
+    // Writes since the last flush are verified when the file is dropped
+    log_file.regular("second line");
+    File::options().append(true).open(path).unwrap().set_len(24).unwrap();
+    drop(log_file);
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "first line\nframe 2\nsecond line\n");
+}
+
+/// Writer keeping the rendered lines in memory, progress lines being updated in place.
 #[derive(Default, Debug)]
-pub struct LogStdout {
+pub struct MemoryWriter {
+    pub lines: Vec<String>,
+    progress_positions: HashMap<Uuid,usize>
+}
+
+impl LogWriter for MemoryWriter {
+    fn regular(&mut self, line: &str) {
+        self.lines.push(line.into());
+    }
+
+    fn progress(&mut self, line: &str, id: Uuid) {
+        if let Some(pos) = self.progress_positions.get(&id) {
+            self.lines[*pos] = line.into();
+        } else {
+            self.progress_positions.insert(id, self.lines.len());
+            self.lines.push(line.into());
+        }
+    }
+
+    fn finished(&mut self, id: Uuid) {
+        self.progress_positions.remove(&id);
+    }
+}
+
+/// Minimum delay between two redraws of the same progress bar on a terminal,
+/// faster updates are coalesced into the latest frame.
+const MIN_REDRAW_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Source of the instants the redraw intervals are measured with, `Instant::now` if `None`.
+#[derive(Clone, Default)]
+struct Ticker(Option<Arc<dyn Fn() -> Instant + Send + Sync>>);
+
+impl Ticker {
+    fn now(&self) -> Instant {
+        self.0.as_ref().map_or_else(Instant::now, |now| now())
+    }
+}
+
+impl std::fmt::Debug for Ticker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Ticker")
+    }
+}
+
+/// Terminal writer redrawing progress lines in place with cursor escape codes.
+#[derive(Debug)]
+pub struct LogStdout<W: Write = Stdout> {
+    output: W,
     progress_positions: HashMap<Uuid,usize>,
-    line_counter: usize
+    line_counter: usize,
+    ticker: Ticker,
+    last_redraws: HashMap<Uuid,Instant>,
+    pending_frames: HashMap<Uuid,String>,
+    /// Last frame drawn of each bar, drawn again when a line is printed above it.
+    frames: HashMap<Uuid,String>,
+    leave_on_finish: bool,
+    /// Minimum time between two redraws of any bar, from the redraw cap.
+    min_redraw_interval: Duration,
+    last_redraw: Option<Instant>,
+    /// Whether bars are redrawn in place with escape codes, or only their last frame written.
+    terminal: bool,
+    /// Set once the output is gone (broken pipe), nothing being written to it afterwards.
+    closed: bool
+}
+
+/// Terminal writer on stderr, used for progress bars kept out of stdout.
+pub type LogStderr = LogStdout<Stderr>;
+
+impl Default for LogStdout {
+    /// Writer on stdout, drawing bars as on a terminal only if stdout is one.
+    fn default() -> Self {
+        Self::new(std::io::stdout()).terminal(std::io::stdout().is_terminal())
+    }
+}
+
+impl<W: Write> LogStdout<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            progress_positions: HashMap::new(),
+            line_counter: 0,
+            ticker: Ticker::default(),
+            last_redraws: HashMap::new(),
+            pending_frames: HashMap::new(),
+            frames: HashMap::new(),
+            leave_on_finish: true,
+            min_redraw_interval: Duration::ZERO,
+            last_redraw: None,
+            terminal: true,
+            closed: false
+        }
+    }
+
+    /// Whether the output is drawn as a terminal (the default), bars being redrawn in place with
+    /// escape codes. Otherwise, e.g. for output redirected to a file, the last frame of each bar is
+    /// written once it is finished, as a plain line.
+    pub fn terminal(self, terminal: bool) -> Self {
+        Self { terminal, ..self }
+    }
+
+    /// Whether the line of a finished progress bar is kept, or cleared so that the next line overwrites it.
+    pub fn leave_on_finish(self, leave_on_finish: bool) -> Self {
+        Self { leave_on_finish, ..self }
+    }
+
+    /// Caps the number of progress redraws per second across all bars, the latest frame of each
+    /// bar being kept until it can be drawn. Final frames of finished bars are always drawn.
+    pub fn max_redraws_per_second(self, max_redraws_per_second: Option<u32>) -> Self {
+        let min_redraw_interval = max_redraws_per_second.map_or(Duration::ZERO, |n| Duration::from_secs(1) / n.max(1));
+        Self { min_redraw_interval, ..self }
+    }
+
+    /// Measures the redraw intervals with `now` instead of `Instant::now`, redraws being
+    /// throttled even in test mode.
+    #[cfg(test)]
+    fn with_ticker(self, now: impl Fn() -> Instant + Send + Sync + 'static) -> Self {
+        Self { ticker: Ticker(Some(Arc::new(now))), ..self }
+    }
+
+    /// Delay before a new frame of a bar can be drawn, given the per-bar and global intervals.
+    /// Always zero in test mode, unless the intervals are measured with another clock.
+    fn redraw_delay(&self, id: Uuid) -> Duration {
+        if in_test_mode() && self.ticker.0.is_none() {
+            return Duration::ZERO;
+        }
+        let now = self.ticker.now();
+        let remaining = |last: Option<&Instant>, interval: Duration| last.map_or(Duration::ZERO, |last| interval.saturating_sub(now.saturating_duration_since(*last)));
+        remaining(self.last_redraws.get(&id), MIN_REDRAW_INTERVAL).max(remaining(self.last_redraw.as_ref(), self.min_redraw_interval))
+    }
+
+    /// Marks the output as closed on a broken pipe, e.g. when stdout is piped to `head` which exited.
+    /// Other errors panic.
+    fn check(&mut self, result: std::io::Result<()>) {
+        match result {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => self.closed = true,
+            result => result.unwrap(),
+        }
+    }
+
+    /// Number of rows between the cursor and the line of a progress bar, e.g. 1 for the last line.
+    fn cursor_offset(&self, id: Uuid) -> Option<usize> {
+        self.progress_positions.get(&id).map(|pos| self.line_counter+1-pos)
+    }
+
+    /// Clears the line of a progress bar. The cursor is moved back to it if it is the last line,
+    /// otherwise the line is left blank.
+    fn clear(&mut self, id: Uuid) {
+        if let Some(pos) = self.cursor_offset(id) {
+            let result = if pos == 1 {
+                self.line_counter -= 1;
+                write!(self.output,"\x1B[1A\r\x1B[2K")
+            } else {
+                write!(self.output,"\x1B[{pos}A\r\x1B[2K\x1B[{pos}B\r")
+            };
+            let result = result.and_then(|()| self.output.flush());
+            self.check(result);
+        }
+    }
+
+    fn redraw(&mut self, line: &str, id: Uuid) {
+        if let Some(pos) = self.cursor_offset(id) {
+            let result = write!(self.output,"\x1B[{pos}A\r{line}\x1B[{pos}B\r").and_then(|()| self.output.flush());
+            self.check(result);
+            self.remember_frame(line, id);
+            let now = self.ticker.now();
+            self.last_redraws.insert(id, now);
+            self.last_redraw = Some(now);
+        }
+    }
+
+    fn remember_frame(&mut self, line: &str, id: Uuid) {
+        let frame = self.frames.entry(id).or_default();
+        frame.clear();
+        frame.push_str(line);
+    }
 }
 
-impl LogWriter for LogStdout {
+impl<W: Write> LogWriter for LogStdout<W> {
     fn regular(&mut self, line: &str) {
+        if self.closed {
+            return;
+        }
         if !self.progress_positions.is_empty(){
-            self.line_counter += 1;
+            // A line with embedded newlines takes several rows
+            self.line_counter += 1 + line.matches('\n').count();
         }
-        println!("{line}");
+        let result = writeln!(self.output,"{line}");
+        self.check(result);
     }
 
     fn progress(&mut self, line: &str, id: Uuid) {
-        if let Some(pos) = self.progress_positions.get(&id) {
-            let pos = self.line_counter+1-pos;       
-            print!("\x1B[{pos}A\r");
-            print!("{line}");
-            print!("\x1B[{pos}B\r");
+        if self.closed {
+            return;
+        }
+        if !self.terminal {
+            return self.remember_frame(line, id);
+        }
+        if self.progress_positions.contains_key(&id) {
+            if self.redraw_delay(id).is_zero() {
+                self.pending_frames.remove(&id);
+                self.redraw(line, id);
+            } else {
+                self.pending_frames.insert(id, line.into());
+            }
         } else {
-            println!("{line}");
+            let result = writeln!(self.output,"{line}");
+            self.check(result);
             self.line_counter += 1;
             self.progress_positions.insert(id, self.line_counter);
+            self.remember_frame(line, id);
+            self.last_redraws.insert(id, self.ticker.now());
         }
     }
 
     fn finished(&mut self, id: Uuid) {
+        if self.closed {
+            return;
+        }
+        if !self.terminal {
+            if let Some(frame) = self.frames.remove(&id).filter(|_| self.leave_on_finish) {
+                self.regular(&frame);
+            }
+            return;
+        }
+        if let Some(line) = self.pending_frames.remove(&id) {
+            self.redraw(&line, id);
+        }
+        if !self.leave_on_finish {
+            self.clear(id);
+        }
+        self.last_redraws.remove(&id);
+        self.frames.remove(&id);
         self.progress_positions.remove(&id);
         if self.progress_positions.is_empty(){
             self.line_counter = 0;
         }
     }
+
+    /// Clears the bar, writes the line and draws the bar again below it. A bar above other lines
+    /// leaves a blank line where it was.
+    fn println(&mut self, line: &str, _log_message: &LogMessage, id: Uuid) {
+        if self.closed {
+            return;
+        }
+        let Some(frame) = self.pending_frames.remove(&id).or_else(|| self.frames.remove(&id)) else {
+            return self.regular(line);
+        };
+        self.clear(id);
+        self.progress_positions.remove(&id);
+        if self.progress_positions.is_empty() {
+            self.line_counter = 0;
+        }
+        self.regular(line);
+        self.progress(&frame, id);
+    }
+
+    fn flush(&mut self) {
+        if !self.closed {
+            let result = self.output.flush();
+            self.check(result);
+        }
+    }
+
+    fn write_pending(&mut self) -> Option<Duration> {
+        let ids = self.pending_frames.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            if self.redraw_delay(id).is_zero() {
+                if let Some(line) = self.pending_frames.remove(&id) {
+                    self.redraw(&line, id);
+                }
+            }
+        }
+        self.pending_frames.keys().map(|id| self.redraw_delay(*id)).min()
+    }
+}
+
+/// Terminal writer for applications drawing their own bars with an indicatif `MultiProgress`:
+/// regular lines are printed with the bars suspended, and progress lines are drawn as bars of the
+/// `MultiProgress`, so that neither corrupts the other.
+#[cfg(feature = "indicatif")]
+pub struct MultiProgressWriter<W: Write = Stdout> {
+    output: W,
+    multi: indicatif::MultiProgress,
+    bars: HashMap<Uuid,indicatif::ProgressBar>,
+    leave_on_finish: bool,
+    /// Whether the output was closed, e.g. a broken pipe. Nothing is written to it afterwards.
+    closed: bool,
+}
+
+#[cfg(feature = "indicatif")]
+impl MultiProgressWriter {
+    pub fn new(multi: indicatif::MultiProgress) -> Self {
+        Self::with_output(multi, std::io::stdout())
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl<W: Write> MultiProgressWriter<W> {
+    pub fn with_output(multi: indicatif::MultiProgress, output: W) -> Self {
+        Self { output, multi, bars: HashMap::new(), leave_on_finish: true, closed: false }
+    }
+
+    /// Whether the bar of a finished progress line is kept, or removed from the `MultiProgress`.
+    pub fn leave_on_finish(self, leave_on_finish: bool) -> Self {
+        Self { leave_on_finish, ..self }
+    }
+
+    /// Marks the output as closed on a broken pipe, as [`LogStdout`] does. Other errors panic.
+    fn check(&mut self, result: std::io::Result<()>) {
+        match result {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => self.closed = true,
+            result => result.unwrap(),
+        }
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl<W: Write> LogWriter for MultiProgressWriter<W> {
+    fn regular(&mut self, line: &str) {
+        if self.closed {
+            return;
+        }
+        let Self { output, multi, .. } = self;
+        let result = multi.suspend(|| writeln!(output,"{line}").and_then(|()| output.flush()));
+        self.check(result);
+    }
+
+    fn progress(&mut self, line: &str, id: Uuid) {
+        let multi = &self.multi;
+        let bar = self.bars.entry(id).or_insert_with(|| {
+            let bar = multi.add(indicatif::ProgressBar::no_length());
+            bar.set_style(indicatif::ProgressStyle::with_template("{msg}").unwrap());
+            bar
+        });
+        bar.set_message(line.to_string());
+    }
+
+    fn finished(&mut self, id: Uuid) {
+        if let Some(bar) = self.bars.remove(&id) {
+            if self.leave_on_finish {
+                bar.finish();
+            } else {
+                bar.finish_and_clear();
+                self.multi.remove(&bar);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.closed {
+            let result = self.output.flush();
+            self.check(result);
+        }
+    }
+}
+
+/// In-memory output shared between a writer and a test.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pending_log_file() {
+    let dir = "/tmp/test_pending_log_file";
+    let path = format!("{dir}/app.log");
+    std::fs::remove_dir_all(dir).ok();
+    let mut log_file = PendingLogFile::new(&path, 10, Duration::from_millis(10)).unwrap();
+    log_file.regular("early 1");
+    log_file.regular("early 2");
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(log_file.write_pending(), Some(PENDING_POLL_INTERVAL));
+    std::fs::create_dir(dir).unwrap();
+    // The buffered writes are written once the file is opened, before any other write
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while log_file.write_pending().is_some() {
+        assert!(Instant::now() < deadline);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "early 1\nearly 2\n");
+    log_file.regular("late");
+    drop(log_file);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "early 1\nearly 2\nlate\n");
 }
 
+#[cfg(target_os = "linux")]
+#[test]
+fn test_pending_log_file_failed_replay() {
+    let mut log_file = PendingLogFile::new("/tmp/test_pending_log_file_failed_replay/app.log", 1, Duration::from_secs(60)).unwrap();
+    log_file.regular("early 1");
+    log_file.regular("early 2");
+    // Writes to /dev/full fail with ENOSPC
+    *log_file.opened.lock().unwrap() = Some(LogFile::new("/dev/full").unwrap());
+    assert_eq!(log_file.write_pending(), None);
+    assert!(log_file.pending.is_empty());
+}
 
 #[test]
 fn test_log_stdout() {
@@ -112,3 +1181,206 @@ fn test_log_stdout() {
     log_stdout.progress("LOREM IPSUM", uuid_1);
     log_stdout.finished(uuid_1);
 }
+
+#[test]
+fn test_log_stdout_multiline() {
+    let output = SharedBuffer::default();
+    let mut log_stdout = LogStdout::new(output.clone());
+    let (uuid_1, uuid_2) = (Uuid::new_v4(), Uuid::new_v4());
+    log_stdout.progress("bar 1", uuid_1);
+    log_stdout.regular("one\ntwo\nthree");
+    assert_eq!(log_stdout.cursor_offset(uuid_1), Some(4));
+    log_stdout.progress("bar 2", uuid_2);
+    assert_eq!(log_stdout.cursor_offset(uuid_1), Some(5));
+    assert_eq!(log_stdout.cursor_offset(uuid_2), Some(1));
+    std::thread::sleep(MIN_REDRAW_INTERVAL);
+    log_stdout.progress("BAR 1", uuid_1);
+    assert!(output.contents().ends_with("\x1B[5A\rBAR 1\x1B[5B\r"));
+}
+
+#[test]
+fn test_log_stdout_coalescing() {
+    let output = SharedBuffer::default();
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let ticker = now.clone();
+    let mut log_stdout = LogStdout::new(output.clone()).with_ticker(move || *ticker.lock().unwrap());
+    let uuid = Uuid::new_v4();
+    // One redraw every 20 ms at most, whatever the rate of updates
+    for i in 0..990 {
+        log_stdout.progress(&format!("frame {i}"), uuid);
+        *now.lock().unwrap() += Duration::from_micros(100);
+    }
+    assert_eq!(output.contents().matches("\x1B[1A").count(), 4);
+    // The last frame is drawn once its delay elapsed, without waiting for another update
+    assert_eq!(log_stdout.write_pending(), Some(Duration::from_millis(1)));
+    assert!(output.contents().ends_with("frame 800\x1B[1B\r"));
+    *now.lock().unwrap() += Duration::from_millis(1);
+    assert_eq!(log_stdout.write_pending(), None);
+    assert!(output.contents().ends_with("\x1B[1A\rframe 989\x1B[1B\r"));
+    log_stdout.finished(uuid);
+    assert_eq!(output.contents().matches("\x1B[1A").count(), 5);
+}
+
+#[test]
+fn test_log_stdout_redraw_cap() {
+    let output = SharedBuffer::default();
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let ticker = now.clone();
+    let mut log_stdout = LogStdout::new(output.clone()).max_redraws_per_second(Some(50)).with_ticker(move || *ticker.lock().unwrap());
+    let ids: Vec<_> = (0..5).map(|_| Uuid::new_v4()).collect();
+    for id in &ids {
+        log_stdout.progress("frame 0", *id);
+    }
+    // 200 ms of updates every 5 ms allow 10 redraws across the bars
+    for i in 1..=40 {
+        *now.lock().unwrap() += Duration::from_millis(5);
+        for id in &ids {
+            log_stdout.progress(&format!("frame {i}"), *id);
+        }
+    }
+    assert_eq!(output.contents().matches("A\r").count(), 10);
+    for id in &ids {
+        log_stdout.finished(*id);
+    }
+    assert_eq!(output.contents().matches("frame 40").count(), ids.len());
+}
+
+#[test]
+fn test_log_stdout_println() {
+    let output = SharedBuffer::default();
+    let mut log_stdout = LogStdout::new(output.clone());
+    let (uuid_1, uuid_2) = (Uuid::new_v4(), Uuid::new_v4());
+    let message = LogMessage { message: "ignored".into(), level: log::Level::Info, name: None, fields: Vec::new() };
+    log_stdout.progress("bar 1", uuid_1);
+    log_stdout.println("above 1", &message, uuid_1);
+    assert_eq!(output.contents(), "bar 1\n\x1B[1A\r\x1B[2Kabove 1\nbar 1\n");
+    assert_eq!(log_stdout.cursor_offset(uuid_1), Some(1));
+    // A bar above another one is moved to the bottom
+    log_stdout.progress("bar 2", uuid_2);
+    log_stdout.println("above 2", &message, uuid_1);
+    assert!(output.contents().ends_with("bar 2\n\x1B[2A\r\x1B[2K\x1B[2B\rabove 2\nbar 1\n"));
+    assert_eq!(log_stdout.cursor_offset(uuid_1), Some(1));
+    assert_eq!(log_stdout.cursor_offset(uuid_2), Some(3));
+    // Lines of finished bars are printed as regular lines
+    log_stdout.finished(uuid_1);
+    log_stdout.finished(uuid_2);
+    log_stdout.println("after", &message, uuid_1);
+    assert!(output.contents().ends_with("bar 1\nafter\n"));
+}
+
+#[test]
+fn test_log_stdout_clear_on_finish() {
+    let output = SharedBuffer::default();
+    let mut log_stdout = LogStdout::new(output.clone()).leave_on_finish(false);
+    let uuid = Uuid::new_v4();
+    log_stdout.regular("Hello, world!");
+    log_stdout.progress("lorem ipsum", uuid);
+    log_stdout.finished(uuid);
+    log_stdout.regular("next line");
+    assert_eq!(output.contents(), "Hello, world!\nlorem ipsum\n\x1B[1A\r\x1B[2Knext line\n");
+}
+
+#[cfg(feature = "indicatif")]
+#[test]
+fn test_multi_progress_writer() {
+    use indicatif::{InMemoryTerm, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+    /// Output recording what the terminal displays when each log line is written.
+    struct Snapshots(InMemoryTerm, Vec<String>);
+    impl Write for &mut Snapshots {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.push(self.0.contents());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let term = InMemoryTerm::new(10, 80);
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::term_like(Box::new(term.clone())));
+    let bar = multi.add(ProgressBar::new(10).with_style(ProgressStyle::with_template("indicatif {pos}/{len}").unwrap()));
+    bar.inc(5);
+    bar.tick();
+    assert_eq!(term.contents(), "indicatif 5/10");
+    let mut snapshots = Snapshots(term.clone(), Vec::new());
+    let mut writer = MultiProgressWriter::with_output(multi, &mut snapshots);
+    writer.regular("Hello, world!");
+    let uuid = Uuid::new_v4();
+    writer.progress("mtlog 1/2", uuid);
+    writer.progress("mtlog 2/2", uuid);
+    writer.finished(uuid);
+    drop(writer);
+    assert!(snapshots.1.iter().all(String::is_empty));
+    assert_eq!(term.contents(), "indicatif 5/10\nmtlog 2/2");
+}
+
+#[cfg(feature = "indicatif")]
+#[test]
+fn test_multi_progress_writer_broken_pipe() {
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+    struct BrokenPipe(usize);
+    impl Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            self.0 += 1;
+            Err(ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut writer = MultiProgressWriter::with_output(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), BrokenPipe(0));
+    writer.regular("Hello, world!");
+    writer.regular("Hello again!");
+    writer.flush();
+    // Nothing is written once the pipe is found broken
+    assert!(writer.closed);
+    assert_eq!(writer.output.0, 1);
+}
+
+#[test]
+fn test_fallback_log_file() {
+    let (path, fallback_path) = ("/tmp/test_fallback_log_file.log", "/tmp/test_fallback_log_file.fallback.log");
+    std::fs::remove_file(fallback_path).ok();
+    let mut writer = FallbackLogFile::new(LogFile::open(path, FileMode::Truncate).unwrap(), fallback_path);
+    writer.probe_interval = Duration::MAX;
+    writer.regular("before");
+    // The primary volume becomes unwritable
+    writer.primary.file = File::options().write(true).open("/dev/full").unwrap();
+    writer.regular("failed 1");
+    writer.regular("failed 2");
+    assert!(writer.fallback.is_none());
+    writer.regular("failed 3");
+    writer.regular("after");
+    writer.probe_interval = Duration::ZERO;
+    writer.regular("recovered");
+    let fallback = std::fs::read_to_string(fallback_path).unwrap();
+    let lines = fallback.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 6);
+    assert!(lines[0].starts_with(&format!("===== mtlog: {path} failed 3 times in a row (")));
+    assert_eq!(lines[1..5], ["failed 1", "failed 2", "failed 3", "after"]);
+    assert_eq!(lines[5], format!("===== mtlog: {path} is writable again, writing there ====="));
+    let primary = std::fs::read_to_string(path).unwrap();
+    assert_eq!(primary, format!("before\n===== mtlog: resuming after writing to {fallback_path} =====\nrecovered\n"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_forward_writer() {
+    let output = SharedBuffer::default();
+    let make_writer = {
+        let output = output.clone();
+        move || output.clone()
+    };
+    let subscriber = tracing_subscriber::fmt().with_writer(make_writer).with_ansi(false).without_time().with_max_level(tracing::Level::TRACE).finish();
+    tracing::subscriber::with_default(subscriber, || {
+        let mut writer = TracingForwardWriter;
+        let message = |level, name: Option<&str>| LogMessage { message: "disk almost full".into(), level, name: name.map(Into::into), fields: vec![("free".into(), "3%".into())] };
+        writer.regular_message("ignored", &message(log::Level::Warn, Some("worker")));
+        writer.regular_message("ignored", &message(log::Level::Debug, None));
+        writer.progress("bar", Uuid::new_v4());
+    });
+    let contents = output.contents();
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], r#" WARN mtlog: disk almost full free=3% name="worker""#);
+    assert_eq!(lines[1], "DEBUG mtlog: disk almost full free=3%");
+}
@@ -0,0 +1,68 @@
+use chrono::{Local, NaiveTime};
+use log::{Level, LevelFilter};
+
+/// Daily time range in local time, from `start` included to `end` excluded.
+/// A range ending before it starts crosses midnight, e.g. from 22:00 to 06:00.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeRange {
+    /// Builds a range from `(hour, minute)` pairs, e.g. `TimeRange::new((22, 0), (6, 0))`.
+    ///
+    /// # Panics
+    /// If an hour is above 23 or a minute above 59.
+    pub fn new(start: (u32, u32), end: (u32, u32)) -> Self {
+        let time = |(hour, minute)| NaiveTime::from_hms_opt(hour, minute, 0).unwrap_or_else(|| panic!("Invalid time of day {hour:02}:{minute:02}"));
+        Self { start: time(start), end: time(end) }
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Maximum levels of a sink by time of day.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule(pub Vec<(TimeRange, LevelFilter)>);
+
+impl Schedule {
+    /// Most restrictive level of the ranges containing `time`, `Trace` if there are none.
+    pub fn level_at(&self, time: NaiveTime) -> LevelFilter {
+        self.0.iter()
+            .filter(|(range, _)| range.contains(time))
+            .map(|(_, level)| *level)
+            .min()
+            .unwrap_or(LevelFilter::Trace)
+    }
+
+    /// Whether a message logged now at `level` passes the schedule.
+    pub fn allows(&self, level: Level) -> bool {
+        self.0.is_empty() || level <= self.level_at(Local::now().time())
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let at = |hour, minute, second| NaiveTime::from_hms_opt(hour, minute, second).unwrap();
+    let schedule = Schedule(vec![
+        (TimeRange::new((22, 0), (6, 0)), LevelFilter::Warn),
+        (TimeRange::new((5, 0), (7, 0)), LevelFilter::Error),
+        (TimeRange::new((12, 0), (13, 0)), LevelFilter::Debug),
+    ]);
+    assert_eq!(schedule.level_at(at(21, 59, 59)), LevelFilter::Trace);
+    assert_eq!(schedule.level_at(at(22, 0, 0)), LevelFilter::Warn);
+    assert_eq!(schedule.level_at(at(0, 0, 0)), LevelFilter::Warn);
+    assert_eq!(schedule.level_at(at(4, 59, 59)), LevelFilter::Warn);
+    assert_eq!(schedule.level_at(at(5, 0, 0)), LevelFilter::Error);
+    assert_eq!(schedule.level_at(at(6, 0, 0)), LevelFilter::Error);
+    assert_eq!(schedule.level_at(at(7, 0, 0)), LevelFilter::Trace);
+    assert_eq!(schedule.level_at(at(12, 30, 0)), LevelFilter::Debug);
+    assert!(Schedule::default().allows(Level::Trace));
+}
@@ -0,0 +1,113 @@
+//! Helpers for testing code logging through mtlog, enabled by the `test-util` feature.
+
+use std::{future::Future, sync::{Arc, LazyLock, Mutex}};
+use log::LevelFilter;
+use uuid::Uuid;
+use crate::{schedule::Schedule, log_writer::{LogWriter, MemoryWriter}, utils::{raise_max_level_scoped, spawn_log_thread, ChannelConfig, FormatOptions, LogSender}, LogConfig, PostShutdown, GLOBAL_LOG_CONFIG, LOG_CONFIG};
+
+/// Memory writer whose lines stay readable by [`capture_logs`] once moved to the log thread.
+struct CaptureWriter(Arc<Mutex<MemoryWriter>>);
+
+impl LogWriter for CaptureWriter {
+    fn regular(&mut self, line: &str) {
+        self.0.lock().unwrap().regular(line);
+    }
+    fn progress(&mut self, line: &str, id: Uuid) {
+        self.0.lock().unwrap().progress(line, id);
+    }
+    fn finished(&mut self, id: Uuid) {
+        self.0.lock().unwrap().finished(id);
+    }
+}
+
+/// Runs `f` and returns the lines it logged in the current task, at any level, as rendered in a log file.
+///
+/// `f` runs in a scope writing to memory only: nothing reaches the other sinks and nothing leaks
+/// into the next tests. See [`capture_logs_async`] for futures.
+/// The logger is registered with the `log` facade on first use, so this can be called any number of
+/// times, whether or not a global configuration was initialized.
+///
+/// ```rust
+/// let lines = mtlog_tokio::test_util::capture_logs(|| log::warn!("disk almost full"));
+/// assert!(lines[0].ends_with("disk almost full"));
+/// ```
+pub fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+    let (config, sender, lines) = capture_config();
+    let _raised = raise_max_level_scoped(LevelFilter::Trace);
+    LOG_CONFIG.sync_scope(config, f);
+    take_lines(&sender, &lines)
+}
+
+/// Awaits `f` and returns the lines it logged in the current task, see [`capture_logs`].
+/// Tasks spawned by `f` use their own configuration and are not captured.
+pub async fn capture_logs_async<F: Future<Output = ()>>(f: F) -> Vec<String> {
+    let (config, sender, lines) = capture_config();
+    let _raised = raise_max_level_scoped(LevelFilter::Trace);
+    LOG_CONFIG.scope(config, f).await;
+    take_lines(&sender, &lines)
+}
+
+/// Configuration writing to memory only, with its sender and the lines written.
+fn capture_config() -> (LogConfig, Arc<LogSender>, Arc<Mutex<MemoryWriter>>) {
+    LazyLock::force(&GLOBAL_LOG_CONFIG);
+    let lines = Arc::new(Mutex::new(MemoryWriter::default()));
+    let sender = Arc::new(spawn_log_thread(CaptureWriter(lines.clone()), FormatOptions::default(), ChannelConfig::default()));
+    let config = LogConfig {
+        sender_file: None,
+        sender_trace: None,
+        sender_stdout: None,
+        lazy_stdout: None,
+        sender_progress: None,
+        sender_writers: vec![sender.clone()],
+        name: None,
+        level: LevelFilter::Trace,
+        middlewares: None,
+        samplers: Vec::new(),
+        escape_control_chars: false,
+        post_shutdown: PostShutdown::Discard,
+        clock: Default::default(),
+        shut_down: false,
+        file_schedule: Schedule::default(),
+        error_backtraces: false,
+        name_from_target: false,
+        trace_capture: false,
+        error_summary: None,
+    };
+    (config, sender, lines)
+}
+
+/// Waits for the captured lines to be written, then takes them.
+fn take_lines(sender: &LogSender, lines: &Mutex<MemoryWriter>) -> Vec<String> {
+    sender.flush();
+    std::mem::take(&mut lines.lock().unwrap().lines)
+}
+
+#[test]
+fn test_capture_logs() {
+    let lines = capture_logs(|| {
+        log::info!("first");
+        log::trace!("second");
+    });
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("] first"));
+    assert!(lines[1].ends_with("] second"));
+    let lines = capture_logs(|| {
+        log::error!("outer");
+        assert!(capture_logs(|| log::error!("inner"))[0].ends_with("] inner"));
+    });
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].ends_with("] outer"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_capture_logs_async() {
+    let lines = capture_logs_async(async {
+        log::info!("before");
+        tokio::task::yield_now().await;
+        log::debug!("after");
+    }).await;
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("] before"));
+    assert!(lines[1].ends_with("] after"));
+}
@@ -1,30 +1,209 @@
-use std::{ops::Deref, sync::{mpsc::{channel, Sender}, Arc}, thread::JoinHandle};
+use std::{borrow::Cow, cell::RefCell, collections::{HashMap, HashSet}, fmt::{self, Write}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, mpsc::{self, sync_channel, RecvTimeoutError, SendError, Sender, SyncSender}, Arc, Condvar, LazyLock, Mutex, Weak}, thread::JoinHandle, time::{Duration, Instant}};
 
-use chrono::Utc;
-use colored::Colorize;
-use log::Level;
+use chrono::{DateTime, Utc};
+use colored::{Color, Colorize};
+use log::{Level, LevelFilter};
+use unicode_width::UnicodeWidthChar;
 use uuid::Uuid;
 
-use crate::log_writer::LogWriter;
-
+use crate::{error::ConfigError, log_writer::{LogStdout, LogWriter}, metrics::{NameStats, SinkStats}};
 
+/// A log record as sent to the log threads.
 #[derive(Debug,Clone)]
 pub struct LogMessage {
     pub message: String,
     pub level: Level,
-    pub name: Option<String>,
+    /// Name of the logger, shared with the configuration that sent the record.
+    pub name: Option<Arc<str>>,
+    /// Structured fields, rendered after the message.
+    pub fields: Vec<(String, String)>,
+}
+
+/// Hook applied by the log threads to regular messages before they are written, dropping the
+/// message by returning `None`.
+pub type Middleware = Arc<dyn Fn(LogMessage) -> Option<LogMessage> + Send + Sync>;
+
+/// Passes a regular message through a middleware chain, progress messages are left untouched.
+pub fn apply_middlewares(middlewares: &[Middleware], log_message: LogMessage) -> Option<LogMessage> {
+    if log_message.message.starts_with("___PROGRESS___") {
+        return Some(log_message);
+    }
+    middlewares.iter().try_fold(log_message, |log_message, middleware| middleware(log_message))
+}
+
+/// Layout of regular log lines.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub enum LogFormat {
+    /// `[time name level] message key=value`
+    #[default]
+    Text,
+    /// One JSON object per line, with `time`, `level`, `name`, `message` and the fields as keys.
+    /// Field values reading as numbers or booleans are written unquoted, and fields named like
+    /// one of the first four keys are prefixed with `field_`.
+    Json,
+}
+
+/// How lines longer than the maximum width are shortened.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum WrapPolicy {
+    /// Cut the line, ending it with `…`.
+    Truncate,
+    /// Break the line into several, the continuation lines being indented.
+    Wrap,
+}
+
+/// Time a log thread keeps applying the progress messages of its open bars after a shutdown request.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(100);
+
+/// Indent of the continuation lines of wrapped lines.
+const WRAP_INDENT: &str = "    ";
+
+/// Source of the timestamps of log lines.
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>);
+
+/// Clock shared by the default formatting options, so that they compare equal.
+static SYSTEM_CLOCK: LazyLock<Clock> = LazyLock::new(|| Clock::new(Utc::now));
+
+impl Clock {
+    pub fn new(now: impl Fn() -> DateTime<Utc> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(now))
+    }
+    pub fn now(&self) -> DateTime<Utc> {
+        (self.0)()
+    }
+}
+
+impl Default for Clock {
+    /// `Utc::now`.
+    fn default() -> Self {
+        SYSTEM_CLOCK.clone()
+    }
+}
+
+impl PartialEq for Clock {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// Channel between the senders of a log thread and the thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelConfig {
+    /// Sending never blocks, messages piling up in memory while the thread is behind.
+    #[default]
+    Unbounded,
+    /// Sending blocks while this many messages are waiting, slowing the logging threads down to
+    /// the pace of the thread.
+    Bounded(usize),
+    /// Sending blocks until the thread takes the message.
+    Rendezvous,
+}
+
+/// A message queued for a log thread, with the middlewares, the post-shutdown policy and the
+/// format, if not the one of the thread, of the configuration that sent it.
+struct Queued {
+    log_message: Arc<LogMessage>,
+    middlewares: Option<Arc<[Middleware]>>,
+    post_shutdown: PostShutdown,
+    format: Option<Arc<FormatOptions>>,
+}
+
+/// What happens to the messages logged after the global configuration was shut down,
+/// e.g. from `Drop` implementations during teardown.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub enum PostShutdown {
+    /// Write them directly to stderr, synchronously and without color.
+    #[default]
+    Stderr,
+    /// Drop them.
+    Discard,
+}
+
+/// Sending half of the channel of a log thread.
+#[derive(Clone)]
+enum ChannelSender {
+    Unbounded(Sender<Queued>),
+    Bounded(SyncSender<Queued>),
+}
+
+impl ChannelSender {
+    fn send(&self, log_message: Arc<LogMessage>, middlewares: Option<&Arc<[Middleware]>>, post_shutdown: PostShutdown, format: Option<&Arc<FormatOptions>>) -> Result<(), SendError<Arc<LogMessage>>> {
+        let queued = Queued { log_message, middlewares: middlewares.cloned(), post_shutdown, format: format.cloned() };
+        match self {
+            Self::Unbounded(sender) => sender.send(queued),
+            Self::Bounded(sender) => sender.send(queued),
+        }.map_err(|SendError(queued)| SendError(queued.log_message))
+    }
+}
+
+/// Formatting options applied by a log thread.
+#[derive(Debug,Clone,PartialEq)]
+pub struct FormatOptions {
+    /// Render the level as its numeric code (`log::Level as usize`: 1 for ERROR to 5 for TRACE).
+    pub numeric_levels: bool,
+    /// Keep the line of finished progress bars on terminals, instead of clearing it.
+    pub leave_on_finish: bool,
+    /// Prefix progress lines with the header of regular lines when they first appear.
+    pub progress_headers: bool,
+    /// Layout of regular lines.
+    pub log_format: LogFormat,
+    /// Maximum number of progress redraws per second on terminals, across all bars.
+    pub max_redraws_per_second: Option<u32>,
+    /// Source of the timestamps.
+    pub clock: Clock,
+    /// Maximum width of text lines in columns, and how longer lines are shortened.
+    pub max_width: Option<(usize, WrapPolicy)>,
+    /// Color of the name and level in headers, instead of a color per level.
+    pub accent_color: Option<Color>,
+    /// Escape the newlines, carriage returns and tabs of text lines, so that each record takes one line.
+    pub escape_newlines: bool,
 }
 
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { numeric_levels: false, leave_on_finish: true, progress_headers: false, log_format: LogFormat::Text, max_redraws_per_second: None, clock: Clock::default(), max_width: None, accent_color: None, escape_newlines: false }
+    }
+}
+
+/// Number of flush requests processed by a log thread, notified on each one.
+type FlushState = Arc<(Mutex<usize>,Condvar)>;
+
 pub struct LogSender{
-    sender: Sender<Arc<LogMessage>>,
+    /// Sending half of the channel, dropped on shutdown so that the log thread only waits for the
+    /// progress messages of its open bars while other senders remain.
+    sender: Option<ChannelSender>,
     handler: Option<JoinHandle<bool>>,
     shutdown_initiated: bool,
-}
-impl Deref for LogSender {
-    type Target = Sender<Arc<LogMessage>>;
-    fn deref(&self) -> &Self::Target {
-        &self.sender
-    }
+    /// Formatting options of the messages sent.
+    pub format: FormatOptions,
+    /// Channel to the log thread.
+    pub channel: ChannelConfig,
+    /// Formatting options sent along with each message, when they differ from those of the log thread.
+    message_format: Option<Arc<FormatOptions>>,
+    /// Shared stdout log thread the messages are sent to, see [`shared_stdout`].
+    stdout: Option<Arc<LogSender>>,
+    flush_requests: Arc<AtomicUsize>,
+    flushed: FlushState,
+    stats: NameStats,
+    /// Messages sent and not handled yet by the log thread.
+    pending: Arc<AtomicUsize>,
+    /// Maximum number of pending messages, regular messages sent beyond it being dropped.
+    capacity: Option<usize>,
+    /// Regular messages dropped since the queue was last below its capacity.
+    dropped: AtomicU64,
+    /// Latest frame of each bar held back while the queue is full, sent once it is below its
+    /// capacity again or before the bar finishes.
+    held_frames: Mutex<HashMap<Uuid, Arc<LogMessage>>>,
+    /// Whether `held_frames` is not empty, set with its lock held, so that sending a message only
+    /// locks it while frames are held.
+    holding_frames: AtomicBool,
 }
 impl Drop for LogSender {
     fn drop(&mut self) {
@@ -35,58 +214,1173 @@ impl Drop for LogSender {
 }
 
 impl LogSender {
-    pub fn new(sender: Sender<Arc<LogMessage>>, handler: JoinHandle<bool>) -> Self {
-        Self {sender, handler: Some(handler), shutdown_initiated: false}
+    fn new(sender: ChannelSender, handler: JoinHandle<bool>, format: FormatOptions, channel: ChannelConfig, flushed: FlushState, stats: NameStats, pending: Arc<AtomicUsize>) -> Self {
+        Self {sender: Some(sender), handler: Some(handler), shutdown_initiated: false, format, channel, message_format: None, stdout: None, flush_requests: Arc::default(), flushed, stats, pending, capacity: None, dropped: AtomicU64::new(0), held_frames: Mutex::default(), holding_frames: AtomicBool::new(false)}
+    }
+    /// Sender to the log thread of `stdout` writing its messages with `format`.
+    fn share(stdout: &Arc<LogSender>, format: &FormatOptions) -> Self {
+        Self {
+            sender: stdout.sender.clone(),
+            handler: None,
+            shutdown_initiated: false,
+            format: format.clone(),
+            channel: stdout.channel,
+            message_format: (format != &stdout.format).then(|| Arc::new(format.clone())),
+            stdout: Some(stdout.clone()),
+            flush_requests: stdout.flush_requests.clone(),
+            flushed: stdout.flushed.clone(),
+            stats: stdout.stats.clone(),
+            pending: stdout.pending.clone(),
+            capacity: None,
+            dropped: AtomicU64::new(0),
+            held_frames: Mutex::default(),
+            holding_frames: AtomicBool::new(false),
+        }
+    }
+    /// Bounds the number of messages waiting for the log thread, e.g. when it is blocked by a
+    /// terminal that stopped reading. Regular messages sent while `capacity` messages are waiting
+    /// are dropped, a notice being printed on stderr when drops begin and the number of dropped
+    /// messages being logged once the queue is below its capacity again. Progress frames are
+    /// coalesced meanwhile, only the latest one of each bar being drawn. Unbounded with `None`.
+    pub fn with_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.capacity = capacity;
+        self
+    }
+    /// Number of messages sent and not handled yet by the log thread.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+    /// Sends a message to the log thread, unless the queue is full and it is a regular message.
+    pub fn send(&self, log_message: Arc<LogMessage>) -> Result<(), SendError<Arc<LogMessage>>> {
+        self.send_through(log_message, None, PostShutdown::default())
+    }
+    /// Like [`LogSender::send`], the log thread passing the message through `middlewares` before
+    /// writing it, and handling it according to `post_shutdown` if it arrives while shutting down.
+    pub fn send_through(&self, log_message: Arc<LogMessage>, middlewares: Option<&Arc<[Middleware]>>, post_shutdown: PostShutdown) -> Result<(), SendError<Arc<LogMessage>>> {
+        let Some(sender) = &self.sender else {
+            return Err(SendError(log_message));
+        };
+        let forward = |log_message, middlewares| {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            sender.send(log_message, middlewares, post_shutdown, self.message_format.as_ref())
+        };
+        if let Some(capacity) = self.capacity {
+            let message = &log_message.message;
+            let bypasses_capacity = matches!(message.as_str(), "___SHUTDOWN___" | "___FLUSH___" | "___REOPEN___") || ends_bar(message);
+            if !bypasses_capacity && self.pending() >= capacity {
+                if let Some((id, _)) = parse_progress(message) {
+                    let mut held_frames = self.held_frames.lock().unwrap();
+                    held_frames.insert(id, log_message);
+                    self.holding_frames.store(true, Ordering::SeqCst);
+                    return Ok(());
+                }
+                if self.dropped.fetch_add(1, Ordering::SeqCst) == 0 {
+                    eprintln!("mtlog: log thread blocked with {capacity} pending messages, dropping its regular messages until it catches up");
+                }
+                return Ok(());
+            }
+            // Held frames go first, past the capacity for a finishing bar or a control message
+            let held_frames = if self.holding_frames.load(Ordering::SeqCst) {
+                let mut held_frames = self.held_frames.lock().unwrap();
+                let frames = match parse_progress(message) {
+                    Some((id, "FINISHED")) => held_frames.remove(&id).into_iter().collect(),
+                    _ => std::mem::take(&mut *held_frames).into_values().collect::<Vec<_>>(),
+                };
+                self.holding_frames.store(!held_frames.is_empty(), Ordering::SeqCst);
+                frames
+            } else {
+                Vec::new()
+            };
+            for frame in held_frames {
+                forward(frame, middlewares).ok();
+            }
+            let dropped = if bypasses_capacity { 0 } else { self.dropped.swap(0, Ordering::SeqCst) };
+            if dropped > 0 {
+                let notice = LogMessage { message: format!("mtlog: {dropped} messages dropped while the log thread was blocked"), level: Level::Warn, name: None, fields: Vec::new() };
+                forward(Arc::new(notice), None).ok();
+            }
+        }
+        forward(log_message, middlewares)
+    }
+    /// Whether the volume of the regular lines written is accounted per logger name, see
+    /// [`LogSender::stats_by_name`]. Disabled by default.
+    pub fn with_stats_by_name(self, enabled: bool) -> Self {
+        if enabled {
+            self.stats.enable();
+        }
+        self
+    }
+    /// Volume of the regular lines written so far per logger name, by decreasing number of bytes,
+    /// empty unless enabled with [`LogSender::with_stats_by_name`].
+    pub fn stats_by_name(&self) -> Vec<(Option<String>, SinkStats)> {
+        self.stats.snapshot()
+    }
+    /// Sends a flush request to the log thread, returning its ticket.
+    fn request_flush(&self) -> Option<usize> {
+        let ticket = self.flush_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        self.send(Arc::new(LogMessage {message: "___FLUSH___".into(), level: Level::Info, name: None, fields: Vec::new()})).ok()?;
+        Some(ticket)
+    }
+    /// Asks the log thread to reopen its destination, see [`LogWriter::reopen`].
+    pub fn reopen(&self) {
+        self.send(Arc::new(LogMessage {message: "___REOPEN___".into(), level: Level::Info, name: None, fields: Vec::new()})).ok();
+    }
+    /// Blocks until the log thread has written and flushed every message sent before this call.
+    pub fn flush(&self) {
+        let Some(ticket) = self.request_flush() else {
+            return;
+        };
+        let (flushed, condvar) = &*self.flushed;
+        let mut flushed = flushed.lock().unwrap();
+        while *flushed < ticket {
+            flushed = condvar.wait(flushed).unwrap();
+        }
     }
+    /// Like [`LogSender::flush`], waiting at most `timeout`. Returns whether the flush completed.
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        let Some(ticket) = self.request_flush() else {
+            return false;
+        };
+        let (flushed, condvar) = &*self.flushed;
+        let Ok(flushed) = flushed.lock() else {
+            return false;
+        };
+        condvar.wait_timeout_while(flushed, timeout, |flushed| *flushed < ticket).is_ok_and(|(_, result)| !result.timed_out())
+    }
+    /// Writes every pending message, then stops the log thread. Later calls have no effect.
+    /// A sender sharing the stdout log thread only stops it if it is the last one.
     pub fn shutdown(&mut self) {
-        self.send(Arc::new(LogMessage {message: "___SHUTDOWN___".into(), level: Level::Info, name: None})).expect("Unable to send shutdown message to file logger thread");
-        if !self.handler.take().unwrap().join().expect("Unable to join file logger thread") {
-            panic!("Logger thread shutdown failed");
+        self.shutdown_initiated = true;
+        if let Some(stdout) = self.stdout.take() {
+            self.sender = None;
+            // Stopped under the lock, so that the next thread only starts once this one is done
+            let _shared = SHARED_STDOUT.lock();
+            drop(stdout);
+            return;
+        }
+        let Some(handler) = self.handler.take() else {
+            return;
         };
+        // The thread may already be gone, e.g. after a panic of its writer
+        self.send(Arc::new(LogMessage {message: "___SHUTDOWN___".into(), level: Level::Info, name: None, fields: Vec::new()})).ok();
+        self.sender = None;
+        // Shutting down runs on drop, where panicking again would abort the process
+        if handler.join().is_err() {
+            eprintln!("mtlog: the log thread panicked, some messages may have been lost");
+        }
     }
 }
 
-fn format_log(message: &str, level: Level, name: &Option<String>) -> String {
-    let time = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3f");
-    let level = match level {
-        log::Level::Error => "ERROR".red(),
-        log::Level::Warn => "WARN".yellow(),
-        log::Level::Info => "INFO".green(),
-        log::Level::Debug => "DEBUG".blue(),
-        log::Level::Trace => "TRACE".purple(),
+fn format_header(level: Level, name: Option<&str>, format: &FormatOptions) -> String {
+    accented_header(level, name, format, colored::control::SHOULD_COLORIZE.should_colorize())
+}
+
+/// Wraps `text` in the escape codes of `color` if `colorize`.
+fn paint(text: &str, color: Color, colorize: bool) -> String {
+    if colorize {
+        format!("\x1B[{}m{text}\x1B[0m", color.to_fg_str())
+    } else {
+        text.into()
+    }
+}
+
+/// Header of a line, the name and level being painted with the accent color of `format` if `colorize`.
+fn accented_header(level: Level, name: Option<&str>, format: &FormatOptions, colorize: bool) -> String {
+    let time = format.clock.now().format("%Y-%m-%dT%H:%M:%S%.3f");
+    let label = if format.numeric_levels { (level as usize).to_string() } else { level.as_str().into() };
+    let level = match format.accent_color {
+        Some(accent) => paint(&label, accent, colorize),
+        None if format.numeric_levels => label,
+        None => label.color(match level {
+            log::Level::Error => Color::Red,
+            log::Level::Warn => Color::Yellow,
+            log::Level::Info => Color::Green,
+            log::Level::Debug => Color::Blue,
+            log::Level::Trace => Color::Magenta,
+        }).to_string(),
+    };
+    let name = match (name, format.accent_color) {
+        (Some(name), Some(accent)) => Some(Cow::Owned(paint(name, accent, colorize))),
+        (name, _) => name.map(Cow::Borrowed),
     };
     if let Some(name) = name {
-        format!("[{time} {name} {}] {}", level, message)
+        format!("[{time} {name} {}]", level)
     } else {
-        format!("[{time} {}] {}", level, message)
+        format!("[{time} {}]", level)
     }
 }
 
-pub fn spawn_log_thread<W: LogWriter+Send+'static>(mut writer: W)-> LogSender {
-    let (sender, receiver) = channel::<Arc<LogMessage>>();
-    let handler = std::thread::spawn(move || {
-        for log_message in receiver {
-            let LogMessage { message, level, name } = log_message.as_ref();
-            if message == "___SHUTDOWN___" {
-                break;
-            }
-            if message.starts_with("___PROGRESS___") {
-                let message = message.trim_start_matches("___PROGRESS___");
-                if let Some((uuid_str, message)) = message.split_once("___") {
-                    if let Ok(uuid) = Uuid::parse_str(uuid_str) {
-                        if message=="FINISHED" {
-                            writer.finished(uuid);
-                        } else {
-                            writer.progress(message, uuid);
+/// Quotes and escapes a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Keys set by mtlog in JSON lines, which fields cannot take.
+const RESERVED_JSON_KEYS: [&str; 4] = ["time", "level", "name", "message"];
+
+/// Whether `value` is a number of the JSON grammar, e.g. `-1.5e3` but not `01`, `.5` or `NaN`.
+fn is_json_number(value: &str) -> bool {
+    let digits = |text: &str| text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let rest = value.strip_prefix('-').unwrap_or(value);
+    let integer = digits(rest);
+    if integer == 0 || (integer > 1 && rest.starts_with('0')) {
+        return false;
+    }
+    let mut rest = &rest[integer..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = digits(fraction);
+        if len == 0 {
+            return false;
+        }
+        rest = &fraction[len..];
+    }
+    if let Some(exponent) = rest.strip_prefix(['e', 'E']) {
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        let len = digits(exponent);
+        if len == 0 {
+            return false;
+        }
+        rest = &exponent[len..];
+    }
+    rest.is_empty()
+}
+
+/// Renders a field value as a JSON literal: numbers and booleans as is, anything else as a string.
+fn json_value(value: &str) -> Cow<'_, str> {
+    if value == "true" || value == "false" || is_json_number(value) {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(json_string(value))
+    }
+}
+
+fn format_json(log_message: &LogMessage, format: &FormatOptions) -> String {
+    let LogMessage { message, level, name, fields } = log_message;
+    let time = format.clock.now().format("%Y-%m-%dT%H:%M:%S%.3f");
+    let level = if format.numeric_levels {
+        (*level as usize).to_string()
+    } else {
+        json_string(level.as_str())
+    };
+    let mut line = format!("{{\"time\":\"{time}\",\"level\":{level}");
+    if let Some(name) = name {
+        line.push_str(&format!(",\"name\":{}", json_string(name)));
+    }
+    line.push_str(&format!(",\"message\":{}", json_string(message)));
+    for (key, value) in fields {
+        let key = if RESERVED_JSON_KEYS.contains(&key.as_str()) { json_string(&format!("field_{key}")) } else { json_string(key) };
+        line.push_str(&format!(",{key}:{}", json_value(value)));
+    }
+    line.push('}');
+    line
+}
+
+fn format_log<'a>(log_message: &'a LogMessage, format: &FormatOptions) -> String {
+    let LogMessage { message, level, name, fields } = log_message;
+    if format.log_format == LogFormat::Json {
+        return format_json(log_message, format);
+    }
+    let escape = |text: &'a str| if format.escape_newlines { escape_line_breaks(text) } else { Cow::Borrowed(text) };
+    let mut line = format!("{} {}", format_header(*level, name.as_deref(), format), escape(message));
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={}", escape(value)));
+    }
+    line
+}
+
+/// Escapes newlines, carriage returns and tabs as `\n`, `\r` and `\t`, keeping `text` on one line,
+/// and backslashes as `\\` so that the escapes are unambiguous.
+fn escape_line_breaks(text: &str) -> Cow<'_, str> {
+    if !text.contains(['\\', '\n', '\r', '\t']) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t"))
+}
+
+/// Splits `line` into its characters and ANSI escape sequences, the latter taking no column.
+fn columns(line: &str) -> impl Iterator<Item = (&str, usize)> {
+    let mut rest = line;
+    std::iter::from_fn(move || {
+        let c = rest.chars().next()?;
+        let len = match rest.strip_prefix("\x1B[") {
+            Some(sequence) => sequence.find(|c: char| c.is_ascii_alphabetic()).map_or(rest.len(), |end| end + 3),
+            None => c.len_utf8(),
+        };
+        let (token, tail) = rest.split_at(len);
+        rest = tail;
+        let width = if token.starts_with('\x1B') { 0 } else { c.width().unwrap_or(0) };
+        Some((token, width))
+    })
+}
+
+/// Shortens each line of `text` wider than `width` columns according to `policy`.
+/// Escape sequences are kept, so that the colors of a cut line are still reset.
+fn fit_width(text: &str, width: usize, policy: WrapPolicy) -> Cow<'_, str> {
+    if text.lines().all(|line| columns(line).map(|(_, width)| width).sum::<usize>() <= width) {
+        return Cow::Borrowed(text);
+    }
+    let mut fitted = String::with_capacity(text.len() + 8);
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            fitted.push('\n');
+        }
+        let mut used = 0;
+        match policy {
+            WrapPolicy::Truncate => {
+                let fits = columns(line).map(|(_, width)| width).sum::<usize>() <= width;
+                let mut cut = false;
+                for (token, token_width) in columns(line) {
+                    if !fits && used + token_width >= width {
+                        if !cut && token_width > 0 {
+                            fitted.push('…');
+                            cut = true;
                         }
+                        if token_width > 0 {
+                            continue;
+                        }
+                    }
+                    used += token_width;
+                    fitted.push_str(token);
+                }
+            },
+            WrapPolicy::Wrap => {
+                for (token, token_width) in columns(line) {
+                    if used + token_width > width && used > WRAP_INDENT.len() {
+                        fitted.push('\n');
+                        fitted.push_str(WRAP_INDENT);
+                        used = WRAP_INDENT.len();
                     }
+                    used += token_width;
+                    fitted.push_str(token);
                 }
+            },
+        }
+    }
+    Cow::Owned(fitted)
+}
+
+/// Escapes the characters of `text` matching `escape` as `\xNN`, or `\u{NNNN}` beyond ASCII.
+fn escape_chars(text: &str, escape: impl Fn(char) -> bool) -> Cow<'_, str> {
+    if !text.chars().any(&escape) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        if !escape(c) {
+            escaped.push(c);
+        } else if c.is_ascii() {
+            write!(escaped, "\\x{:02x}", c as u32).unwrap();
+        } else {
+            write!(escaped, "\\u{{{:04x}}}", c as u32).unwrap();
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Escapes the control characters and `]` of a logger name, so that it cannot break or spoof
+/// the `[time name level]` header.
+pub fn sanitize_name(name: &str) -> String {
+    escape_chars(name, |c| c.is_control() || c == ']').into_owned()
+}
+
+/// Substitutes the `{variable}` placeholders of a name template with their value from `lookup`,
+/// `{{` and `}}` standing for literal braces.
+pub fn render_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..pos]);
+        let (brace, after) = rest[pos..].split_at(1);
+        if after.starts_with(brace) {
+            rendered.push_str(brace);
+            rest = &after[1..];
+            continue;
+        }
+        let Some((variable, after)) = after.split_once('}').filter(|_| brace == "{") else {
+            return Err(ConfigError::InvalidTemplate(template.into()));
+        };
+        rendered.push_str(&lookup(variable).ok_or_else(|| ConfigError::UnboundVariable(variable.into()))?);
+        rest = after;
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Name of the host, for name templates.
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return "unknown".into();
+    }
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// Name of the host, for name templates, from the `COMPUTERNAME` variable set by Windows.
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".into())
+}
+
+/// Whether the output is made deterministic for tests, see [`test_mode`].
+static TEST_MODE: LazyLock<AtomicBool> = LazyLock::new(|| AtomicBool::new(std::env::var_os("MTLOG_TEST_MODE").is_some_and(|value| value == "1")));
+
+/// Makes the output of the process deterministic, for tests only: time-based throttling is disabled,
+/// every progress frame being drawn on terminals, and logging a message returns once every sink
+/// wrote and flushed it, so that tests can read log files right away instead of sleeping.
+/// Also enabled by setting `MTLOG_TEST_MODE=1`. It cannot be disabled.
+pub fn test_mode() {
+    TEST_MODE.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn in_test_mode() -> bool {
+    TEST_MODE.load(Ordering::Relaxed)
+}
+
+/// Levels the maximum level of the `log` facade is the highest of.
+struct MaxLevels {
+    /// Highest level of the configurations built so far.
+    base: LevelFilter,
+    /// Levels of the live [`MaxLevelGuard`]s.
+    scoped: Vec<LevelFilter>,
+}
+
+impl MaxLevels {
+    fn level(&self) -> LevelFilter {
+        self.scoped.iter().copied().fold(self.base, Ord::max)
+    }
+
+    fn remove_scoped(&mut self, level: LevelFilter) {
+        if let Some(pos) = self.scoped.iter().position(|scoped| *scoped == level) {
+            self.scoped.swap_remove(pos);
+        }
+    }
+}
+
+static MAX_LEVELS: Mutex<MaxLevels> = Mutex::new(MaxLevels { base: LevelFilter::Off, scoped: Vec::new() });
+
+/// Raises the maximum level of the `log` facade to `level` for good, e.g. for a new configuration.
+pub fn raise_max_level(level: LevelFilter) {
+    let mut levels = MAX_LEVELS.lock().unwrap();
+    levels.base = levels.base.max(level);
+    log::set_max_level(levels.level());
+}
+
+/// Raises the maximum level of the `log` facade to `level` while the returned guard lives, e.g.
+/// for a scope boosting its level. Once dropped, the maximum level goes back to the highest level
+/// still needed by the configurations and the other guards.
+pub fn raise_max_level_scoped(level: LevelFilter) -> MaxLevelGuard {
+    let mut levels = MAX_LEVELS.lock().unwrap();
+    levels.scoped.push(level);
+    log::set_max_level(levels.level());
+    MaxLevelGuard(level)
+}
+
+/// Maximum level raised by [`raise_max_level_scoped`], lowered back on drop.
+pub struct MaxLevelGuard(LevelFilter);
+
+impl Drop for MaxLevelGuard {
+    fn drop(&mut self) {
+        let mut levels = MAX_LEVELS.lock().unwrap();
+        levels.remove_scoped(self.0);
+        log::set_max_level(levels.level());
+    }
+}
+
+thread_local! {
+    /// Names derived from record targets on this thread, by target.
+    static TARGET_NAMES: RefCell<HashMap<String, Arc<str>>> = RefCell::default();
+}
+
+/// Number of names derived and cached by [`target_name`].
+#[cfg(test)]
+static TARGET_NAMES_CACHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Name of the crate of a record target, its first path segment, escaped as by [`sanitize_name`].
+/// Names are cached per thread, so that known targets are looked up without locking or allocating.
+pub fn target_name(target: &str) -> Arc<str> {
+    TARGET_NAMES.with_borrow_mut(|names| {
+        if let Some(name) = names.get(target) {
+            return name.clone();
+        }
+        #[cfg(test)]
+        TARGET_NAMES_CACHED.fetch_add(1, Ordering::SeqCst);
+        let name: Arc<str> = sanitize_name(target.split("::").next().unwrap_or(target)).into();
+        names.insert(target.into(), name.clone());
+        name
+    })
+}
+
+/// Escapes the control characters of a message, except newlines and tabs.
+pub fn sanitize_message(message: &str) -> Cow<'_, str> {
+    escape_chars(message, |c| c.is_control() && c != '\n' && c != '\t')
+}
+
+/// Whether a message is a progress frame or a request to the log thread rather than a line to write.
+fn is_control(message: &str) -> bool {
+    matches!(message, "___SHUTDOWN___" | "___FLUSH___" | "___REOPEN___") || parse_progress(message).is_some()
+}
+
+/// Writes a regular message directly to stderr, without color and timestamped by `clock`, when no
+/// log thread can take it. Progress and control messages are dropped, lines printed by progress bars
+/// are written as is.
+pub fn write_fallback(log_message: &LogMessage, clock: &Clock) {
+    let LogMessage { message, level, name, fields } = log_message;
+    let message = match parse_println(message) {
+        Some((_, line)) => line,
+        None if is_control(message) => return,
+        None => message,
+    };
+    let time = clock.now().format("%Y-%m-%dT%H:%M:%S%.3f");
+    let mut line = match name {
+        Some(name) => format!("[{time} {name} {level}] {message}"),
+        None => format!("[{time} {level}] {message}"),
+    };
+    for (key, value) in fields {
+        write!(line, " {key}={value}").unwrap();
+    }
+    line.push('\n');
+    std::io::Write::write_all(&mut std::io::stderr().lock(), line.as_bytes()).ok();
+}
+
+/// Splits a `___PROGRESS___{uuid}___{frame}` message into the id of the bar and its frame.
+fn parse_progress(message: &str) -> Option<(Uuid, &str)> {
+    parse_bar_message(message, "___PROGRESS___")
+}
+
+/// Whether `message` marks a progress bar as finished.
+pub(crate) fn ends_bar(message: &str) -> bool {
+    matches!(parse_progress(message), Some((_, "FINISHED")))
+}
+
+/// Splits a `___PRINTLN___{uuid}___{line}` message, a regular line printed by a progress bar,
+/// into the id of the bar and the line.
+fn parse_println(message: &str) -> Option<(Uuid, &str)> {
+    parse_bar_message(message, "___PRINTLN___")
+}
+
+fn parse_bar_message<'a>(message: &'a str, prefix: &str) -> Option<(Uuid, &'a str)> {
+    let (uuid_str, text) = message.strip_prefix(prefix)?.split_once("___")?;
+    Some((Uuid::parse_str(uuid_str).ok()?, text))
+}
+
+/// Renders a regular line, fitted to the maximum width of text lines.
+fn regular_line(log_message: &LogMessage, format: &FormatOptions) -> String {
+    let line = format_log(log_message, format);
+    match format.max_width {
+        Some((width, policy)) if format.log_format == LogFormat::Text => fit_width(&line, width, policy).into_owned(),
+        _ => line,
+    }
+}
+
+/// Records the progress bars drawn and not finished yet.
+fn track_bar(open_bars: &mut HashSet<Uuid>, message: &str) {
+    match parse_progress(message) {
+        Some((uuid, "FINISHED")) => { open_bars.remove(&uuid); },
+        Some((uuid, _)) => { open_bars.insert(uuid); },
+        None => (),
+    }
+}
+
+/// Dispatches a message to the matching writer method.
+/// Returns `false` if the message is a shutdown request. Regular lines are accounted in `stats`, if any.
+fn write_message<W: LogWriter>(writer: &mut W, log_message: &LogMessage, format: &FormatOptions, stats: Option<&NameStats>) -> bool {
+    let LogMessage { message, level, name, .. } = log_message;
+    if message == "___SHUTDOWN___" {
+        return false;
+    }
+    if message == "___FLUSH___" {
+        writer.flush();
+    } else if message == "___REOPEN___" {
+        writer.reopen();
+    } else if let Some((uuid, line)) = parse_println(message) {
+        let log_message = LogMessage { message: line.into(), ..log_message.clone() };
+        let line = regular_line(&log_message, format);
+        writer.println(&line, &log_message, uuid);
+        if let Some(stats) = stats {
+            stats.record(name, line.len() + 1);
+        }
+    } else if message.starts_with("___PROGRESS___") {
+        if let Some((uuid, message)) = parse_progress(message) {
+            if message=="FINISHED" {
+                writer.finished(uuid);
             } else {
-                let message = format_log(message, *level, name);
-                writer.regular(&message);
+                // A wrapped progress line could not be redrawn in place
+                let message = match format.max_width {
+                    Some((width, _)) => fit_width(message, width, WrapPolicy::Truncate),
+                    None => Cow::Borrowed(message),
+                };
+                if format.progress_headers {
+                    writer.progress_with_header(&format_header(*level, name.as_deref(), format), &message, uuid);
+                } else {
+                    writer.progress(&message, uuid);
+                }
+            }
+        }
+    } else {
+        let line = regular_line(log_message, format);
+        writer.regular_message(&line, log_message);
+        if let Some(stats) = stats {
+            stats.record(name, line.len() + 1);
+        }
+    }
+    true
+}
+
+/// Replays a stream of log messages through a writer, e.g. to re-render captured logs.
+pub fn replay(messages: impl Iterator<Item = LogMessage>, writer: &mut impl LogWriter) {
+    for log_message in messages {
+        if !write_message(writer, &log_message, &FormatOptions::default(), None) {
+            break;
+        }
+    }
+}
+
+pub fn spawn_log_thread<W: LogWriter+Send+'static>(mut writer: W, format: FormatOptions, channel: ChannelConfig)-> LogSender {
+    let (sender, receiver) = match channel {
+        ChannelConfig::Unbounded => {
+            let (sender, receiver) = mpsc::channel::<Queued>();
+            (ChannelSender::Unbounded(sender), receiver)
+        }
+        ChannelConfig::Bounded(capacity) => {
+            let (sender, receiver) = sync_channel(capacity);
+            (ChannelSender::Bounded(sender), receiver)
+        }
+        ChannelConfig::Rendezvous => {
+            let (sender, receiver) = sync_channel(0);
+            (ChannelSender::Bounded(sender), receiver)
+        }
+    };
+    let thread_format = format.clone();
+    let flushed = FlushState::default();
+    let thread_flushed = flushed.clone();
+    let stats = NameStats::default();
+    let thread_stats = stats.clone();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let thread_pending = pending.clone();
+    let handler = std::thread::spawn(move || {
+        let mut open_bars = HashSet::new();
+        let notify_flush = || {
+            let (flushed, condvar) = &*thread_flushed;
+            *flushed.lock().unwrap() += 1;
+            condvar.notify_all();
+        };
+        // Middlewares run here rather than on the logging threads, each sink getting its own copy
+        let apply = |Queued { log_message, middlewares, .. }: Queued| match middlewares {
+            Some(middlewares) => apply_middlewares(&middlewares, Arc::unwrap_or_clone(log_message)).map(Arc::new),
+            None => Some(log_message),
+        };
+        loop {
+            // Frames held back by a redraw cap are drawn when due, even if no message follows them
+            let queued = match writer.write_pending() {
+                Some(delay) => match receiver.recv_timeout(delay) {
+                    Ok(queued) => queued,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match receiver.recv() {
+                    Ok(queued) => queued,
+                    Err(_) => break,
+                },
+            };
+            thread_pending.fetch_sub(1, Ordering::SeqCst);
+            let format = queued.format.clone();
+            let Some(log_message) = apply(queued) else {
+                continue;
+            };
+            track_bar(&mut open_bars, &log_message.message);
+            if !write_message(&mut writer, &log_message, format.as_deref().unwrap_or(&thread_format), Some(&thread_stats)) {
+                break;
             }
+            if log_message.message == "___FLUSH___" {
+                notify_flush();
+            }
+        }
+        // A bar dropped while the sender shuts down may still send its end: progress messages are
+        // applied for a short while, regular ones being handled as after the shutdown.
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        while !open_bars.is_empty() {
+            let Ok(queued) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) else {
+                break;
+            };
+            thread_pending.fetch_sub(1, Ordering::SeqCst);
+            let post_shutdown = queued.post_shutdown;
+            let format = queued.format.clone();
+            let Some(log_message) = apply(queued) else {
+                continue;
+            };
+            match log_message.message.as_str() {
+                "___SHUTDOWN___" => (),
+                "___FLUSH___" => {
+                    writer.flush();
+                    notify_flush();
+                },
+                message if message.starts_with("___") => {
+                    track_bar(&mut open_bars, message);
+                    write_message(&mut writer, &log_message, format.as_deref().unwrap_or(&thread_format), None);
+                },
+                _ if post_shutdown == PostShutdown::Stderr => write_fallback(&log_message, &format.as_deref().unwrap_or(&thread_format).clock),
+                _ => (),
+            }
+        }
+        for uuid in open_bars {
+            writer.finished(uuid);
         }
         true
     });
-    LogSender::new(sender, handler)
-}
\ No newline at end of file
+    LogSender::new(sender, handler, format, channel, flushed, stats, pending)
+}
+
+/// Stdout log thread shared by every configuration writing to stdout, and its writer.
+struct SharedStdout {
+    sender: Weak<LogSender>,
+    writer: Weak<Mutex<Box<dyn LogWriter + Send>>>,
+}
+
+/// Stdout log thread of the process, stopped when the last configuration sharing it is dropped.
+static SHARED_STDOUT: Mutex<SharedStdout> = Mutex::new(SharedStdout { sender: Weak::new(), writer: Weak::new() });
+
+/// Number of running stdout log threads, at most one.
+static STDOUT_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+impl SharedStdout {
+    fn start(&mut self, writer: Box<dyn LogWriter + Send>, format: &FormatOptions, channel: ChannelConfig) -> Arc<LogSender> {
+        let running = STDOUT_THREADS.fetch_add(1, Ordering::SeqCst);
+        debug_assert_eq!(running, 0, "a single log thread writes to stdout");
+        let writer = Arc::new(Mutex::new(writer));
+        self.writer = Arc::downgrade(&writer);
+        let sender = Arc::new(spawn_log_thread(StdoutWriter(writer), format.clone(), channel));
+        self.sender = Arc::downgrade(&sender);
+        sender
+    }
+}
+
+/// Writer of the stdout log thread, replaced in place by a configuration drawing through an
+/// indicatif `MultiProgress`.
+struct StdoutWriter(Arc<Mutex<Box<dyn LogWriter + Send>>>);
+
+impl Drop for StdoutWriter {
+    fn drop(&mut self) {
+        STDOUT_THREADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LogWriter for StdoutWriter {
+    fn regular(&mut self, line: &str) {
+        self.0.lock().unwrap().regular(line);
+    }
+    fn regular_message(&mut self, line: &str, log_message: &LogMessage) {
+        self.0.lock().unwrap().regular_message(line, log_message);
+    }
+    fn progress(&mut self, line: &str, id: Uuid) {
+        self.0.lock().unwrap().progress(line, id);
+    }
+    fn finished(&mut self, id: Uuid) {
+        self.0.lock().unwrap().finished(id);
+    }
+    fn progress_with_header(&mut self, header: &str, line: &str, id: Uuid) {
+        self.0.lock().unwrap().progress_with_header(header, line, id);
+    }
+    fn println(&mut self, line: &str, log_message: &LogMessage, id: Uuid) {
+        self.0.lock().unwrap().println(line, log_message, id);
+    }
+    fn flush(&mut self) {
+        self.0.lock().unwrap().flush();
+    }
+    fn write_pending(&mut self) -> Option<Duration> {
+        self.0.lock().unwrap().write_pending()
+    }
+    fn reopen(&mut self) {
+        self.0.lock().unwrap().reopen();
+    }
+}
+
+/// Returns a sender writing to stdout with `format` through the stdout log thread of the process,
+/// started by the first configuration writing to stdout, so that a single thread writes to stdout.
+/// Its terminal settings, e.g. [`FormatOptions::leave_on_finish`], and its `channel` are those of
+/// that first configuration. With `exclusive`, a thread started with another format is a
+/// [`ConfigError::StdoutInUse`] error instead.
+pub(crate) fn shared_stdout(format: &FormatOptions, channel: ChannelConfig, capacity: Option<usize>, exclusive: bool) -> Result<Arc<LogSender>, ConfigError> {
+    let mut shared = SHARED_STDOUT.lock().unwrap();
+    let stdout = match shared.sender.upgrade() {
+        Some(stdout) if exclusive && &stdout.format != format => return Err(ConfigError::StdoutInUse),
+        Some(stdout) => stdout,
+        None => {
+            let writer = LogStdout::default().leave_on_finish(format.leave_on_finish).max_redraws_per_second(format.max_redraws_per_second);
+            shared.start(Box::new(writer), format, channel)
+        }
+    };
+    Ok(Arc::new(LogSender::share(&stdout, format).with_capacity(capacity)))
+}
+
+/// Like [`shared_stdout`], the stdout log thread drawing through `multi` from now on, for every
+/// configuration. With `exclusive`, a running thread is a [`ConfigError::StdoutInUse`] error.
+#[cfg(feature = "indicatif")]
+pub(crate) fn multi_progress_stdout(multi: indicatif::MultiProgress, format: &FormatOptions, channel: ChannelConfig, capacity: Option<usize>, exclusive: bool) -> Result<Arc<LogSender>, ConfigError> {
+    let mut shared = SHARED_STDOUT.lock().unwrap();
+    let writer = Box::new(crate::log_writer::MultiProgressWriter::new(multi).leave_on_finish(format.leave_on_finish));
+    let stdout = match (shared.sender.upgrade(), shared.writer.upgrade()) {
+        (Some(_), _) if exclusive => return Err(ConfigError::StdoutInUse),
+        (Some(stdout), Some(current)) => {
+            *current.lock().unwrap() = writer;
+            stdout
+        }
+        _ => shared.start(writer, format, channel),
+    };
+    Ok(Arc::new(LogSender::share(&stdout, format).with_capacity(capacity)))
+}
+
+/// Whether the stdout log thread of the process is running.
+#[cfg(test)]
+pub(crate) fn stdout_running() -> bool {
+    SHARED_STDOUT.lock().unwrap().sender.upgrade().is_some()
+}
+
+#[test]
+fn test_max_levels() {
+    let mut levels = MaxLevels { base: LevelFilter::Info, scoped: Vec::new() };
+    levels.scoped.extend([LevelFilter::Trace, LevelFilter::Debug]);
+    assert_eq!(levels.level(), LevelFilter::Trace);
+    levels.remove_scoped(LevelFilter::Trace);
+    assert_eq!(levels.level(), LevelFilter::Debug);
+    levels.remove_scoped(LevelFilter::Debug);
+    assert_eq!(levels.level(), LevelFilter::Info);
+    let guard = raise_max_level_scoped(LevelFilter::Trace);
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+    drop(guard);
+}
+
+#[test]
+fn test_replay() {
+    use crate::log_writer::MemoryWriter;
+    let uuid = Uuid::new_v4();
+    let message = |message: String| LogMessage { message, level: Level::Info, name: Some("replay".into()), fields: Vec::new() };
+    let messages = vec![
+        message("Hello, world!".into()),
+        message(format!("___PROGRESS___{uuid}___lorem ipsum")),
+        message("rust is awesome !".into()),
+        message(format!("___PROGRESS___{uuid}___LOREM IPSUM")),
+        message(format!("___PROGRESS___{uuid}___FINISHED")),
+        message("___SHUTDOWN___".into()),
+        message("ignored".into()),
+    ];
+    let mut writer = MemoryWriter::default();
+    replay(messages.into_iter(), &mut writer);
+    assert_eq!(writer.lines.len(), 3);
+    assert!(writer.lines[0].ends_with("replay INFO] Hello, world!"));
+    assert_eq!(writer.lines[1], "LOREM IPSUM");
+    assert!(writer.lines[2].ends_with("replay INFO] rust is awesome !"));
+}
+
+#[test]
+fn test_format_log() {
+    let log_message = LogMessage {
+        message: "user \"logged\" in".into(),
+        level: Level::Warn,
+        name: Some("auth".into()),
+        fields: vec![("id".into(), "42".into()), ("path".into(), "a\\b".into())],
+    };
+    let text = format_log(&log_message, &FormatOptions::default());
+    assert!(text.ends_with("] user \"logged\" in id=42 path=a\\b"));
+    let json = format_log(&log_message, &FormatOptions { log_format: LogFormat::Json, ..Default::default() });
+    assert!(json.starts_with("{\"time\":\""));
+    assert!(json.ends_with(r#","level":"WARN","name":"auth","message":"user \"logged\" in","id":42,"path":"a\\b"}"#));
+    let fields = [("ratio", "-1.5e3"), ("admin", "true"), ("zip", "01234"), ("level", "high"), ("version", "1.2.3")];
+    let log_message = LogMessage { fields: fields.map(|(key, value)| (key.into(), value.into())).into(), ..log_message };
+    let json = format_log(&log_message, &FormatOptions { log_format: LogFormat::Json, ..Default::default() });
+    assert!(json.ends_with(r#","ratio":-1.5e3,"admin":true,"zip":"01234","field_level":"high","version":"1.2.3"}"#));
+}
+
+#[test]
+fn test_clock() {
+    let log_message = LogMessage { message: "tick".into(), level: Level::Info, name: Some("clock".into()), fields: vec![("n".into(), "1".into())] };
+    let clock = Clock::new(|| "2024-02-29T23:59:59.999Z".parse().unwrap());
+    let text = format_log(&log_message, &FormatOptions { numeric_levels: true, clock: clock.clone(), ..Default::default() });
+    assert_eq!(text, "[2024-02-29T23:59:59.999 clock 3] tick n=1");
+    let json = format_log(&log_message, &FormatOptions { log_format: LogFormat::Json, clock, ..Default::default() });
+    assert_eq!(json, r#"{"time":"2024-02-29T23:59:59.999","level":"INFO","name":"clock","message":"tick","n":1}"#);
+    assert_eq!(FormatOptions::default(), FormatOptions::default());
+}
+
+#[test]
+fn test_fit_width() {
+    let line = "[time INFO] lorem ipsum dolor sit amet";
+    assert_eq!(fit_width(line, 80, WrapPolicy::Truncate), line);
+    assert_eq!(fit_width(line, 20, WrapPolicy::Truncate), "[time INFO] lorem i…");
+    assert_eq!(fit_width(line, 20, WrapPolicy::Wrap), "[time INFO] lorem ip\n    sum dolor sit am\n    et");
+    assert_eq!(fit_width("short\nlorem ipsum dolor", 10, WrapPolicy::Truncate), "short\nlorem ips…");
+    assert_eq!(fit_width("\x1B[32mINFO\x1B[0m lorem ipsum", 8, WrapPolicy::Truncate), "\x1B[32mINFO\x1B[0m lo…");
+    assert_eq!(fit_width("\x1B[32mINFO\x1B[0m lorem ipsum", 8, WrapPolicy::Wrap), "\x1B[32mINFO\x1B[0m lor\n    em i\n    psum");
+    assert_eq!(fit_width("日本語のログ", 7, WrapPolicy::Truncate), "日本語…");
+}
+
+#[test]
+fn test_max_width_below_progress() {
+    use crate::log_writer::{LogStdout, SharedBuffer};
+    let uuid = Uuid::new_v4();
+    let format = FormatOptions { max_width: Some((40, WrapPolicy::Wrap)), ..Default::default() };
+    let message = |message: String| LogMessage { message, level: Level::Info, name: None, fields: Vec::new() };
+    let output = SharedBuffer::default();
+    let mut writer = LogStdout::new(output.clone());
+    write_message(&mut writer, &message(format!("___PROGRESS___{uuid}___{}", "#".repeat(50))), &format, None);
+    write_message(&mut writer, &message("lorem ipsum dolor sit amet, consectetur adipiscing elit".into()), &format, None);
+    write_message(&mut writer, &message(format!("___PROGRESS___{uuid}___{}", "=".repeat(50))), &format, None);
+    write_message(&mut writer, &message(format!("___PROGRESS___{uuid}___FINISHED")), &format, None);
+    let contents = output.contents();
+    let lines = contents.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], format!("{}…", "#".repeat(39)));
+    assert!(lines[3].ends_with("elit"));
+    // The bar is redrawn 4 rows up, above the 3 rows of the wrapped line
+    assert!(contents.ends_with(&format!("elit\n\x1B[4A\r{}…\x1B[4B\r", "=".repeat(39))));
+}
+
+#[test]
+fn test_progress_end_after_shutdown() {
+    /// Writer recording the calls it receives.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+    impl LogWriter for Recorder {
+        fn regular(&mut self, _: &str) {}
+        fn progress(&mut self, line: &str, _: Uuid) {
+            self.0.lock().unwrap().push(line.into());
+        }
+        fn finished(&mut self, _: Uuid) {
+            self.0.lock().unwrap().push("finished".into());
+        }
+    }
+    let message = |message: String| Arc::new(LogMessage { message, level: Level::Info, name: None, fields: Vec::new() });
+    // The end of the bar is sent right after the shutdown request, as by a bar dropped concurrently
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let sender = spawn_log_thread(Recorder(calls.clone()), FormatOptions::default(), ChannelConfig::default());
+    let uuid = Uuid::new_v4();
+    let bar = sender.sender.clone().unwrap();
+    bar.send(message(format!("___PROGRESS___{uuid}___1/2")), None, PostShutdown::Stderr, None).unwrap();
+    sender.send(message("___SHUTDOWN___".into())).unwrap();
+    bar.send(message(format!("___PROGRESS___{uuid}___2/2")), None, PostShutdown::Stderr, None).unwrap();
+    bar.send(message(format!("___PROGRESS___{uuid}___FINISHED")), None, PostShutdown::Stderr, None).unwrap();
+    drop(sender);
+    assert_eq!(*calls.lock().unwrap(), ["1/2", "2/2", "finished"]);
+    // A bar that never ends is finished by the log thread when it exits, right away when no other
+    // sender could still send its end
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let sender = spawn_log_thread(Recorder(calls.clone()), FormatOptions::default(), ChannelConfig::default());
+    sender.send(message(format!("___PROGRESS___{uuid}___1/2"))).unwrap();
+    let start = Instant::now();
+    drop(sender);
+    assert!(start.elapsed() < SHUTDOWN_GRACE);
+    assert_eq!(*calls.lock().unwrap(), ["1/2", "finished"]);
+    // and after the grace period otherwise
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let sender = spawn_log_thread(Recorder(calls.clone()), FormatOptions::default(), ChannelConfig::default());
+    let bar = sender.sender.clone().unwrap();
+    sender.send(message(format!("___PROGRESS___{uuid}___1/2"))).unwrap();
+    let start = Instant::now();
+    drop(sender);
+    assert!(start.elapsed() >= SHUTDOWN_GRACE);
+    assert_eq!(*calls.lock().unwrap(), ["1/2", "finished"]);
+    drop(bar);
+}
+
+#[test]
+fn test_post_shutdown_during_grace() {
+    if std::env::var_os("MTLOG_TEST_GRACE_CHILD").is_some() {
+        let message = |message: &str| Arc::new(LogMessage { message: message.into(), level: Level::Info, name: None, fields: Vec::new() });
+        let sender = spawn_log_thread(crate::log_writer::MemoryWriter::default(), FormatOptions::default(), ChannelConfig::default());
+        let bar = sender.sender.clone().unwrap();
+        sender.send(message(&format!("___PROGRESS___{}___1/2", Uuid::new_v4()))).unwrap();
+        sender.send(message("___SHUTDOWN___")).unwrap();
+        bar.send(message("discarded during grace"), None, PostShutdown::Discard, None).unwrap();
+        bar.send(message("written during grace"), None, PostShutdown::Stderr, None).unwrap();
+        drop(bar);
+        drop(sender);
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "utils::test_post_shutdown_during_grace"])
+        .env("MTLOG_TEST_GRACE_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("written during grace"));
+    assert!(!stderr.contains("discarded during grace"));
+}
+
+#[test]
+fn test_pending_frame_drawn_without_update() {
+    use crate::log_writer::{LogStdout, SharedBuffer};
+    let output = SharedBuffer::default();
+    let sender = spawn_log_thread(LogStdout::new(output.clone()).max_redraws_per_second(Some(10)), FormatOptions::default(), ChannelConfig::default());
+    let uuid = Uuid::new_v4();
+    for frame in ["frame 0", "frame 1"] {
+        sender.send(Arc::new(LogMessage { message: format!("___PROGRESS___{uuid}___{frame}"), level: Level::Info, name: None, fields: Vec::new() })).unwrap();
+    }
+    // The second frame is held back by the redraw cap, then drawn by the log thread on its own
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !output.contents().contains("frame 1") {
+        assert!(Instant::now() < deadline);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    drop(sender);
+}
+
+#[test]
+fn test_stats_by_name() {
+    use crate::log_writer::{FileMode, LogFile};
+    let path = "/tmp/test_stats_by_name.log";
+    let sender = spawn_log_thread(LogFile::open(path, FileMode::Truncate).unwrap(), FormatOptions::default(), ChannelConfig::default()).with_stats_by_name(true);
+    let message = |name: &str, message: &str| Arc::new(LogMessage { message: message.into(), level: Level::Info, name: Some(name.into()), fields: Vec::new() });
+    for (name, count) in [("small", 1), ("large", 5), ("medium", 3)] {
+        for _ in 0..count {
+            sender.send(message(name, "lorem ipsum")).unwrap();
+        }
+    }
+    sender.send(Arc::new(LogMessage { message: "unnamed".into(), level: Level::Info, name: None, fields: Vec::new() })).unwrap();
+    sender.flush();
+    let stats = sender.stats_by_name();
+    let names = stats.iter().map(|(name, _)| name.as_deref()).collect::<Vec<_>>();
+    assert_eq!(names, [Some("large"), Some("medium"), Some("small"), None]);
+    assert_eq!(stats.iter().map(|(_, stats)| stats.lines).collect::<Vec<_>>(), [5, 3, 1, 1]);
+    let bytes = stats.iter().map(|(_, stats)| stats.bytes).sum::<u64>();
+    assert_eq!(bytes, std::fs::metadata(path).unwrap().len());
+    // Names beyond the cap are summed under `other`
+    for i in 0..100 {
+        sender.send(message(&format!("name_{i}"), "lorem ipsum")).unwrap();
+    }
+    sender.flush();
+    let stats = sender.stats_by_name();
+    assert_eq!(stats.len(), 65);
+    assert_eq!(stats.iter().find(|(name, _)| name.as_deref() == Some("other")).unwrap().1.lines, 40);
+    assert_eq!(stats.iter().map(|(_, stats)| stats.bytes).sum::<u64>(), std::fs::metadata(path).unwrap().len());
+    // Nothing is accounted unless enabled
+    let sender = spawn_log_thread(LogFile::open(path, FileMode::Truncate).unwrap(), FormatOptions::default(), ChannelConfig::default());
+    sender.send(message("small", "lorem ipsum")).unwrap();
+    sender.flush();
+    assert!(sender.stats_by_name().is_empty());
+}
+
+#[test]
+fn test_target_name() {
+    let cached = TARGET_NAMES_CACHED.load(Ordering::SeqCst);
+    assert_eq!(&*target_name("test_target_name_alpha::db::pool"), "test_target_name_alpha");
+    assert_eq!(&*target_name("test_target_name_beta"), "test_target_name_beta");
+    assert_eq!(&*target_name("test_target_name_alpha::db::pool"), "test_target_name_alpha");
+    assert_eq!(TARGET_NAMES_CACHED.load(Ordering::SeqCst) - cached, 2);
+}
+
+#[test]
+fn test_accent_color() {
+    let format = FormatOptions { accent_color: Some(Color::Magenta), ..Default::default() };
+    let name = Some("billing");
+    assert!(accented_header(Level::Warn, name, &format, true).ends_with(" \x1B[35mbilling\x1B[0m \x1B[35mWARN\x1B[0m]"));
+    assert!(accented_header(Level::Warn, None, &format, true).ends_with(" \x1B[35mWARN\x1B[0m]"));
+    assert!(accented_header(Level::Warn, name, &format, false).ends_with(" billing WARN]"));
+}
+
+#[test]
+fn test_shutdown_twice() {
+    use crate::log_writer::MemoryWriter;
+    let mut sender = spawn_log_thread(MemoryWriter::default(), FormatOptions::default(), ChannelConfig::default());
+    sender.send(Arc::new(LogMessage { message: "before".into(), level: Level::Info, name: None, fields: Vec::new() })).unwrap();
+    sender.shutdown();
+    sender.shutdown();
+    assert!(sender.handler.is_none());
+    drop(sender);
+}
+
+#[test]
+fn test_shutdown_after_writer_panic() {
+    struct PanickingWriter;
+    impl LogWriter for PanickingWriter {
+        fn regular(&mut self, _line: &str) {
+            panic!("writer failure");
+        }
+        fn progress(&mut self, _line: &str, _id: Uuid) {}
+        fn finished(&mut self, _id: Uuid) {}
+    }
+    let sender = spawn_log_thread(PanickingWriter, FormatOptions::default(), ChannelConfig::default());
+    sender.send(Arc::new(LogMessage { message: "panics".into(), level: Level::Info, name: None, fields: Vec::new() })).unwrap();
+    drop(sender);
+}
+
+#[test]
+fn test_render_template() {
+    let lookup = |variable: &str| (variable == "index").then(|| "3".to_string());
+    assert_eq!(render_template("worker-{index}", lookup).unwrap(), "worker-3");
+    assert_eq!(render_template("{{index}}={index}", lookup).unwrap(), "{index}=3");
+    assert_eq!(render_template("plain", lookup).unwrap(), "plain");
+    assert!(matches!(render_template("worker-{host}", lookup), Err(ConfigError::UnboundVariable(variable)) if variable == "host"));
+    assert!(matches!(render_template("worker-{index", lookup), Err(ConfigError::InvalidTemplate(_))));
+    assert!(matches!(render_template("worker-}", lookup), Err(ConfigError::InvalidTemplate(_))));
+    assert!(!hostname().is_empty());
+}
+
+#[test]
+fn test_channel_config() {
+    // Writer holding the log thread on its first message until the gate opens, meeting the test
+    // at the barrier once it holds it
+    struct Gated(Arc<(Mutex<bool>, Condvar)>, Arc<Mutex<Vec<String>>>, Option<Arc<std::sync::Barrier>>);
+    impl LogWriter for Gated {
+        fn regular(&mut self, line: &str) {
+            if let Some(holding) = self.2.take() {
+                holding.wait();
+            }
+            let (open, condvar) = &*self.0;
+            let _open = condvar.wait_while(open.lock().unwrap(), |open| !*open).unwrap();
+            self.1.lock().unwrap().push(line.into());
+        }
+        fn progress(&mut self, _: &str, _: Uuid) {}
+        fn finished(&mut self, _: Uuid) {}
+    }
+    // Number of messages sent while the log thread is blocked, all of them being written once it resumes
+    let sent_while_blocked = |channel: ChannelConfig| {
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let holding = Arc::new(std::sync::Barrier::new(2));
+        let sender = Arc::new(spawn_log_thread(Gated(gate.clone(), lines.clone(), Some(holding.clone())), FormatOptions::default(), channel));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (thread_sender, thread_sent) = (sender.clone(), sent.clone());
+        let producer = std::thread::spawn(move || {
+            for i in 0..10 {
+                thread_sender.send(Arc::new(LogMessage { message: format!("message {i}"), level: Level::Info, name: None, fields: Vec::new() })).unwrap();
+                thread_sent.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        holding.wait();
+        // Pending once the producer blocks: the messages waiting in the channel and the one being sent
+        let blocked_pending = match channel {
+            ChannelConfig::Unbounded => usize::MAX,
+            ChannelConfig::Bounded(capacity) => capacity + 1,
+            ChannelConfig::Rendezvous => 1,
+        };
+        while sender.pending() < blocked_pending && !producer.is_finished() {
+            std::thread::yield_now();
+        }
+        let blocked = sent.load(Ordering::SeqCst);
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        producer.join().unwrap();
+        drop(sender);
+        assert_eq!(lines.lock().unwrap().len(), 10);
+        blocked
+    };
+    assert_eq!(sent_while_blocked(ChannelConfig::Unbounded), 10);
+    // One message held by the log thread, the others waiting in the channel
+    assert_eq!(sent_while_blocked(ChannelConfig::Bounded(2)), 3);
+    assert_eq!(sent_while_blocked(ChannelConfig::Rendezvous), 1);
+}
@@ -0,0 +1,28 @@
+use mtlog_tokio::{instrument, logger_config};
+
+#[instrument(logger_config().with_log_file(path).unwrap().no_stdout().with_name(format!("job {id}")))]
+async fn job(id: u32, path: &'static str) -> Result<u32, std::num::ParseIntError> {
+    log::info!("started");
+    tokio::task::yield_now().await;
+    let parsed = "12".parse::<u32>()?;
+    log::info!("parsed {parsed}");
+    Ok(parsed + id)
+}
+
+#[instrument]
+async fn unnamed() -> String {
+    mtlog_tokio::current_senders().name().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_instrument() {
+    let path = "/tmp/test_instrument_tokio.log";
+    std::fs::remove_file(path).ok();
+    assert_eq!(job(3, path).await, Ok(15));
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" job 3 INFO] started"));
+    assert!(lines[1].ends_with(" job 3 INFO] parsed 12"));
+    assert_eq!(unnamed().await, "unnamed");
+}
@@ -5,16 +5,16 @@ use mtlog::logger_config;
 
 fn main() {
     // main thread log to stdout only
-    logger_config()
+    let handle = logger_config()
         .with_name("main thread")
-        .init_global();
+        .init_global_with_handle();
     log::info!("Hello, world!");
     // threaded tasks log to files
     let (handles, senders): (Vec<_>,Vec<_>) = (0..5).map(|i| {
         let (sender, receiver) = channel::<&'static str>();
         (std::thread::spawn(move || {
             logger_config()
-                .with_name(&format!("thread {i}"))
+                .with_name(format!("thread {i}"))
                 .with_log_file(format!("/tmp/thread_{i}.log"))
                 .unwrap()
                 .init_local();
@@ -32,5 +32,5 @@ fn main() {
     for i in 0..5 {
         log::info!("last line of /tmp/thread_{i}.log is:\n\t{}",std::fs::read_to_string(format!("/tmp/thread_{i}.log")).unwrap().trim_end().lines().last().unwrap());
     }
-    std::thread::sleep(std::time::Duration::from_millis(1)); // wait for the last log to be writtens
+    handle.shutdown(); // writes the last log before exiting
 }
\ No newline at end of file
@@ -31,7 +31,7 @@
 //! for i in 0..5 {
 //!     std::thread::spawn(move || {
 //!        logger_config()
-//!             .with_name(&format!("thread {i}"))
+//!             .with_name(format!("thread {i}"))
 //!             .init_local();
 //!     log::warn!("Hello, world from thread {i}!")
 //!    });
@@ -42,6 +42,8 @@
 //! ## Logging to files
 //! Files can be used to log messages. The log file is created if it does not exist and appended to if it does.
 //! Threads can log to different files. If no file is specified in local config, the global file is used.
+//! A single record can be kept out of the terminal with the `mtlog_dest` key-value,
+//! `log::info!(mtlog_dest = "file"; "...")`, or out of the file with `mtlog_dest = "stdout"`.
 //! 
 //! ```rust
 //! use mtlog::logger_config;
@@ -56,44 +58,341 @@
 //! std::thread::sleep(std::time::Duration::from_millis(1)); // wait for log to flush
 //! assert!(std::fs::read_to_string("/tmp/app.log").unwrap().ends_with("Hello, world!\n"));
 //! ```
+//!
+//! ## Compile-time maximum level
+//! The `max_level_*` and `release_max_level_*` features (e.g. `release_max_level_info`) set the
+//! matching features of `log`: records above that level are compiled out of every crate of the
+//! build, arguments included. This is distinct from [`ConfigBuilder::with_level`], which filters
+//! at runtime and can only be more restrictive.
 
 
 // mod progress_bar;
+mod error;
 mod log_writer;
+mod metrics;
+mod schedule;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 mod utils;
 
-use std::{cell::RefCell, path::Path, sync::{Arc, LazyLock, RwLock}};
-use log_writer::{LogFile, LogStdout};
-use utils::{spawn_log_thread, LogSender, LogMessage};
-use log::{LevelFilter, Log};
+use std::{backtrace::{Backtrace, BacktraceStatus}, borrow::Cow, cell::RefCell, fmt::{self, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, LazyLock, OnceLock, RwLock}, time::Duration};
+use log_writer::{FallbackLogFile, LogFile, LogStderr, PendingLogFile};
+use schedule::Schedule;
+use utils::{apply_middlewares, ends_bar, hostname, in_test_mode, raise_max_level, raise_max_level_scoped, render_template, sanitize_message, sanitize_name, shared_stdout, spawn_log_thread, target_name, write_fallback, Clock, FormatOptions, LogSender, Middleware};
+pub use utils::{ChannelConfig, LogFormat, PostShutdown, WrapPolicy};
+pub use colored::Color;
+pub use error::ConfigError;
+pub use log_writer::{FileMode, LogStdout, LogWriter, MemoryWriter};
+#[cfg(feature = "indicatif")]
+pub use log_writer::MultiProgressWriter;
+#[cfg(feature = "indicatif")]
+use utils::multi_progress_stdout;
+#[cfg(feature = "tracing")]
+pub use log_writer::TracingForwardWriter;
+pub use schedule::TimeRange;
+use metrics::ErrorSummary;
+pub use metrics::{counter, gauge, report_metrics, tick_metrics, Counter, Gauge, SinkStats};
+pub use utils::{replay, test_mode, LogMessage};
+use chrono::{DateTime, Utc};
+use log::{Level, LevelFilter, Log};
+use uuid::Uuid;
+
+
+/// Deterministic sampling of the Debug and Trace messages of the targets starting with a prefix.
+///
+/// The first matching message is kept, then one message out of `every`.
+struct Sampler {
+    target_prefix: String,
+    every: u64,
+    counter: AtomicU64,
+}
+
+impl Sampler {
+    fn new(target_prefix: &str, ratio: f64) -> Self {
+        let every = (1.0 / ratio).round().clamp(1.0, u64::MAX as f64) as u64;
+        Self { target_prefix: target_prefix.into(), every, counter: AtomicU64::new(0) }
+    }
+
+    fn keep(&self) -> bool {
+        self.counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.every)
+    }
+}
 
+/// Sinks a record is sent to, restricted at the call site with the `mtlog_dest` key-value,
+/// e.g. `log::info!(mtlog_dest = "file"; "token rotated")`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Destination {
+    /// Every sink, the default.
+    All,
+    /// The log file only, for `mtlog_dest = "file"`.
+    File,
+    /// The terminal only, for `mtlog_dest = "stdout"`.
+    Stdout,
+    /// The trace file only, for the Trace records when one is set.
+    Trace,
+}
+
+impl Destination {
+    fn of(record: &log::Record) -> Self {
+        match record.key_values().get(log::kv::Key::from_str("mtlog_dest")).as_ref().and_then(log::kv::Value::to_borrowed_str) {
+            Some("file") => Self::File,
+            Some("stdout") => Self::Stdout,
+            _ => Self::All,
+        }
+    }
+}
 
 /// Configuration for the logger.
+///
+/// The senders are declared in the order messages are sent to them, so that dropping the
+/// configuration also shuts down the file log thread first.
+#[derive(Clone)]
 struct LogConfig {
     /// Optional log message sender to a thread handling file logging.
     sender_file: Option<Arc<LogSender>>,
+    /// Optional log message sender to a thread writing the Trace messages, instead of the other sinks.
+    sender_trace: Option<Arc<LogSender>>,
+    /// Log message senders to threads handling custom writers.
+    sender_writers: Vec<Arc<LogSender>>,
+    /// Formatting and channel options of the custom writers, also used by [`LoggerHandle::add_writer`].
+    writer_options: (FormatOptions, ChannelConfig),
     /// Optional log message sender to a thread handling stdout.
     sender_stdout: Option<Arc<LogSender>>,
+    /// Stdout sender of the default global configuration, only started by the first message sent to it.
+    lazy_stdout: Option<Arc<OnceLock<Arc<LogSender>>>>,
+    /// Optional log message sender to a thread drawing progress bars on stderr instead of stdout.
+    sender_progress: Option<Arc<LogSender>>,
     /// Optional logger name.
-    name: Option<String>,
+    name: Option<Arc<str>>,
     /// Maximum log level
     level: LevelFilter,
+    /// Middlewares applied in order to regular messages by the log threads, if any.
+    middlewares: Option<Arc<[Middleware]>>,
+    /// Samplers of Debug and Trace messages, the first matching the target applies.
+    samplers: Vec<Arc<Sampler>>,
+    /// Escape the control characters of regular messages, except newlines and tabs.
+    escape_control_chars: bool,
+    /// Handling of the messages that no log thread can take.
+    post_shutdown: PostShutdown,
+    /// Source of the timestamps of the messages written to stderr by the post-shutdown policy.
+    clock: Clock,
+    /// Whether the configuration was shut down, its senders being gone.
+    shut_down: bool,
+    /// Maximum levels of the file sink by time of day.
+    file_schedule: Schedule,
+    /// Append a backtrace to the Error messages, if enabled by `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE`.
+    error_backtraces: bool,
+    /// Use the crate of the record target as name when none is set.
+    name_from_target: bool,
+    /// Whether Trace messages are written to the trace file, or dropped.
+    trace_capture: bool,
+    /// Occurrences of the Error and Warn messages, shared by the clones of the configuration and
+    /// reported when the last one is dropped.
+    error_summary: Option<Arc<ErrorSummary>>,
+}
+
+impl Drop for LogConfig {
+    fn drop(&mut self) {
+        if let Some(error_summary) = self.error_summary.take().and_then(Arc::into_inner) {
+            self.send_error_summary(&error_summary);
+        }
+    }
+}
+
+impl LogConfig {
+    /// Sends the message to every sink of the configuration.
+    fn send(&self, log_message: LogMessage) {
+        self.send_to(log_message, Destination::All);
+    }
+
+    /// Sends the message to the sinks of `destination`, whose log threads apply the middlewares.
+    fn send_to(&self, mut log_message: LogMessage, destination: Destination) {
+        if self.escape_control_chars && !log_message.message.starts_with("___PROGRESS___") {
+            if let Cow::Owned(message) = sanitize_message(&log_message.message) {
+                log_message.message = message;
+            }
+        }
+        if self.error_backtraces && log_message.level == Level::Error && !log_message.message.starts_with("___PROGRESS___") {
+            let backtrace = Backtrace::capture();
+            if backtrace.status() == BacktraceStatus::Captured {
+                for line in backtrace.to_string().lines() {
+                    write!(log_message.message, "\n    {line}").unwrap();
+                }
+            }
+        }
+        if let Some(error_summary) = &self.error_summary {
+            if log_message.level <= Level::Warn && !log_message.message.starts_with("___PROGRESS___") {
+                error_summary.record(log_message.level, &log_message.message);
+            }
+        }
+        if self.shut_down {
+            self.write_fallback(&log_message);
+            return;
+        }
+        let log_message = Arc::new(log_message);
+        let progress = log_message.message.starts_with("___PROGRESS___");
+        let file_allowed = self.file_schedule.allows(log_message.level);
+        let (mut delivered, mut failed, mut terminal_failed) = (false, false, false);
+        let test_mode = in_test_mode();
+        for sender in self.message_senders(progress, file_allowed, destination) {
+            if sender.send_through(log_message.clone(), self.middlewares.as_ref(), self.post_shutdown).is_ok() {
+                delivered = true;
+            } else {
+                failed = true;
+                terminal_failed |= self.is_terminal(sender);
+            }
+            if test_mode {
+                sender.flush_timeout(TEST_MODE_FLUSH_TIMEOUT);
+            }
+        }
+        // Stderr stands in for the terminal: a message already written by the other sinks is not
+        // repeated there when only a file or custom writer failed
+        if failed && (terminal_failed || !delivered) {
+            self.write_fallback(&log_message);
+        }
+    }
+
+    /// Whether `sender` writes to the terminal, on stdout or stderr.
+    fn is_terminal(&self, sender: &Arc<LogSender>) -> bool {
+        self.started_stdout().into_iter().chain(&self.sender_progress).any(|terminal| Arc::ptr_eq(terminal, sender))
+    }
+
+    /// Logs the most frequent Error and Warn messages counted by `error_summary`, see [`ConfigBuilder::with_error_summary`].
+    fn send_error_summary(&self, error_summary: &ErrorSummary) {
+        if let Some(report) = error_summary.report() {
+            self.send(LogMessage { level: Level::Info, name: self.name.clone(), message: report, fields: Vec::new() });
+        }
+    }
+
+    /// Sinks of a message at `level` asked for `destination`, or `None` if it is filtered out.
+    /// With a trace file, Trace messages only go there, and only while capturing.
+    fn route(&self, level: Level, destination: Destination) -> Option<Destination> {
+        if level == Level::Trace && self.sender_trace.is_some() {
+            self.trace_capture.then_some(Destination::Trace)
+        } else {
+            (level <= self.level).then_some(destination)
+        }
+    }
+
+    /// Senders a message is sent to, in order: the file first so that durable output is prioritized,
+    /// then the custom writers, then the terminal, where progress lines may go to stderr.
+    /// Sinks outside of `destination` are skipped.
+    fn message_senders(&self, progress: bool, file_allowed: bool, destination: Destination) -> impl Iterator<Item = &Arc<LogSender>> {
+        let sender_file = self.sender_file.as_ref().filter(|_| file_allowed && matches!(destination, Destination::All | Destination::File));
+        let sender_trace = self.sender_trace.as_ref().filter(|_| destination == Destination::Trace);
+        let sender_writers = if destination == Destination::All { &self.sender_writers[..] } else { &[] };
+        let sender_terminal = match (destination, progress) {
+            (Destination::File | Destination::Trace, _) => None,
+            (_, true) => self.sender_progress.as_ref().or_else(|| self.stdout()),
+            (_, false) => self.stdout(),
+        };
+        sender_file.into_iter().chain(sender_trace).chain(sender_writers).chain(sender_terminal)
+    }
+
+    /// Senders of every sink, in the order of [`LogConfig::message_senders`].
+    fn senders(&self) -> impl Iterator<Item = &Arc<LogSender>> {
+        self.sender_file.iter().chain(&self.sender_trace).chain(&self.sender_writers).chain(self.started_stdout()).chain(&self.sender_progress)
+    }
+
+    /// Stdout sender, started on the first call for the default global configuration.
+    fn stdout(&self) -> Option<&Arc<LogSender>> {
+        self.sender_stdout.as_ref().or_else(|| self.lazy_stdout.as_ref().map(|lazy_stdout| lazy_stdout.get_or_init(|| shared_stdout(&FormatOptions::default(), ChannelConfig::default(), Some(STDOUT_CAPACITY), false).expect("stdout is shared when not exclusive"))))
+    }
+
+    /// Stdout sender, if started.
+    fn started_stdout(&self) -> Option<&Arc<LogSender>> {
+        self.sender_stdout.as_ref().or_else(|| self.lazy_stdout.as_ref().and_then(|lazy_stdout| lazy_stdout.get()))
+    }
+
+    /// Handles a message that no log thread can take, according to the post-shutdown policy.
+    fn write_fallback(&self, log_message: &LogMessage) {
+        if self.post_shutdown != PostShutdown::Stderr {
+            return;
+        }
+        match &self.middlewares {
+            Some(middlewares) => if let Some(log_message) = apply_middlewares(middlewares, log_message.clone()) {
+                write_fallback(&log_message, &self.clock);
+            },
+            None => write_fallback(log_message, &self.clock),
+        }
+    }
+}
+
+/// Whether the logger of the crate was installed in the `log` facade, see [`is_installed`].
+static LOGGER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the logger of the crate is installed, i.e. whether a configuration was
+/// initialized or logged to. Unlike the other functions, it never installs it.
+pub fn is_installed() -> bool {
+    LOGGER_INSTALLED.load(Ordering::SeqCst)
 }
 
 /// Global configuration for the logger, accessible across threads.
 static GLOBAL_LOG_CONFIG: LazyLock<Arc<RwLock<LogConfig>>> = LazyLock::new(|| {
     log::set_boxed_logger(Box::new(MTLogger)).unwrap();
-    log::set_max_level(LevelFilter::Info);
-    let sender = spawn_log_thread(LogStdout::default());
+    LOGGER_INSTALLED.store(true, Ordering::SeqCst);
+    raise_max_level(LevelFilter::Info);
     Arc::new(RwLock::new(LogConfig {
-        sender_stdout: Some(Arc::new(sender)),
+        sender_stdout: None,
+        lazy_stdout: Some(Arc::default()),
+        sender_progress: None,
+        sender_writers: Vec::new(),
+        writer_options: Default::default(),
         sender_file: None,
+        sender_trace: None,
         name: None,
         level: LevelFilter::Info,
+        middlewares: None,
+        samplers: Vec::new(),
+        escape_control_chars: false,
+        post_shutdown: PostShutdown::Stderr,
+        clock: Clock::default(),
+        shut_down: false,
+        file_schedule: Schedule::default(),
+        error_backtraces: false,
+        name_from_target: false,
+        trace_capture: false,
+        error_summary: None,
     }))
 });
 
 
+/// Default maximum number of messages waiting for the stdout log thread, see [`ConfigBuilder::with_stdout_capacity`].
+const STDOUT_CAPACITY: usize = 10_000;
+
+/// Whether the global configuration was set by `init_global` or `ensure_initialized`.
+static GLOBAL_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Incremented each time the global configuration is replaced.
+static GLOBAL_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum time spent flushing the global sinks at process exit.
+#[cfg(unix)]
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum time spent waiting for each sink to write a message in test mode, see [`test_mode`].
+const TEST_MODE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Guards the registration of [`flush_at_exit`].
+#[cfg(unix)]
+static EXIT_FLUSH_REGISTERED: std::sync::Once = std::sync::Once::new();
+
+/// Best-effort flush of the global sinks, registered with `atexit` by [`LoggerHandle::leak`].
+/// The global configuration is never dropped, so its error summary is logged first.
+#[cfg(unix)]
+extern "C" fn flush_at_exit() {
+    let Ok(global_config) = GLOBAL_LOG_CONFIG.try_read() else {
+        return;
+    };
+    if let Some(error_summary) = &global_config.error_summary {
+        global_config.send_error_summary(error_summary);
+    }
+    let deadline = std::time::Instant::now() + EXIT_FLUSH_TIMEOUT;
+    for sender in global_config.senders() {
+        sender.flush_timeout(deadline.saturating_duration_since(std::time::Instant::now()));
+    }
+}
+
 thread_local! {
     /// Thread-local logger configuration for finer control over logging settings per thread.
     pub static LOG_CONFIG: RefCell<Option<LogConfig>> = const { RefCell::new(None) };
@@ -104,83 +403,334 @@ thread_local! {
 struct MTLogger;
 
 impl Log for MTLogger {
-    fn enabled(&self, _: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        PAUSES.load(Ordering::Relaxed) == 0 && metadata.level() <= log::STATIC_MAX_LEVEL
     }
 
     fn log(&self, record: &log::Record) {
+        if PAUSES.load(Ordering::Relaxed) > 0 {
+            SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
         LOG_CONFIG.with(|local_config| {
             let local_config = local_config.borrow();
             let global_config = GLOBAL_LOG_CONFIG.read().unwrap();
             let config = local_config.as_ref().unwrap_or(&global_config);
             let level = record.level();
-            if level > config.level {
+            let Some(destination) = config.route(level, Destination::of(record)) else {
                 return;
+            };
+            let mut sampled_every = None;
+            if level >= Level::Debug {
+                if let Some(sampler) = config.samplers.iter().find(|sampler| record.target().starts_with(&sampler.target_prefix)) {
+                    if !sampler.keep() {
+                        return;
+                    }
+                    sampled_every = Some(sampler.every);
+                }
             }
-            let log_message = Arc::new(LogMessage { level, name: config.name.clone(), message: record.args().to_string()});
-            if let Some(sender) = &config.sender_stdout {
-                sender.send(log_message.clone()).expect("Unable to send log message to stdout logging thread");
-            }
-            if let Some(sender) = &config.sender_file {
-                sender.send(log_message).expect("Unable to send log message to file logging thread");
+            let mut message = record.args().to_string();
+            if let Some(every) = sampled_every {
+                message.push_str(&format!(" sampled=1/{every}"));
             }
+            let name = match &config.name {
+                None if config.name_from_target && !message.starts_with("___PROGRESS___") => Some(target_name(record.target())),
+                name => name.clone(),
+            };
+            config.send_to(LogMessage { level, name, message, fields: Vec::new() }, destination);
         });
     }
 
     fn flush(&self) {}
 }
 
+/// Opens a log file, the error holding its path.
+fn open_log_file<P: AsRef<Path>>(path: P, mode: FileMode) -> Result<LogFile, ConfigError> {
+    LogFile::open(&path, mode).map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => ConfigError::FileExists(path.as_ref().into()),
+        _ => ConfigError::io(err, path.as_ref()),
+    })
+}
+
 /// Builder for configuring and initializing the logger.
 pub struct ConfigBuilder {
     log_file: Option<LogFile>,
+    pending_log_file: Option<PendingLogFile>,
+    trace_file: Option<LogFile>,
+    fallback_file: Option<PathBuf>,
     no_stdout: bool,
+    stdout_capacity: Option<usize>,
+    channel: ChannelConfig,
     no_file: bool,
+    verify_writes: bool,
+    progress_on_stderr: bool,
+    leave_on_finish: bool,
+    max_redraws_per_second: Option<u32>,
+    progress_headers: bool,
+    numeric_levels: bool,
+    log_format: LogFormat,
+    stdout_format: Option<LogFormat>,
+    file_format: Option<LogFormat>,
     log_level: LevelFilter,
     name: Option<String>,
+    name_template: Option<String>,
+    /// Variables of the name template, the last binding of a variable winning.
+    name_bindings: Vec<(String, String)>,
+    middlewares: Vec<Middleware>,
+    /// Custom writers, each handled by its own log thread started when the configuration is built.
+    writers: Vec<Box<dyn LogWriter + Send>>,
+    samplers: Vec<Arc<Sampler>>,
+    escape_control_chars: bool,
+    strict_names: bool,
+    post_shutdown: PostShutdown,
+    file_schedule: Schedule,
+    clock: Clock,
+    max_width: Option<(usize, WrapPolicy)>,
+    accent_color: Option<Color>,
+    escape_newlines: bool,
+    session_separator: bool,
+    error_backtraces: bool,
+    error_summary: Option<usize>,
+    name_from_target: bool,
+    stats_by_name: bool,
+    #[cfg(feature = "indicatif")]
+    multi_progress: Option<indicatif::MultiProgress>,
 }
 
 impl Default for ConfigBuilder {
     fn default() -> Self {
         Self {
             log_file: None,
+            pending_log_file: None,
+            trace_file: None,
+            fallback_file: None,
             no_stdout: false,
+            stdout_capacity: Some(STDOUT_CAPACITY),
+            channel: ChannelConfig::Unbounded,
             no_file: false,
+            verify_writes: false,
+            progress_on_stderr: false,
+            leave_on_finish: true,
+            max_redraws_per_second: None,
+            progress_headers: false,
+            numeric_levels: false,
+            log_format: LogFormat::Text,
+            stdout_format: None,
+            file_format: None,
             log_level: LevelFilter::Info,
             name: None,
+            name_template: None,
+            name_bindings: Vec::new(),
+            middlewares: Vec::new(),
+            writers: Vec::new(),
+            samplers: Vec::new(),
+            escape_control_chars: false,
+            strict_names: false,
+            post_shutdown: PostShutdown::Stderr,
+            file_schedule: Schedule::default(),
+            clock: Clock::default(),
+            max_width: None,
+            accent_color: None,
+            escape_newlines: false,
+            session_separator: false,
+            error_backtraces: false,
+            error_summary: None,
+            name_from_target: false,
+            stats_by_name: false,
+            #[cfg(feature = "indicatif")]
+            multi_progress: None,
         }
     }
 }
 
 impl ConfigBuilder {
+    /// Formatting options of the terminal and file log threads.
+    fn formats(&self) -> (FormatOptions, FormatOptions) {
+        let format = FormatOptions {
+            numeric_levels: self.numeric_levels,
+            leave_on_finish: self.leave_on_finish,
+            max_redraws_per_second: self.max_redraws_per_second,
+            progress_headers: false,
+            log_format: self.stdout_format.unwrap_or(self.log_format),
+            clock: self.clock.clone(),
+            max_width: self.max_width,
+            accent_color: self.accent_color,
+            escape_newlines: false,
+        };
+        let file_format = FormatOptions {
+            progress_headers: self.progress_headers,
+            log_format: self.file_format.unwrap_or(self.log_format),
+            escape_newlines: self.escape_newlines,
+            ..format.clone()
+        };
+        (format, file_format)
+    }
+
+    /// Stdout sender of the configuration, see [`shared_stdout`].
+    fn stdout_sender(&mut self, format: &FormatOptions, exclusive: bool) -> Result<Option<Arc<LogSender>>, ConfigError> {
+        if self.no_stdout {
+            return Ok(None);
+        }
+        #[cfg(feature = "indicatif")]
+        if let Some(multi) = self.multi_progress.take() {
+            return multi_progress_stdout(multi, format, self.channel, self.stdout_capacity, exclusive).map(Some);
+        }
+        shared_stdout(format, self.channel, self.stdout_capacity, exclusive).map(Some)
+    }
+
+    /// Value of a variable of the name template: bound, or built in.
+    fn name_variable(&self, variable: &str) -> Option<String> {
+        if let Some((_, value)) = self.name_bindings.iter().rev().find(|(bound, _)| bound == variable) {
+            return Some(value.clone());
+        }
+        match variable {
+            "hostname" => Some(hostname()),
+            "pid" => Some(std::process::id().to_string()),
+            "thread" => {
+                let thread = std::thread::current();
+                let id = format!("{:?}", thread.id());
+                Some(thread.name().map_or_else(|| id.trim_start_matches("ThreadId(").trim_end_matches(')').into(), Into::into))
+            }
+            _ => None,
+        }
+    }
+
     fn build(self) -> LogConfig {
-        let Self { log_file, no_stdout, no_file, log_level, name } = self;
-        let name = name.map(String::from);
+        self.try_build(false).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Builds the configuration, failing with `exclusive_stdout` if the stdout log thread was started
+    /// with another format.
+    fn try_build(mut self, exclusive_stdout: bool) -> Result<LogConfig, ConfigError> {
+        if let Some(template) = self.name_template.take() {
+            let name = render_template(&template, |variable| self.name_variable(variable))?;
+            self.name = Some(name);
+        }
+        if self.escape_newlines && matches!(self.max_width, Some((_, WrapPolicy::Wrap))) {
+            return Err(ConfigError::Conflict("escape_newlines and with_max_width wrapping lines"));
+        }
+        if self.fallback_file.is_some() {
+            if self.pending_log_file.is_some() {
+                return Err(ConfigError::Conflict("with_fallback_file and with_log_file_retry"));
+            }
+            if self.log_file.as_ref().is_some_and(|log_file| !log_file.has_path()) {
+                return Err(ConfigError::Conflict("with_fallback_file and with_log_file_handle"));
+            }
+        }
+        // Installs the logger even when the built config does not inherit from the global one
+        LazyLock::force(&GLOBAL_LOG_CONFIG);
+        let (format, file_format) = self.formats();
+        let sender_stdout = self.stdout_sender(&format, exclusive_stdout)?;
+        let Self { log_file, pending_log_file, trace_file, fallback_file, no_file, verify_writes, progress_on_stderr, log_level, name, middlewares, writers, samplers, escape_control_chars, strict_names, post_shutdown, file_schedule, error_backtraces, session_separator, name_from_target, stats_by_name, error_summary, stdout_capacity, channel, .. } = self;
+        let name = match name {
+            Some(name) if strict_names && sanitize_name(&name) != name => return Err(ConfigError::InvalidName(name)),
+            name => name.as_deref().map(|name| sanitize_name(name).into()),
+        };
+        let max_level = if trace_file.is_some() { LevelFilter::Trace } else { log_level };
+        raise_max_level(max_level);
+        let sender_trace = trace_file.map(|trace_file| Arc::new(spawn_log_thread(trace_file, file_format.clone(), channel)));
+        let separator = session_separator.then(|| format!("===== new session {} pid={} =====", file_format.clock.now().format("%Y-%m-%d %H:%M:%S"), std::process::id()));
         let sender_file = if no_file {
             None
-        } else if let Some(log_file) = log_file {
-            let sender = spawn_log_thread(log_file);
-            Some(Arc::new(sender))
+        } else if let Some(mut log_file) = log_file {
+            log_file.verify_writes = verify_writes;
+            if let Some(separator) = separator.filter(|_| log_file.mode() == FileMode::Append) {
+                log_file.regular(&separator);
+            }
+            let sender = match fallback_file {
+                Some(fallback_file) => spawn_log_thread(FallbackLogFile::new(log_file, fallback_file), file_format, channel),
+                None => spawn_log_thread(log_file, file_format, channel),
+            };
+            Some(Arc::new(sender.with_stats_by_name(stats_by_name)))
+        } else if let Some(mut log_file) = pending_log_file {
+            log_file.verify_writes = verify_writes;
+            if let Some(separator) = separator {
+                log_file.regular(&separator);
+            }
+            Some(Arc::new(spawn_log_thread(log_file, file_format, channel).with_stats_by_name(stats_by_name)))
         } else {
             GLOBAL_LOG_CONFIG.read().unwrap().sender_file.clone()
         };
-        let sender_stdout = if no_stdout {None} else {
-            GLOBAL_LOG_CONFIG.read().unwrap().sender_stdout.clone()
+        let sender_progress = if progress_on_stderr {
+            let global_sender = GLOBAL_LOG_CONFIG.read().unwrap().sender_progress.clone();
+            Some(global_sender.unwrap_or_else(|| Arc::new(spawn_log_thread(LogStderr::new(std::io::stderr()).leave_on_finish(format.leave_on_finish).max_redraws_per_second(format.max_redraws_per_second), format.clone(), channel).with_capacity(stdout_capacity))))
+        } else {
+            None
         };
-        LogConfig {
+        Ok(LogConfig {
             sender_file,
+            sender_trace,
             sender_stdout,
+            lazy_stdout: None,
+            sender_progress,
+            sender_writers: writers.into_iter().map(|writer| Arc::new(spawn_log_thread(writer, format.clone(), channel))).collect(),
+            writer_options: (format.clone(), channel),
             name,
             level: log_level,
-        }
+            middlewares: (!middlewares.is_empty()).then(|| middlewares.into()),
+            samplers,
+            escape_control_chars,
+            post_shutdown,
+            clock: format.clock.clone(),
+            shut_down: false,
+            file_schedule,
+            error_backtraces,
+            name_from_target,
+            trace_capture: true,
+            error_summary: error_summary.map(|top_n| Arc::new(ErrorSummary::new(top_n))),
+        })
     }
 
-    /// Sets a log file.
-    pub fn with_log_file<P: AsRef<Path>>(self, path: P) -> Result<Self,std::io::Error> {
-        Ok(Self { log_file: Some(LogFile::new(path)?), ..self })
+    /// Sets a log file, appending to its content if it exists.
+    pub fn with_log_file<P: AsRef<Path>>(self, path: P) -> Result<Self,ConfigError> {
+        self.with_log_file_mode(path, FileMode::Append)
+    }
+    /// Sets a log file, starting from an empty file on each run.
+    pub fn with_log_file_truncate<P: AsRef<Path>>(self, path: P) -> Result<Self,ConfigError> {
+        self.with_log_file_mode(path, FileMode::Truncate)
+    }
+    /// Sets a log file, its existing content being handled according to `mode`.
+    /// With [`FileMode::CreateNew`], an existing file is a [`ConfigError::FileExists`] error.
+    pub fn with_log_file_mode<P: AsRef<Path>>(self, path: P, mode: FileMode) -> Result<Self,ConfigError> {
+        Ok(Self { log_file: Some(open_log_file(path, mode)?), pending_log_file: None, ..self })
+    }
+    /// Sets a log file from a file already opened for writing, e.g. when the process cannot open
+    /// files itself, appending to its existing content. It is not reopened by
+    /// [`LoggerHandle::rotate_now`].
+    pub fn with_log_file_handle(self, file: std::fs::File) -> Result<Self,ConfigError> {
+        let log_file = LogFile::from_file(file).map_err(|err| ConfigError::io(err, "<file handle>"))?;
+        Ok(Self { log_file: Some(log_file), pending_log_file: None, ..self })
+    }
+    /// Writes the Trace messages to a file of their own, appending to its content if it exists,
+    /// whatever the maximum level. They are not written to the other sinks.
+    pub fn with_trace_file<P: AsRef<Path>>(self, path: P) -> Result<Self,ConfigError> {
+        Ok(Self { trace_file: Some(open_log_file(path, FileMode::Append)?), ..self })
+    }
+    /// Sets a log file whose path may not be available yet, e.g. on a volume mounted after startup.
+    /// If the file cannot be opened because the path is not found or not accessible, the opening is
+    /// retried in the background `attempts` times, waiting `backoff` then doubling it between attempts.
+    /// Messages are buffered in memory meanwhile, and dropped if every attempt fails.
+    pub fn with_log_file_retry<P: AsRef<Path>>(self, path: P, attempts: usize, backoff: Duration) -> Result<Self,ConfigError> {
+        let pending_log_file = PendingLogFile::new(&path, attempts, backoff).map_err(|err| ConfigError::io(err, path.as_ref()))?;
+        Ok(Self { log_file: None, pending_log_file: Some(pending_log_file), ..self })
+    }
+    /// Writes to `path`, e.g. on a local tmpfs, when writes to the log file set by
+    /// [`ConfigBuilder::with_log_file`] keep failing, switching back to the log file once it is
+    /// writable again. Each switch is marked by a line in the fallback file. The primary log file
+    /// must be opened from a path: building with [`ConfigBuilder::with_log_file_handle`] or
+    /// [`ConfigBuilder::with_log_file_retry`] as well is a [`ConfigError::Conflict`] error.
+    pub fn with_fallback_file<P: AsRef<Path>>(self, path: P) -> Self {
+        Self { fallback_file: Some(path.as_ref().into()), ..self }
     }
     /// Maybe sets a log file.
-    pub fn maybe_with_log_file<P: AsRef<Path>>(self, path: Option<P>) -> Result<Self,std::io::Error> {
-        Ok(Self { log_file: path.map(|p| LogFile::new(p)).transpose()? , ..self })
+    pub fn maybe_with_log_file<P: AsRef<Path>>(self, path: Option<P>) -> Result<Self,ConfigError> {
+        Ok(Self { log_file: path.map(|path| open_log_file(path, FileMode::Append)).transpose()?, pending_log_file: None, ..self })
+    }
+    /// Reads back the regions written to the log file on each flush, when the file is closed and
+    /// every 64 KiB, and rewrites those altered in the meantime (e.g. truncated by another process).
+    /// This doubles the file I/O, so it is meant for critical audit logs only.
+    pub fn verify_writes(self, yes: bool) -> Self {
+        Self { verify_writes: yes, ..self }
     }
     /// Ignore stdout logging
     pub fn no_stdout(self) -> Self {
@@ -190,27 +740,238 @@ impl ConfigBuilder {
     pub fn with_stdout(self, yes: bool) -> Self {
         Self { no_stdout: !yes, ..self }
     }
+    /// Maximum number of messages waiting to be written to the terminal, 10 000 by default. When the
+    /// terminal stops reading (e.g. a paused `less`), regular messages beyond it are dropped from
+    /// the console output instead of piling up in memory, the file keeping every message. `None`
+    /// lets them pile up.
+    pub fn with_stdout_capacity(self, capacity: Option<usize>) -> Self {
+        Self { stdout_capacity: capacity, ..self }
+    }
+    /// Channel between the logging threads and the log threads, unbounded by default. A bounded
+    /// or rendezvous channel makes logging block while a log thread is behind, trading the latency
+    /// of the callers for a bounded memory use.
+    pub fn with_channel(self, channel: ChannelConfig) -> Self {
+        Self { channel, ..self }
+    }
+    /// Draw progress bars on stderr, keeping regular logs on stdout.
+    pub fn with_progress_on_stderr(self) -> Self {
+        Self { progress_on_stderr: true, ..self }
+    }
+    /// Whether the line of a finished progress bar stays on the terminal (the default),
+    /// or is cleared and overwritten by the next log line.
+    pub fn leave_progress_on_finish(self, yes: bool) -> Self {
+        Self { leave_on_finish: yes, ..self }
+    }
+    /// Caps the number of progress redraws per second on terminals, across all bars (e.g. 30).
+    /// Updates coming faster are coalesced, keeping the latest frame of each bar.
+    pub fn with_max_progress_redraws(self, per_second: u32) -> Self {
+        Self { max_redraws_per_second: Some(per_second), ..self }
+    }
+    /// Whether progress lines in the log file are prefixed with the `[time name level]` header
+    /// of regular lines, off by default. The header keeps the time at which the bar first appeared.
+    pub fn with_progress_headers(self, yes: bool) -> Self {
+        Self { progress_headers: yes, ..self }
+    }
+    /// Prints the terminal logs through an indicatif `MultiProgress`, suspending its bars for each
+    /// regular line and drawing progress lines as bars of it, so that logs and indicatif bars
+    /// share the terminal without corrupting each other. The thread writing to stdout draws
+    /// through `multi` from then on, for every configuration.
+    #[cfg(feature = "indicatif")]
+    pub fn with_multi_progress(self, multi: indicatif::MultiProgress) -> Self {
+        Self { multi_progress: Some(multi), ..self }
+    }
     /// Ignore file logging
     pub fn no_file(self) -> Self {
         Self { no_file: true, ..self }
     }
-    /// Sets a log name
-    pub fn with_name(self, name: &str) -> Self {
-        Self { name: Some(name.into()), ..self }
+    /// Adds a middleware applied to regular messages, after the previously added ones.
+    /// It can rewrite any field of the message, or drop it by returning `None`. Middlewares run on
+    /// the log thread of each sink rather than on the logging thread, once per sink.
+    pub fn with_middleware<M>(mut self, middleware: M) -> Self
+    where M: Fn(LogMessage) -> Option<LogMessage> + Send + Sync + 'static {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+    /// Adds a writer receiving every message, handled by its own log thread with the terminal
+    /// format of the configuration, e.g. a [`MemoryWriter`].
+    pub fn with_writer<W: LogWriter + Send + 'static>(mut self, writer: W) -> Self {
+        self.writers.push(Box::new(writer));
+        self
+    }
+    /// Sets the maximum log level
+    pub fn with_level(self, log_level: LevelFilter) -> Self {
+        Self { log_level, ..self }
+    }
+    /// Sets the maximum level from its name, e.g. read from a config file or an environment variable.
+    /// Names are case-insensitive: `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub fn with_level_str(self, level: &str) -> Result<Self,ConfigError> {
+        let log_level = level.trim().parse().map_err(|_| ConfigError::InvalidLevel(level.into()))?;
+        Ok(Self { log_level, ..self })
+    }
+    /// Samples the Debug and Trace messages of the targets starting with `target_prefix`,
+    /// keeping a `ratio` of them. Sampling is counter-based: the first message is kept,
+    /// then one out of `round(1 / ratio)`. Kept lines are suffixed with a `sampled=1/N` field.
+    /// Warn and above are never sampled.
+    pub fn sample(mut self, target_prefix: &str, ratio: f64) -> Self {
+        self.samplers.push(Arc::new(Sampler::new(target_prefix, ratio)));
+        self
+    }
+    /// Renders levels as numeric codes: 1 for ERROR, 2 for WARN, 3 for INFO, 4 for DEBUG and 5 for TRACE.
+    pub fn with_numeric_levels(self) -> Self {
+        Self { numeric_levels: true, ..self }
+    }
+    /// Sets the layout of regular lines, e.g. [`LogFormat::Json`] for one JSON object per line.
+    pub fn with_format(self, log_format: LogFormat) -> Self {
+        Self { log_format, ..self }
+    }
+    /// Sets the layout of regular lines on the terminal, overriding [`ConfigBuilder::with_format`].
+    pub fn with_stdout_format(self, log_format: LogFormat) -> Self {
+        Self { stdout_format: Some(log_format), ..self }
+    }
+    /// Sets the layout of regular lines in the log file, overriding [`ConfigBuilder::with_format`].
+    pub fn with_file_format(self, log_format: LogFormat) -> Self {
+        Self { file_format: Some(log_format), ..self }
+    }
+    /// Whether the control characters of regular messages, except newlines and tabs, are escaped
+    /// as `\xNN`, so that a message cannot rewrite the terminal or forge log lines. Off by default:
+    /// messages are written as logged, names being escaped in any case.
+    pub fn with_escaped_control_chars(self, yes: bool) -> Self {
+        Self { escape_control_chars: yes, ..self }
+    }
+    /// Appends a backtrace of the call site to the Error messages, one indented line per frame.
+    /// Capturing is expensive, and only happens if enabled by `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE`.
+    pub fn with_error_backtraces(self) -> Self {
+        Self { error_backtraces: true, ..self }
+    }
+    /// Counts the occurrences of the Error and Warn messages, a distinct message text being counted
+    /// separately, and logs the `top_n` most frequent ones with their count when the configuration
+    /// is dropped: when the global one is shut down or replaced, or at exit after
+    /// [`LoggerHandle::leak`], and when a local one is replaced or its thread exits.
+    pub fn with_error_summary(self, top_n: usize) -> Self {
+        Self { error_summary: Some(top_n), ..self }
+    }
+    /// Starts a log file opened in append mode with a `===== new session <time> pid=<pid> =====` line,
+    /// marking where each run begins. Global configurations inheriting the file of a previous one do not.
+    pub fn with_session_separator(self) -> Self {
+        Self { session_separator: true, ..self }
+    }
+    /// Limits text lines to `width` columns, longer lines being cut or wrapped according to `policy`.
+    /// Progress lines are always cut, so that they can be redrawn in place. JSON lines are left as is.
+    pub fn with_max_width(self, width: usize, policy: WrapPolicy) -> Self {
+        Self { max_width: Some((width, policy)), ..self }
+    }
+    /// Escapes the newlines, carriage returns and tabs of the file lines as `\n`, `\r` and `\t`,
+    /// and backslashes as `\\`, so that each record takes exactly one line, e.g. for line-based log
+    /// shippers. The terminal is left as is, and JSON lines are escaped anyway. Wrapping lines with
+    /// [`ConfigBuilder::with_max_width`] as well is a [`ConfigError::Conflict`] error, whatever the
+    /// order of the calls.
+    pub fn escape_newlines(self, yes: bool) -> Result<Self,ConfigError> {
+        if yes && matches!(self.max_width, Some((_, WrapPolicy::Wrap))) {
+            return Err(ConfigError::Conflict("escape_newlines and with_max_width wrapping lines"));
+        }
+        Ok(Self { escape_newlines: yes, ..self })
+    }
+    /// Sets the source of the timestamps of log lines, `Utc::now` by default, e.g. a fixed clock in tests.
+    pub fn with_clock(self, now: impl Fn() -> DateTime<Utc> + Send + Sync + 'static) -> Self {
+        Self { clock: Clock::new(now), ..self }
+    }
+    /// Restricts the level of the file sink by time of day, e.g. to `Warn` from 22:00 to 06:00 to reduce
+    /// disk writes overnight, the other sinks keeping the level of the configuration. The time at which
+    /// a message is logged decides, in local time, and overlapping ranges resolve to the most restrictive level.
+    pub fn with_schedule(self, schedule: Vec<(TimeRange, LevelFilter)>) -> Self {
+        Self { file_schedule: Schedule(schedule), ..self }
+    }
+    /// Sets what happens to the messages logged after [`LoggerHandle::shutdown`], or that a
+    /// log thread can no longer take. They are written directly to stderr by default.
+    pub fn post_shutdown(self, post_shutdown: PostShutdown) -> Self {
+        Self { post_shutdown, ..self }
+    }
+    /// Sets a log name, e.g. a string, a number or an id. Control characters and `]` are escaped as
+    /// `\xNN` when the configuration is built, so that the name cannot break the `[time name level]`
+    /// header, unless [`ConfigBuilder::strict_names`] rejects them.
+    pub fn with_name(self, name: impl fmt::Display) -> Self {
+        Self { name: Some(name.to_string()), name_template: None, ..self }
+    }
+    /// Rejects the names with control characters or `]`, set directly or rendered from a template,
+    /// instead of escaping them: a [`ConfigError::InvalidName`] error of the fallible
+    /// initializations, a panic otherwise.
+    pub fn strict_names(self) -> Self {
+        Self { strict_names: true, ..self }
     }
-    /// Maybe sets a log name
+    /// Sets a log name from a template resolved when the configuration is built, e.g.
+    /// `worker-{index}@{hostname}`: `{variable}` placeholders are replaced by the values set with
+    /// [`ConfigBuilder::bind`], or by the built-ins `{hostname}`, `{pid}` and `{thread}` (the name of
+    /// the building thread, or its id).
+    /// `{{` and `}}` stand for literal braces. An unbound variable or an unpaired brace is a
+    /// [`ConfigError::UnboundVariable`] or [`ConfigError::InvalidTemplate`] error of the fallible
+    /// initializations, a panic otherwise.
+    pub fn with_name_template(self, template: &str) -> Self {
+        Self { name_template: Some(template.into()), ..self }
+    }
+    /// Binds a variable of the name template, see [`ConfigBuilder::with_name_template`].
+    pub fn bind(mut self, variable: &str, value: impl fmt::Display) -> Self {
+        self.name_bindings.push((variable.into(), value.to_string()));
+        self
+    }
+    /// Paints the name and level of every line with `color`, e.g. to tell apart the services
+    /// sharing a terminal, instead of a color per level. Ignored like other colors when `NO_COLOR`
+    /// is set or stdout is not a terminal.
+    pub fn with_accent_color(self, color: Color) -> Self {
+        Self { accent_color: Some(color), ..self }
+    }
+    /// Whether records logged without a name are named after the crate of their target, e.g. `hyper`
+    /// for a record of `hyper::client`. Names set by [`ConfigBuilder::with_name`] or a scope always win.
+    pub fn name_from_target(self, yes: bool) -> Self {
+        Self { name_from_target: yes, ..self }
+    }
+    /// Whether the volume written to the log file is accounted per logger name, for
+    /// [`LoggerHandle::stats_by_name`]. Disabled by default, sparing a lookup per line.
+    pub fn with_stats_by_name(self, yes: bool) -> Self {
+        Self { stats_by_name: yes, ..self }
+    }
+    /// Maybe sets a log name, escaped as in [`ConfigBuilder::with_name`].
     pub fn maybe_with_name(self, name: Option<&str>) -> Self {
-        Self { name: name.map(String::from), ..self }
+        Self { name: name.map(Into::into), ..self }
     }
     // Initalize the logger globaly
     pub fn init_global(self) {
-        *GLOBAL_LOG_CONFIG.write().unwrap()=self.build();
+        self.init_global_with_handle();
+    }
+    /// Initializes the logger globally, returning a handle to reconfigure it at runtime.
+    /// Panics on an invalid configuration, e.g. an unbound variable of the name template, which
+    /// [`ConfigBuilder::try_init_global`] returns as an error instead.
+    pub fn init_global_with_handle(self) -> LoggerHandle {
+        Self::set_global(self.build())
     }
-    // Initalize the logger for the current thread
+    /// Like [`ConfigBuilder::init_global_with_handle`], but returns the errors of an invalid
+    /// configuration rather than panicking, and fails with [`ConfigError::StdoutInUse`] when the
+    /// thread writing to stdout was started by a configuration with another format (e.g. other
+    /// levels or colors), rather than sharing it with the terminal settings of that one.
+    pub fn try_init_global(self) -> Result<LoggerHandle, ConfigError> {
+        Ok(Self::set_global(self.try_build(true)?))
+    }
+    fn set_global(config: LogConfig) -> LoggerHandle {
+        let previous = std::mem::replace(&mut *GLOBAL_LOG_CONFIG.write().unwrap(), config);
+        drop(previous);
+        GLOBAL_INITIALIZED.store(true, Ordering::SeqCst);
+        let generation = GLOBAL_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+        LoggerHandle { generation: Some(generation) }
+    }
+    /// Initializes the logger for the current thread. Panics on an invalid configuration, as
+    /// [`ConfigBuilder::init_global_with_handle`] does.
     pub fn init_local(self) {
+        Self::set_local(self.build());
+    }
+    /// Like [`ConfigBuilder::init_local`], but fails with [`ConfigError::StdoutInUse`] as
+    /// [`ConfigBuilder::try_init_global`] does.
+    pub fn try_init_local(self) -> Result<(), ConfigError> {
+        Self::set_local(self.try_build(true)?);
+        Ok(())
+    }
+    fn set_local(config: LogConfig) {
         LOG_CONFIG.with(|logger_config| {
             let mut logger_config = logger_config.borrow_mut();
-            *logger_config = Some(self.build());
+            *logger_config = Some(config);
         });
     }
 }
@@ -218,4 +979,1381 @@ impl ConfigBuilder {
 /// Returns a default ConfigBuilder for configuring the logger.
 pub fn logger_config() -> ConfigBuilder {
     ConfigBuilder::default()
-}
\ No newline at end of file
+}
+
+/// Logs a message with structured fields through the current configuration, bypassing the `log` facade.
+/// Fields are rendered as ` key=value` after the message in text, or as keys of the object in JSON.
+/// See the [`event!`] macro for a more convenient syntax.
+pub fn log_event(level: Level, message: &str, fields: Vec<(String, String)>) {
+    LOG_CONFIG.with(|local_config| {
+        let local_config = local_config.borrow();
+        let global_config = GLOBAL_LOG_CONFIG.read().unwrap();
+        let config = local_config.as_ref().unwrap_or(&global_config);
+        if let Some(destination) = config.route(level, Destination::All) {
+            config.send_to(LogMessage { level, name: config.name.clone(), message: message.into(), fields }, destination);
+        }
+    });
+}
+
+/// Logs `err` at the error level, each cause of its chain on an indented `caused by:` line
+/// below its message, e.g. for an error built with `anyhow::Context`.
+#[cfg(feature = "anyhow")]
+pub fn log_error(err: &anyhow::Error) {
+    let mut message = err.to_string();
+    for cause in err.chain().skip(1) {
+        write!(message, "\n    caused by: {cause}").unwrap();
+    }
+    log::error!("{message}");
+}
+
+/// Logs a message with structured fields, e.g. `event!(Level::Info, "user logged in", id = 42, role = "admin")`.
+/// Field values are rendered with their `Display` implementation.
+#[macro_export]
+macro_rules! event {
+    ($level:expr, $message:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::log_event($level, $message, vec![$((stringify!($key).to_string(), $value.to_string())),*])
+    };
+}
+
+/// Runs `f` with the logger name of the current thread set to `name` (escaped as in
+/// [`ConfigBuilder::with_name`]), restoring the previous name afterwards, even if `f` panics.
+/// A thread without a local configuration uses a copy of the global one during `f`.
+pub fn with_scoped_name<R>(name: &str, f: impl FnOnce() -> R) -> R {
+    /// Previous name of the local configuration, or `None` if there was no local configuration.
+    struct RestoreName(Option<Option<Arc<str>>>);
+    impl Drop for RestoreName {
+        fn drop(&mut self) {
+            LOG_CONFIG.with(|local_config| {
+                let mut local_config = local_config.borrow_mut();
+                match self.0.take() {
+                    Some(name) => if let Some(config) = local_config.as_mut() {
+                        config.name = name;
+                    },
+                    None => *local_config = None,
+                }
+            });
+        }
+    }
+    let name = Some(sanitize_name(name).into());
+    let previous = LOG_CONFIG.with(|local_config| {
+        let mut local_config = local_config.borrow_mut();
+        if let Some(config) = local_config.as_mut() {
+            Some(std::mem::replace(&mut config.name, name))
+        } else {
+            let mut config = GLOBAL_LOG_CONFIG.read().unwrap().clone();
+            config.name = name;
+            *local_config = Some(config);
+            None
+        }
+    });
+    let _restore = RestoreName(previous);
+    f()
+}
+
+/// Maximum level raised for a scope, see [`with_boosted_level`].
+pub struct BoostedLevel {
+    level: LevelFilter,
+}
+
+/// Raises the maximum level of the current thread to `level` within [`BoostedLevel::scope`],
+/// e.g. `with_boosted_level(Level::Trace).scope(|| suspect_function())`, other threads being
+/// unaffected. A level below the current maximum level is ignored.
+pub fn with_boosted_level(level: Level) -> BoostedLevel {
+    BoostedLevel { level: level.to_level_filter() }
+}
+
+impl BoostedLevel {
+    /// Runs `f` with the boosted level, restoring the previous level afterwards, even if `f` panics.
+    /// A thread without a local configuration uses a copy of the global one during `f`.
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        /// Previous level of the local configuration, or `None` if there was no local configuration.
+        struct RestoreLevel(Option<LevelFilter>);
+        impl Drop for RestoreLevel {
+            fn drop(&mut self) {
+                LOG_CONFIG.with(|local_config| {
+                    let mut local_config = local_config.borrow_mut();
+                    match self.0.take() {
+                        Some(level) => if let Some(config) = local_config.as_mut() {
+                            config.level = level;
+                        },
+                        None => *local_config = None,
+                    }
+                });
+            }
+        }
+        let _raised = raise_max_level_scoped(self.level);
+        let previous = LOG_CONFIG.with(|local_config| {
+            let mut local_config = local_config.borrow_mut();
+            if let Some(config) = local_config.as_mut() {
+                let level = config.level.max(self.level);
+                Some(std::mem::replace(&mut config.level, level))
+            } else {
+                let mut config = GLOBAL_LOG_CONFIG.read().unwrap().clone();
+                config.level = config.level.max(self.level);
+                *local_config = Some(config);
+                None
+            }
+        });
+        let _restore = RestoreLevel(previous);
+        f()
+    }
+}
+
+/// Clonable handles on the sinks of the configuration active where [`current_senders`] was called,
+/// for writing to them without going through the `log` facade.
+#[derive(Clone)]
+pub struct ScopeSenders {
+    config: LogConfig,
+}
+
+impl ScopeSenders {
+    /// Sends a regular message, named after the configuration when `name` is `None`.
+    /// Messages above the maximum level of the configuration are ignored.
+    pub fn send_regular(&self, level: Level, name: Option<&str>, message: &str) {
+        if PAUSES.load(Ordering::Relaxed) > 0 && !ends_bar(message) {
+            SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if level <= self.config.level {
+            let name = name.map(|name| sanitize_name(name).into()).or_else(|| self.config.name.clone());
+            self.config.send(LogMessage { level, name, message: message.into(), fields: Vec::new() });
+        }
+    }
+
+    /// Draws a frame of the progress bar `id`, on its own line the first time.
+    pub fn send_progress(&self, id: Uuid, text: &str) {
+        self.send_regular(Level::Info, None, &format!("___PROGRESS___{id}___{text}"));
+    }
+
+    /// Marks the progress bar `id` as finished, its line being left as is.
+    pub fn finish_progress(&self, id: Uuid) {
+        self.send_progress(id, "FINISHED");
+    }
+
+    /// Sends a regular Info message on behalf of the progress bar `id`: terminals print it above
+    /// the bar, which is drawn again below it.
+    pub fn send_println(&self, id: Uuid, message: &str) {
+        self.send_regular(Level::Info, None, &format!("___PRINTLN___{id}___{message}"));
+    }
+
+    /// Name of the configuration, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.config.name.as_deref()
+    }
+}
+
+/// Returns handles on the sinks of the current thread's configuration, or of the global one.
+pub fn current_senders() -> ScopeSenders {
+    LOG_CONFIG.with(|local_config| {
+        let local_config = local_config.borrow();
+        let global_config = GLOBAL_LOG_CONFIG.read().unwrap();
+        ScopeSenders { config: local_config.as_ref().unwrap_or(&global_config).clone() }
+    })
+}
+
+/// Handle on the global configuration, returned by [`ensure_initialized`] and
+/// [`ConfigBuilder::init_global_with_handle`].
+///
+/// Reconfiguration methods apply to the threads without a local configuration.
+pub struct LoggerHandle {
+    /// Generation of the global configuration created by this handle, if any.
+    generation: Option<usize>,
+}
+
+impl LoggerHandle {
+    /// Whether the global configuration was created by this handle.
+    pub fn owns_config(&self) -> bool {
+        self.generation.is_some()
+    }
+
+    /// Sets the maximum log level.
+    pub fn set_level(&self, level: LevelFilter) {
+        GLOBAL_LOG_CONFIG.write().unwrap().level = level;
+        raise_max_level(level);
+    }
+
+    /// Replaces the log file, the previous one being closed once every pending message is written.
+    pub fn set_file<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let previous = GLOBAL_LOG_CONFIG.read().unwrap().sender_file.as_ref().map(|sender| (sender.format.clone(), sender.channel));
+        let (format, channel) = previous.unwrap_or_default();
+        let sender = Arc::new(spawn_log_thread(open_log_file(path, FileMode::Append)?, format, channel));
+        let previous = GLOBAL_LOG_CONFIG.write().unwrap().sender_file.replace(sender);
+        drop(previous);
+        Ok(())
+    }
+
+    /// Starts or stops writing the Trace messages to the trace file of the global configuration,
+    /// see [`ConfigBuilder::with_trace_file`]. While stopped, they are dropped before being formatted.
+    pub fn enable_trace_capture(&self, yes: bool) {
+        GLOBAL_LOG_CONFIG.write().unwrap().trace_capture = yes;
+    }
+
+    /// Blocks until every message logged so far is written and flushed by the global sinks.
+    pub fn flush(&self) {
+        let global_config = GLOBAL_LOG_CONFIG.read().unwrap();
+        for sender in global_config.senders() {
+            sender.flush();
+        }
+    }
+
+    /// Like [`LoggerHandle::flush`], for the global log file only, the other sinks being left
+    /// alone, e.g. at a checkpoint where only the file matters.
+    pub fn flush_file(&self) {
+        if let Some(sender) = &GLOBAL_LOG_CONFIG.read().unwrap().sender_file {
+            sender.flush();
+        }
+    }
+
+    /// Like [`LoggerHandle::flush`], for the global stdout sink only.
+    pub fn flush_stdout(&self) {
+        if let Some(sender) = GLOBAL_LOG_CONFIG.read().unwrap().started_stdout() {
+            sender.flush();
+        }
+    }
+
+    /// Volume of the regular lines written to the global log file per logger name, by decreasing number
+    /// of bytes, if enabled with [`ConfigBuilder::with_stats_by_name`]. Lines logged before a call to
+    /// [`LoggerHandle::flush`] may not be accounted yet.
+    pub fn stats_by_name(&self) -> Vec<(Option<String>, SinkStats)> {
+        GLOBAL_LOG_CONFIG.read().unwrap().sender_file.as_ref().map(|sender| sender.stats_by_name()).unwrap_or_default()
+    }
+
+    /// Adds a writer receiving every message, handled by its own log thread and formatted like the
+    /// writers of [`ConfigBuilder::with_writer`].
+    pub fn add_writer<W: LogWriter + Send + 'static>(&self, writer: W) {
+        let (format, channel) = GLOBAL_LOG_CONFIG.read().unwrap().writer_options.clone();
+        let sender = Arc::new(spawn_log_thread(writer, format, channel));
+        GLOBAL_LOG_CONFIG.write().unwrap().sender_writers.push(sender);
+    }
+
+    /// Reopens the global log file, e.g. at a deploy boundary after moving the current file away:
+    /// the following messages are written to a new file at the same path.
+    pub fn rotate_now(&self) {
+        if let Some(sender) = &GLOBAL_LOG_CONFIG.read().unwrap().sender_file {
+            sender.reopen();
+        }
+    }
+
+    /// Gives up the handle for daemons that never return from their run loop, or exit through
+    /// `std::process::exit`, after registering a flush of the global sinks at process exit.
+    ///
+    /// The flush is best-effort and bounded to 2 seconds. Nothing is flushed when the process
+    /// is killed by a signal (SIGKILL, or SIGTERM/SIGINT without handler) or aborts. The flush is
+    /// only registered on unix targets: elsewhere, the messages still pending at exit are lost.
+    pub fn leak(self) {
+        #[cfg(unix)]
+        EXIT_FLUSH_REGISTERED.call_once(|| {
+            // SAFETY: `flush_at_exit` is a plain function taking no arguments, as `atexit` requires.
+            unsafe { libc::atexit(flush_at_exit) };
+        });
+    }
+
+    /// Shuts down the global configuration if it was created by this handle and not replaced since,
+    /// writing every pending message. Sinks created by someone else are never touched.
+    ///
+    /// Messages logged afterwards, until the next global initialization, are handled according to
+    /// [`ConfigBuilder::post_shutdown`].
+    pub fn shutdown(self) {
+        let Some(generation) = self.generation else {
+            return;
+        };
+        let mut global_config = GLOBAL_LOG_CONFIG.write().unwrap();
+        if GLOBAL_GENERATION.load(Ordering::SeqCst) == generation {
+            let mut config = global_config.clone();
+            config.sender_file = None;
+            config.sender_trace = None;
+            config.sender_stdout = None;
+            config.lazy_stdout = None;
+            config.sender_progress = None;
+            config.sender_writers.clear();
+            config.error_summary = None;
+            config.shut_down = true;
+            let previous = std::mem::replace(&mut *global_config, config);
+            GLOBAL_INITIALIZED.store(false, Ordering::SeqCst);
+            GLOBAL_GENERATION.fetch_add(1, Ordering::SeqCst);
+            drop(global_config);
+            // Logs the error summary, unless a scope still holds a clone of the configuration
+            drop(previous);
+        }
+    }
+}
+
+/// Initializes the global configuration with `default_builder` unless an application already did.
+///
+/// Meant for libraries ensuring some logging exists without clobbering the host application's
+/// setup: a later `init_global` from the application still takes precedence.
+pub fn ensure_initialized(default_builder: ConfigBuilder) -> LoggerHandle {
+    if GLOBAL_INITIALIZED.swap(true, Ordering::SeqCst) {
+        return LoggerHandle { generation: None };
+    }
+    let config = default_builder.build();
+    let previous = std::mem::replace(&mut *GLOBAL_LOG_CONFIG.write().unwrap(), config);
+    drop(previous);
+    let generation = GLOBAL_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    LoggerHandle { generation: Some(generation) }
+}
+
+/// Number of live [`PauseGuard`]s, logging being suspended while it is not zero.
+static PAUSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of records dropped during the current pause.
+static SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+
+/// Suspends every log record, progress updates included, until the returned guard is dropped,
+/// e.g. around a latency-critical section. While paused, a record costs a relaxed load, without
+/// being formatted. The ends of the progress bars, sent through the configuration rather than
+/// the `log` facade, still go through, so that no bar is left open in the sinks. Pauses nest:
+/// logging resumes when the last guard is dropped, which logs the number of records dropped
+/// meanwhile.
+pub fn pause() -> PauseGuard {
+    PAUSES.fetch_add(1, Ordering::SeqCst);
+    PauseGuard { _private: () }
+}
+
+/// Guard returned by [`pause`], resuming logging when the last one is dropped.
+#[must_use = "logging resumes as soon as the guard is dropped"]
+pub struct PauseGuard {
+    _private: (),
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if PAUSES.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let suppressed = SUPPRESSED.swap(0, Ordering::Relaxed);
+            if suppressed > 0 {
+                log::info!("logging resumed, {suppressed} records suppressed while paused");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_progress_on_stderr() {
+    if std::env::var_os("MTLOG_TEST_PROGRESS_ON_STDERR_CHILD").is_some() {
+        logger_config()
+            .no_file()
+            .with_progress_on_stderr()
+            .init_local();
+        let uuid = uuid::Uuid::new_v4();
+        log::info!("___PROGRESS___{uuid}___frame 1");
+        log::info!("Hello, world!");
+        log::info!("___PROGRESS___{uuid}___frame 2");
+        log::info!("___PROGRESS___{uuid}___FINISHED");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_progress_on_stderr"])
+        .env("MTLOG_TEST_PROGRESS_ON_STDERR_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let (stdout, stderr) = (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap());
+    assert!(stdout.ends_with("Hello, world!\n"));
+    assert!(!stdout.contains("frame"));
+    assert_eq!(stderr, "frame 1\n\x1B[1A\rframe 2\x1B[1B\r");
+}
+
+#[test]
+fn test_broken_pipe_stdout() {
+    struct BrokenPipe;
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+    let path = "/tmp/test_broken_pipe_stdout.log";
+    std::fs::remove_file(path).ok();
+    let mut config = logger_config()
+        .with_log_file(path).unwrap()
+        .build();
+    let stdout = Arc::new(spawn_log_thread(LogStdout::new(BrokenPipe), FormatOptions::default(), ChannelConfig::default()));
+    config.sender_stdout = Some(stdout.clone());
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    let uuid = uuid::Uuid::new_v4();
+    log::info!("first");
+    log::info!("___PROGRESS___{uuid}___frame");
+    log::info!("___PROGRESS___{uuid}___FINISHED");
+    log::info!("second");
+    stdout.flush();
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 3);
+    assert!(content.ends_with("] second\n"));
+}
+
+#[test]
+fn test_log_file_handle() {
+    let path = "/tmp/test_log_file_handle.log";
+    std::fs::write(path, "previous run\n").unwrap();
+    let file = std::fs::File::options().write(true).open(path).unwrap();
+    logger_config()
+        .with_log_file_handle(file).unwrap()
+        .no_stdout()
+        .init_local();
+    log::info!("written through the handle");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "previous run");
+    assert!(lines[1].ends_with(" INFO] written through the handle"));
+}
+
+#[test]
+fn test_fallback_file_conflicts() {
+    let file = std::fs::File::create("/tmp/test_fallback_file_conflicts.log").unwrap();
+    let config = logger_config().with_log_file_handle(file).unwrap().with_fallback_file("/tmp/test_fallback_file_conflicts_fallback.log");
+    assert!(matches!(config.no_stdout().try_init_local(), Err(ConfigError::Conflict(_))));
+    let config = logger_config().with_fallback_file("/tmp/test_fallback_file_conflicts_fallback.log");
+    let config = config.with_log_file_retry("/tmp/test_fallback_file_conflicts.log", 3, Duration::from_millis(10)).unwrap();
+    assert!(matches!(config.no_stdout().try_init_local(), Err(ConfigError::Conflict(_))));
+}
+
+#[test]
+fn test_log_file_modes() {
+    let path = "/tmp/test_log_file_modes_builder.log";
+    std::fs::write(path, "previous run\n").unwrap();
+    assert!(matches!(logger_config().with_log_file_mode(path, FileMode::CreateNew), Err(ConfigError::FileExists(existing)) if existing == Path::new(path)));
+    logger_config()
+        .with_log_file_truncate(path).unwrap()
+        .no_stdout()
+        .init_local();
+    log::info!("fresh start");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    assert!(content.ends_with("] fresh start\n"));
+}
+
+#[test]
+fn test_session_separator() {
+    let path = "/tmp/test_session_separator.log";
+    std::fs::remove_file(path).ok();
+    for run in ["first run", "second run"] {
+        logger_config()
+            .with_log_file(path).unwrap()
+            .with_session_separator()
+            .no_stdout()
+            .init_local();
+        log::info!("{run}");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+    }
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    let separator = format!(" pid={} =====", std::process::id());
+    assert!(lines[0].starts_with("===== new session ") && lines[0].ends_with(&separator));
+    assert!(lines[1].ends_with("] first run"));
+    assert!(lines[2].starts_with("===== new session ") && lines[2].ends_with(&separator));
+    assert!(lines[3].ends_with("] second run"));
+}
+
+#[cfg(feature = "max_level_info")]
+#[test]
+fn test_static_max_level() {
+    let evaluated = AtomicBool::new(false);
+    log::debug!("{}", evaluated.swap(true, Ordering::SeqCst));
+    assert!(!evaluated.load(Ordering::SeqCst));
+    assert!(!MTLogger.enabled(&log::Metadata::builder().level(Level::Debug).build()));
+    assert!(MTLogger.enabled(&log::Metadata::builder().level(Level::Info).build()));
+}
+
+#[test]
+fn test_level_str() {
+    for (name, level) in [("off", LevelFilter::Off), ("ERROR", LevelFilter::Error), ("Warn", LevelFilter::Warn), (" info ", LevelFilter::Info), ("debug", LevelFilter::Debug), ("TRACE", LevelFilter::Trace)] {
+        assert_eq!(logger_config().with_level_str(name).unwrap().log_level, level);
+    }
+    for name in ["", "warning", "verbose", "3"] {
+        assert!(matches!(logger_config().with_level_str(name), Err(ConfigError::InvalidLevel(invalid)) if invalid == name));
+    }
+}
+
+#[test]
+fn test_sink_order() {
+    let sender = || Arc::new(spawn_log_thread(MemoryWriter::default(), FormatOptions::default(), ChannelConfig::default()));
+    let (file, writer, stdout, progress) = (sender(), sender(), sender(), sender());
+    let mut config = logger_config().no_stdout().no_file().build();
+    config.sender_file = Some(file.clone());
+    config.sender_writers = vec![writer.clone()];
+    config.sender_stdout = Some(stdout.clone());
+    config.sender_progress = Some(progress.clone());
+    let ids = |senders: Vec<&Arc<LogSender>>| senders.into_iter().map(Arc::as_ptr).collect::<Vec<_>>();
+    assert_eq!(ids(config.message_senders(false, true, Destination::All).collect()), ids(vec![&file, &writer, &stdout]));
+    assert_eq!(ids(config.message_senders(true, true, Destination::All).collect()), ids(vec![&file, &writer, &progress]));
+    assert_eq!(ids(config.message_senders(false, false, Destination::All).collect()), ids(vec![&writer, &stdout]));
+    assert_eq!(ids(config.message_senders(false, true, Destination::File).collect()), ids(vec![&file]));
+    assert_eq!(ids(config.message_senders(true, true, Destination::Stdout).collect()), ids(vec![&progress]));
+    assert_eq!(ids(config.senders().collect()), ids(vec![&file, &writer, &stdout, &progress]));
+}
+
+#[test]
+fn test_middlewares() {
+    let path = "/tmp/test_middlewares.log";
+    std::fs::remove_file(path).ok();
+    let caller = std::thread::current().id();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_middleware(move |mut log_message| {
+            assert_ne!(std::thread::current().id(), caller);
+            if log_message.message.starts_with("public") {
+                log_message.name = Some("rewritten".into());
+            }
+            Some(log_message)
+        })
+        .with_middleware(|log_message| (log_message.name.as_deref() == Some("rewritten")).then_some(log_message))
+        .init_local();
+    log::info!("public message");
+    log::info!("private message");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    assert!(content.ends_with(" rewritten INFO] public message\n"));
+}
+
+#[test]
+fn test_numeric_levels() {
+    let path = "/tmp/test_numeric_levels.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_numeric_levels()
+        .init_local();
+    log::info!("numeric level");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    assert!(std::fs::read_to_string(path).unwrap().ends_with(" 3] numeric level\n"));
+}
+
+#[test]
+fn test_event() {
+    let path = "/tmp/test_event.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_name("events")
+        .init_local();
+    event!(Level::Info, "msg", foo = 1, bar = "x");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    assert!(std::fs::read_to_string(path).unwrap().ends_with(" events INFO] msg foo=1 bar=x\n"));
+
+    let path = "/tmp/test_event_json.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_name("events")
+        .with_format(LogFormat::Json)
+        .init_local();
+    event!(Level::Warn, "msg", foo = 1, bar = "x");
+    log::info!("plain");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(r#","level":"WARN","name":"events","message":"msg","foo":1,"bar":"x"}"#));
+    assert!(lines[1].ends_with(r#","level":"INFO","name":"events","message":"plain"}"#));
+}
+
+#[test]
+fn test_per_sink_format() {
+    use log_writer::SharedBuffer;
+    let path = "/tmp/test_per_sink_format.log";
+    std::fs::remove_file(path).ok();
+    let builder = logger_config()
+        .with_log_file(path).unwrap()
+        .with_stdout_format(LogFormat::Text)
+        .with_file_format(LogFormat::Json);
+    let stdout = SharedBuffer::default();
+    let stdout_sender = spawn_log_thread(LogStdout::new(stdout.clone()), builder.formats().0, ChannelConfig::default());
+    let mut config = builder.build();
+    config.sender_stdout = Some(Arc::new(stdout_sender));
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    event!(Level::Info, "same record", id = 7);
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let file = std::fs::read_to_string(path).unwrap();
+    assert!(file.starts_with("{\"time\":\""));
+    assert_eq!(file.lines().count(), 1);
+    assert!(file.trim_end().ends_with(r#","level":"INFO","message":"same record","id":7}"#));
+    assert!(stdout.contents().ends_with("] same record id=7\n"));
+}
+
+#[test]
+fn test_file_schedule() {
+    use log_writer::SharedBuffer;
+    let path = "/tmp/test_file_schedule.log";
+    std::fs::remove_file(path).ok();
+    let builder = logger_config()
+        .with_log_file(path).unwrap()
+        .with_schedule(vec![
+            (TimeRange::new((0, 0), (12, 0)), LevelFilter::Warn),
+            (TimeRange::new((12, 0), (0, 0)), LevelFilter::Warn),
+        ]);
+    let stdout = SharedBuffer::default();
+    let stdout_sender = spawn_log_thread(LogStdout::new(stdout.clone()), builder.formats().0, ChannelConfig::default());
+    let mut config = builder.build();
+    config.sender_stdout = Some(Arc::new(stdout_sender));
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    log::info!("routine");
+    log::warn!("unusual");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let file = std::fs::read_to_string(path).unwrap();
+    assert_eq!(file.lines().count(), 1);
+    assert!(file.ends_with("] unusual\n"));
+    assert_eq!(stdout.contents().lines().count(), 2);
+}
+
+#[test]
+fn test_destination() {
+    use log_writer::SharedBuffer;
+    let path = "/tmp/test_destination.log";
+    let builder = logger_config().with_log_file_truncate(path).unwrap();
+    let stdout = SharedBuffer::default();
+    let stdout_sender = spawn_log_thread(LogStdout::new(stdout.clone()), builder.formats().0, ChannelConfig::default());
+    let mut config = builder.build();
+    config.sender_stdout = Some(Arc::new(stdout_sender));
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    log::info!("everywhere");
+    log::info!(mtlog_dest = "file"; "file only");
+    log::info!(mtlog_dest = "stdout"; "stdout only");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let file = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = file.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("] everywhere"));
+    assert!(lines[1].ends_with("] file only"));
+    let stdout = stdout.contents();
+    assert!(stdout.contains("] everywhere\n") && stdout.contains("] stdout only\n"));
+    assert!(!stdout.contains("file only"));
+}
+
+#[test]
+fn test_sanitized_names() {
+    let path = "/tmp/test_sanitized_names.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_name("evil\n[2024-01-01 admin] \x1b[31m")
+        .with_escaped_control_chars(true)
+        .init_local();
+    log::info!("first \x1b[2J\r");
+    log::info!("second\twith tab");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(r" evil\x0a[2024-01-01 admin\x5d \x1b[31m INFO] first \x1b[2J\x0d"));
+    assert!(lines[1].ends_with("] second\twith tab"));
+    let err = logger_config().no_stdout().no_file().with_name("evil\n").strict_names().try_init_local().unwrap_err();
+    assert_eq!(err.to_string(), r#"invalid logger name: "evil\n", control characters and `]` are not allowed"#);
+    let err = logger_config().no_stdout().no_file().with_name_template("{name}").bind("name", "a]b").strict_names().try_init_local().unwrap_err();
+    assert!(matches!(err, ConfigError::InvalidName(name) if name == "a]b"));
+}
+
+#[test]
+fn test_name_display() {
+    enum Worker { Indexer }
+    impl fmt::Display for Worker {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("indexer")
+        }
+    }
+    let name = |builder: ConfigBuilder| builder.no_stdout().no_file().build().name.clone();
+    assert_eq!(name(logger_config().with_name(Worker::Indexer)).as_deref(), Some("indexer"));
+    assert_eq!(name(logger_config().with_name(42)).as_deref(), Some("42"));
+    let id = uuid::Uuid::new_v4();
+    assert_eq!(name(logger_config().with_name(id)).as_deref(), Some(&*id.to_string()));
+    assert_eq!(name(logger_config().with_name(String::from("a]b"))).as_deref(), Some("a\\x5db"));
+}
+
+#[test]
+fn test_name_from_target() {
+    let path = "/tmp/test_name_from_target.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .name_from_target(true)
+        .init_local();
+    log::info!(target: "storage::disk", "first");
+    log::info!(target: "network", "second");
+    with_scoped_name("explicit", || log::info!(target: "network", "third"));
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert!(lines[0].ends_with(" storage INFO] first"));
+    assert!(lines[1].ends_with(" network INFO] second"));
+    assert!(lines[2].ends_with(" explicit INFO] third"));
+}
+
+#[test]
+fn test_boosted_level() {
+    let path = "/tmp/test_boosted_level.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    log::trace!("before");
+    with_boosted_level(Level::Trace).scope(|| {
+        log::trace!("inside");
+        with_boosted_level(Level::Debug).scope(|| log::trace!("nested"));
+    });
+    std::thread::spawn(|| log::trace!("other thread")).join().unwrap();
+    log::trace!("after");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" TRACE] inside"));
+    assert!(lines[1].ends_with(" TRACE] nested"));
+}
+
+#[test]
+fn test_escape_newlines() {
+    let path = "/tmp/test_escape_newlines.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .escape_newlines(true).unwrap()
+        .init_local();
+    log::error!("thread 'main' panicked at src/main.rs:3:5:\nboom\n\tat main");
+    log::warn!(r"unable to open C:\new\table.csv");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(r"ERROR] thread 'main' panicked at src/main.rs:3:5:\nboom\n\tat main"));
+    assert!(lines[1].ends_with(r"WARN] unable to open C:\\new\\table.csv"));
+    assert!(matches!(logger_config().with_max_width(80, WrapPolicy::Wrap).escape_newlines(true), Err(ConfigError::Conflict(_))));
+    let config = logger_config().no_stdout().no_file().escape_newlines(true).unwrap().with_max_width(80, WrapPolicy::Wrap);
+    assert!(matches!(config.try_init_local(), Err(ConfigError::Conflict(_))));
+}
+
+#[test]
+fn test_scoped_name() {
+    let path = "/tmp/test_scoped_name.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_name("server")
+        .init_local();
+    log::info!("before");
+    let answer = with_scoped_name("GET /users", || {
+        log::info!("inside");
+        with_scoped_name("db", || log::info!("nested"));
+        42
+    });
+    log::info!("after");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    assert_eq!(answer, 42);
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert!(lines[0].ends_with(" server INFO] before"));
+    assert!(lines[1].ends_with(" GET /users INFO] inside"));
+    assert!(lines[2].ends_with(" db INFO] nested"));
+    assert!(lines[3].ends_with(" server INFO] after"));
+}
+
+#[test]
+fn test_sampling() {
+    let path = "/tmp/test_sampling.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_level(LevelFilter::Debug)
+        .sample("packets", 0.001)
+        .init_local();
+    for i in 0..10_000 {
+        log::debug!(target: "packets::decode", "packet {i}");
+    }
+    log::warn!(target: "packets::decode", "never sampled");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 11);
+    assert!(lines[0].ends_with("packet 0 sampled=1/1000"));
+    assert!(lines[9].ends_with("packet 9000 sampled=1/1000"));
+    assert!(lines[10].ends_with("never sampled"));
+}
+
+/// Serializes the tests replacing the global configuration.
+#[cfg(test)]
+static GLOBAL_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[test]
+fn test_ensure_initialized() {
+    let _lock = GLOBAL_TEST_LOCK.lock().unwrap();
+    let app_file = |path: &str| {
+        std::fs::remove_file(path).ok();
+        logger_config().with_log_file(path).unwrap().no_stdout()
+    };
+    // Application first
+    GLOBAL_INITIALIZED.store(false, Ordering::SeqCst);
+    app_file("/tmp/test_ensure_initialized_application_first.log").init_global();
+    let handle = ensure_initialized(logger_config().no_stdout());
+    assert!(!handle.owns_config());
+    handle.shutdown();
+    log::info!("application first");
+    // Library first
+    GLOBAL_INITIALIZED.store(false, Ordering::SeqCst);
+    let handle = ensure_initialized(logger_config().no_stdout());
+    assert!(handle.owns_config());
+    app_file("/tmp/test_ensure_initialized_library_first.log").init_global();
+    handle.shutdown();
+    log::info!("library first");
+    logger_config().no_file().init_global();
+    for order in ["application first", "library first"] {
+        let path = format!("/tmp/test_ensure_initialized_{}.log", order.replace(' ', "_"));
+        assert!(std::fs::read_to_string(path).unwrap().ends_with(&format!("{order}\n")));
+    }
+}
+
+#[test]
+fn test_logger_handle() {
+    use log_writer::SharedBuffer;
+    let _lock = GLOBAL_TEST_LOCK.lock().unwrap();
+    let (path, new_path) = ("/tmp/test_logger_handle.log", "/tmp/test_logger_handle_new.log");
+    std::fs::remove_file(path).ok();
+    std::fs::remove_file(new_path).ok();
+    let handle = logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_numeric_levels()
+        .init_global_with_handle();
+    handle.set_level(LevelFilter::Warn);
+    log::info!("filtered");
+    log::warn!("kept");
+    handle.flush();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    assert!(content.ends_with("kept\n"));
+    let output = SharedBuffer::default();
+    handle.add_writer(LogStdout::new(output.clone()));
+    handle.set_file(new_path).unwrap();
+    log::warn!("moved");
+    handle.flush();
+    assert!(std::fs::read_to_string(path).unwrap().ends_with("kept\n"));
+    assert!(std::fs::read_to_string(new_path).unwrap().ends_with("moved\n"));
+    assert!(output.contents().ends_with("moved\n"));
+    assert!(output.contents().contains(" 2]"));
+    std::fs::rename(new_path, path).unwrap();
+    handle.rotate_now();
+    log::warn!("rotated");
+    handle.flush();
+    assert!(std::fs::read_to_string(path).unwrap().ends_with("moved\n"));
+    assert_eq!(std::fs::read_to_string(new_path).unwrap().lines().count(), 1);
+    logger_config().init_global();
+}
+
+#[test]
+fn test_flush_file() {
+    /// Stdout writer blocked while its gate is held, recording whether it was flushed.
+    struct Gated {
+        gate: Arc<std::sync::Mutex<()>>,
+        flushed: Arc<AtomicBool>,
+    }
+    impl LogWriter for Gated {
+        fn regular(&mut self, _line: &str) {
+            drop(self.gate.lock().unwrap());
+        }
+        fn progress(&mut self, _line: &str, _id: Uuid) {}
+        fn finished(&mut self, _id: Uuid) {}
+        fn flush(&mut self) {
+            self.flushed.store(true, Ordering::SeqCst);
+        }
+    }
+    let _lock = GLOBAL_TEST_LOCK.lock().unwrap();
+    let path = "/tmp/test_flush_file.log";
+    std::fs::remove_file(path).ok();
+    let (gate, flushed) = (Arc::new(std::sync::Mutex::new(())), Arc::new(AtomicBool::new(false)));
+    let closed = gate.lock().unwrap();
+    let mut config = logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .build();
+    config.sender_stdout = Some(Arc::new(spawn_log_thread(Gated { gate: gate.clone(), flushed: flushed.clone() }, FormatOptions::default(), ChannelConfig::default())));
+    let handle = ConfigBuilder::set_global(config);
+    for i in 0..1000 {
+        log::info!("line {i}");
+    }
+    handle.flush_file();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1000);
+    assert!(content.ends_with("line 999\n"));
+    assert!(!flushed.load(Ordering::SeqCst));
+    drop(closed);
+    handle.flush_stdout();
+    assert!(flushed.load(Ordering::SeqCst));
+    logger_config().init_global();
+}
+
+#[test]
+fn test_trace_file() {
+    let _lock = GLOBAL_TEST_LOCK.lock().unwrap();
+    let (path, trace_path) = ("/tmp/test_trace_file.log", "/tmp/test_trace_file_trace.log");
+    std::fs::remove_file(path).ok();
+    std::fs::remove_file(trace_path).ok();
+    let handle = logger_config()
+        .with_log_file(path).unwrap()
+        .with_trace_file(trace_path).unwrap()
+        .no_stdout()
+        .with_level(LevelFilter::Trace)
+        .init_global_with_handle();
+    handle.enable_trace_capture(false);
+    log::trace!("before");
+    handle.enable_trace_capture(true);
+    log::trace!("window");
+    log::debug!("main");
+    handle.enable_trace_capture(false);
+    log::trace!("after");
+    handle.flush();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    assert!(content.ends_with(" DEBUG] main\n"));
+    let trace_content = std::fs::read_to_string(trace_path).unwrap();
+    assert_eq!(trace_content.lines().count(), 1);
+    assert!(trace_content.ends_with(" TRACE] window\n"));
+    logger_config().init_global();
+}
+
+#[test]
+fn test_pause() {
+    let path = "/tmp/test_pause.log";
+    if std::env::var_os("MTLOG_TEST_PAUSE_CHILD").is_some() {
+        logger_config()
+            .with_log_file_truncate(path).unwrap()
+            .no_stdout()
+            .init_local();
+        let sent = uuid::Uuid::new_v4();
+        log::info!("before");
+        current_senders().send_progress(sent, "sent 1/2");
+        let outer = pause();
+        log::info!("dropped");
+        let inner = pause();
+        log::error!("dropped");
+        // Finished bars are closed, their next frames taking new lines
+        current_senders().finish_progress(sent);
+        drop(inner);
+        log::info!("still dropped");
+        assert!(!log::logger().enabled(&log::Metadata::builder().level(Level::Error).build()));
+        drop(outer);
+        log::info!("after");
+        current_senders().send_progress(sent, "sent 2/2");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_pause"])
+        .env("MTLOG_TEST_PAUSE_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].ends_with(" INFO] before"));
+    assert_eq!(lines[1], "sent 1/2");
+    assert!(lines[2].ends_with(" INFO] logging resumed, 3 records suppressed while paused"));
+    assert!(lines[3].ends_with(" INFO] after"));
+    assert_eq!(lines[4], "sent 2/2");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_leak_flushes_at_exit() {
+    let path = "/tmp/test_leak_flushes_at_exit.log";
+    if std::env::var_os("MTLOG_TEST_LEAK_CHILD").is_some() {
+        logger_config()
+            .with_log_file(path).unwrap()
+            .no_stdout()
+            .init_global_with_handle()
+            .leak();
+        for i in 0..1000 {
+            log::info!("line {i}");
+        }
+        log::info!("final line");
+        std::process::exit(0);
+    }
+    std::fs::remove_file(path).ok();
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_leak_flushes_at_exit"])
+        .env("MTLOG_TEST_LEAK_CHILD", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let content = std::fs::read_to_string(path).unwrap();
+    assert_eq!(content.lines().count(), 1001);
+    assert!(content.ends_with("final line\n"));
+}
+
+#[cfg(feature = "anyhow")]
+#[test]
+fn test_log_error() {
+    use anyhow::Context;
+    let path = "/tmp/test_log_error.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .init_local();
+    let err = Err::<(), _>(anyhow::anyhow!("permission denied")).context("unable to read config.toml").context("startup failed").unwrap_err();
+    log_error(&err);
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(" ERROR] startup failed"));
+    assert_eq!(lines[1], "    caused by: unable to read config.toml");
+    assert_eq!(lines[2], "    caused by: permission denied");
+}
+
+#[test]
+fn test_error_backtraces() {
+    let path = "/tmp/test_error_backtraces.log";
+    if std::env::var_os("MTLOG_TEST_BACKTRACE_CHILD").is_some() {
+        logger_config()
+            .with_log_file_truncate(path).unwrap()
+            .no_stdout()
+            .with_error_backtraces()
+            .init_local();
+        log::error!("failure");
+        log::info!("fine");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+        std::process::exit(0);
+    }
+    let run_child = |backtrace| std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_error_backtraces"])
+        .env("MTLOG_TEST_BACKTRACE_CHILD", "1")
+        .env("RUST_LIB_BACKTRACE", backtrace)
+        .status()
+        .unwrap();
+    assert!(run_child("1").success());
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert!(lines[0].ends_with("] failure"));
+    assert!(lines.len() > 3);
+    assert!(lines[1..lines.len() - 1].iter().all(|line| line.starts_with("    ")));
+    assert!(content.contains("test_error_backtraces"));
+    assert!(lines[lines.len() - 1].ends_with("] fine"));
+    assert!(run_child("0").success());
+    assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn test_exclusive_stdout() {
+    if std::env::var_os("MTLOG_TEST_EXCLUSIVE_STDOUT_CHILD").is_some() {
+        logger_config().init_local();
+        assert!(logger_config().try_init_local().is_ok());
+        assert!(matches!(logger_config().with_numeric_levels().try_init_local(), Err(ConfigError::StdoutInUse)));
+        assert!(logger_config().with_numeric_levels().no_stdout().try_init_local().is_ok());
+        // Another format shares the running thread, a second one failing a debug assertion
+        logger_config().init_local();
+        log::info!("text levels");
+        logger_config().with_numeric_levels().init_local();
+        log::info!("numeric levels");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_exclusive_stdout"])
+        .env("MTLOG_TEST_EXCLUSIVE_STDOUT_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().filter(|line| line.ends_with(" levels")).collect();
+    assert!(lines[0].ends_with(" INFO] text levels"));
+    assert!(lines[1].ends_with(" 3] numeric levels"));
+}
+
+#[test]
+fn test_lazy_stdout() {
+    if std::env::var_os("MTLOG_TEST_LAZY_STDOUT_CHILD").is_some() {
+        logger_config()
+            .with_log_file_truncate("/tmp/test_lazy_stdout.log").unwrap()
+            .no_stdout()
+            .init_global();
+        log::info!("file only");
+        assert!(!utils::stdout_running());
+        logger_config().no_file().init_global();
+        log::info!("on stdout");
+        assert!(utils::stdout_running());
+        logger_config().no_stdout().no_file().init_global();
+        assert!(!utils::stdout_running());
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_lazy_stdout"])
+        .env("MTLOG_TEST_LAZY_STDOUT_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("] on stdout"));
+}
+
+#[test]
+fn test_post_shutdown() {
+    struct LogsOnDrop;
+    impl Drop for LogsOnDrop {
+        fn drop(&mut self) {
+            log::warn!("dropped after shutdown");
+            log::warn!("___underscored after shutdown");
+        }
+    }
+    if let Some(policy) = std::env::var_os("MTLOG_TEST_POST_SHUTDOWN_CHILD") {
+        let post_shutdown = if policy == "discard" { PostShutdown::Discard } else { PostShutdown::Stderr };
+        let handle = logger_config()
+            .no_stdout()
+            .post_shutdown(post_shutdown)
+            .with_clock(|| DateTime::from_timestamp(0, 0).unwrap())
+            .init_global_with_handle();
+        let value = LogsOnDrop;
+        handle.shutdown();
+        drop(value);
+        std::process::exit(0);
+    }
+    for (policy, expected) in [("stderr", true), ("discard", false)] {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test_post_shutdown"])
+            .env("MTLOG_TEST_POST_SHUTDOWN_CHILD", policy)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(stderr.contains("[1970-01-01T00:00:00.000 WARN] dropped after shutdown\n"), expected);
+        assert_eq!(stderr.contains("[1970-01-01T00:00:00.000 WARN] ___underscored after shutdown\n"), expected);
+    }
+}
+
+#[test]
+fn test_writer_format() {
+    struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+    impl LogWriter for Recorder {
+        fn regular(&mut self, line: &str) {
+            self.0.lock().unwrap().push(line.into());
+        }
+        fn progress(&mut self, _line: &str, _id: Uuid) {}
+        fn finished(&mut self, _id: Uuid) {}
+    }
+    let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+    logger_config()
+        .no_file()
+        .no_stdout()
+        .with_writer(Recorder(lines.clone()))
+        .with_numeric_levels()
+        .with_clock(|| DateTime::from_timestamp(0, 0).unwrap())
+        .init_local();
+    log::warn!("formatted as configured");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    assert_eq!(*lines.lock().unwrap(), ["[1970-01-01T00:00:00.000 2] formatted as configured"]);
+}
+
+#[test]
+fn test_failed_sink_fallback() {
+    if std::env::var_os("MTLOG_TEST_FAILED_SINK_CHILD").is_some() {
+        let path = "/tmp/test_failed_sink_fallback.log";
+        let mut writer = spawn_log_thread(MemoryWriter::default(), FormatOptions::default(), ChannelConfig::default());
+        writer.shutdown();
+        let mut config = logger_config().with_log_file_truncate(path).unwrap().no_stdout().build();
+        config.sender_writers.push(Arc::new(writer));
+        ConfigBuilder::set_local(config);
+        log::info!("written to the file only");
+        LOG_CONFIG.with(|config| config.borrow_mut().take());
+        assert!(std::fs::read_to_string(path).unwrap().ends_with("] written to the file only\n"));
+        let mut writer = spawn_log_thread(MemoryWriter::default(), FormatOptions::default(), ChannelConfig::default());
+        writer.shutdown();
+        let mut config = logger_config().no_file().no_stdout().build();
+        config.sender_writers.push(Arc::new(writer));
+        ConfigBuilder::set_local(config);
+        log::info!("written nowhere");
+        std::process::exit(0);
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_failed_sink_fallback"])
+        .env("MTLOG_TEST_FAILED_SINK_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // Only the message that no sink took is written to stderr
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("written to the file only"));
+    assert!(stderr.contains(" INFO] written nowhere\n"));
+}
+
+#[test]
+fn test_name_template() {
+    let base = || logger_config().no_stdout().no_file().with_name_template("worker-{index}@{hostname}-{pid} {{{thread}}}");
+    let names = std::thread::scope(|s| {
+        (0..2).map(|index| {
+            let builder = base().bind("index", index);
+            std::thread::Builder::new().name(format!("thread-{index}")).spawn_scoped(s, || builder.build().name.clone().unwrap().to_string()).unwrap()
+        }).collect::<Vec<_>>().into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+    });
+    let host = utils::hostname();
+    let pid = std::process::id();
+    assert_eq!(names, [format!("worker-0@{host}-{pid} {{thread-0}}"), format!("worker-1@{host}-{pid} {{thread-1}}")]);
+    assert_eq!(logger_config().with_name_template("{index}").bind("index", 1).bind("index", 2).build().name.as_deref(), Some("2"));
+    assert_eq!(logger_config().with_name_template("{index}").with_name("fixed").build().name.as_deref(), Some("fixed"));
+    let err = logger_config().no_stdout().no_file().with_name_template("worker-{index}").try_init_local().unwrap_err();
+    assert!(matches!(err, ConfigError::UnboundVariable(variable) if variable == "index"));
+}
+
+#[test]
+fn test_error_summary() {
+    let _lock = GLOBAL_TEST_LOCK.lock().unwrap();
+    let path = "/tmp/test_error_summary.log";
+    std::fs::remove_file(path).ok();
+    let handle = logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_error_summary(1)
+        .init_global_with_handle();
+    for _ in 0..5 {
+        log::error!("connection refused");
+    }
+    log::warn!("slow query");
+    log::info!("not counted");
+    handle.shutdown();
+    logger_config().init_global();
+    let content = std::fs::read_to_string(path).unwrap();
+    let summary = content.split_once(" INFO] most frequent errors and warnings:\n").unwrap().1;
+    assert_eq!(summary, "    5x ERROR connection refused\n    1x other messages\n");
+    // Without an explicit shutdown, when the global configuration is replaced
+    std::fs::remove_file(path).ok();
+    logger_config().with_log_file(path).unwrap().no_stdout().with_error_summary(1).init_global();
+    log::warn!("disk almost full");
+    logger_config().no_stdout().no_file().init_global();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert!(content.ends_with(" INFO] most frequent errors and warnings:\n    1x WARN disk almost full\n"));
+    // A local configuration, when its thread exits
+    let path = "/tmp/test_error_summary_local.log";
+    std::fs::remove_file(path).ok();
+    std::thread::spawn(move || {
+        logger_config().with_log_file(path).unwrap().no_stdout().with_error_summary(1).init_local();
+        log::error!("timeout");
+    }).join().unwrap();
+    let content = std::fs::read_to_string(path).unwrap();
+    assert!(content.ends_with(" INFO] most frequent errors and warnings:\n    1x ERROR timeout\n"));
+}
+
+#[test]
+fn test_test_mode() {
+    if std::env::var_os("MTLOG_TEST_MODE_CHILD").is_some() {
+        let path = "/tmp/test_test_mode.log";
+        logger_config()
+            .with_log_file_truncate(path).unwrap()
+            .no_stdout()
+            .init_local();
+        for i in 0..100 {
+            log::info!("line {i}");
+            // Written by the time the record returns
+            assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), i + 1);
+        }
+        let output = log_writer::SharedBuffer::default();
+        let mut log_stdout = LogStdout::new(output.clone());
+        let uuid = uuid::Uuid::new_v4();
+        for i in 0..10 {
+            log_stdout.progress(&format!("frame {i}"), uuid);
+        }
+        assert_eq!(output.contents().matches("frame ").count(), 10);
+        std::process::exit(0);
+    }
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_test_mode"])
+        .env("MTLOG_TEST_MODE_CHILD", "1")
+        .env("MTLOG_TEST_MODE", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_blocked_stdout() {
+    use std::{io::Read, os::fd::FromRawFd};
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors of the pipe
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    // SAFETY: the descriptors were just opened and are owned by these files only
+    let (mut reader, writer) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+    let path = "/tmp/test_blocked_stdout.log";
+    let mut config = logger_config()
+        .with_log_file_truncate(path).unwrap()
+        .no_stdout()
+        .build();
+    let stdout = Arc::new(spawn_log_thread(LogStdout::new(writer), FormatOptions::default(), ChannelConfig::default()).with_capacity(Some(100)));
+    config.sender_stdout = Some(stdout.clone());
+    let file = config.sender_file.clone().unwrap();
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    // Nobody reads the pipe: the stdout thread blocks once its buffer is full
+    for i in 0..2000 {
+        log::info!("line {i} {}", "x".repeat(100));
+    }
+    assert!(stdout.pending() <= 100);
+    file.flush();
+    assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 2000);
+    let drain = std::thread::spawn(move || {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        output
+    });
+    stdout.flush();
+    log::info!("caught up");
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    drop((stdout, file));
+    let output = drain.join().unwrap();
+    assert!(output.lines().count() < 2000);
+    assert!(output.trim_end().ends_with("] caught up"));
+    // Each time the thread catches up, the number of messages dropped meanwhile is logged
+    let dropped = output.lines()
+        .filter_map(|line| line.strip_suffix(" messages dropped while the log thread was blocked"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+        .sum::<usize>();
+    assert!(dropped > 0);
+    assert_eq!(output.lines().filter(|line| line.contains(" line ")).count() + dropped, 2000);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_blocked_stdout_progress() {
+    use std::{io::Read, os::fd::FromRawFd};
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors of the pipe
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    // SAFETY: the descriptors were just opened and are owned by these files only
+    let (mut reader, writer) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+    let mut config = logger_config().no_stdout().no_file().build();
+    let stdout = Arc::new(spawn_log_thread(LogStdout::new(writer), FormatOptions::default(), ChannelConfig::default()).with_capacity(Some(100)));
+    config.sender_stdout = Some(stdout.clone());
+    LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = Some(config));
+    // Nobody reads the pipe: the stdout thread blocks once its buffer is full
+    for i in 0..2000 {
+        log::info!("line {i} {}", "x".repeat(100));
+    }
+    let uuid = Uuid::new_v4();
+    for i in 0..2000 {
+        log::info!("___PROGRESS___{uuid}___frame {i}");
+    }
+    // Only the latest frame is queued with the end of the bar
+    log::info!("___PROGRESS___{uuid}___FINISHED");
+    assert!(stdout.pending() <= 102);
+    let drain = std::thread::spawn(move || {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        output
+    });
+    LOG_CONFIG.with(|config| config.borrow_mut().take());
+    drop(stdout);
+    let output = drain.join().unwrap();
+    assert!(output.contains("frame 1999"));
+}
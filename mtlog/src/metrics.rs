@@ -0,0 +1,246 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering}, Arc, LazyLock, Mutex, Once}, time::{Duration, Instant}};
+
+use log::Level;
+
+#[derive(Clone,Copy,PartialEq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+/// A registered metric and its value at the previous report.
+struct Metric {
+    name: String,
+    kind: MetricKind,
+    value: Arc<AtomicI64>,
+    reported: i64,
+}
+
+/// Metrics reported by [`tick_metrics`], in registration order.
+static METRICS: LazyLock<Mutex<Vec<Metric>>> = LazyLock::new(Mutex::default);
+
+/// Guards the spawning of the reporter thread.
+static REPORTER: Once = Once::new();
+
+fn register(name: &str, kind: MetricKind) -> Arc<AtomicI64> {
+    let mut metrics = METRICS.lock().unwrap();
+    if let Some(metric) = metrics.iter().find(|metric| metric.name == name && metric.kind == kind) {
+        return metric.value.clone();
+    }
+    let value = Arc::new(AtomicI64::new(0));
+    metrics.push(Metric { name: name.into(), kind, value: value.clone(), reported: 0 });
+    value
+}
+
+/// A monotonic count, reported with its rate since the previous report.
+#[derive(Clone)]
+pub struct Counter(Arc<AtomicI64>);
+
+impl Counter {
+    pub fn inc(&self, n: u64) {
+        self.0.fetch_add(n as i64, Ordering::Relaxed);
+    }
+}
+
+/// A value reported as last set.
+#[derive(Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Returns the counter registered under `name`, registering it on first use.
+pub fn counter(name: &str) -> Counter {
+    Counter(register(name, MetricKind::Counter))
+}
+
+/// Returns the gauge registered under `name`, registering it on first use.
+pub fn gauge(name: &str) -> Gauge {
+    Gauge(register(name, MetricKind::Gauge))
+}
+
+/// Volume of the regular lines written by a log thread.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct SinkStats {
+    pub lines: u64,
+    /// Bytes written, line endings included.
+    pub bytes: u64,
+}
+
+/// Maximum number of logger names accounted separately by [`NameStats`].
+const MAX_STAT_NAMES: usize = 64;
+
+/// Volume written by a log thread per logger name, accounted once enabled. Once [`MAX_STAT_NAMES`]
+/// names are accounted, lines of new names are summed under the `other` name.
+#[derive(Clone,Default)]
+pub(crate) struct NameStats(Arc<NameStatsInner>);
+
+#[derive(Default)]
+struct NameStatsInner {
+    enabled: AtomicBool,
+    by_name: Mutex<ByName>,
+}
+
+#[derive(Default)]
+struct ByName {
+    names: HashMap<Option<Arc<str>>, SinkStats>,
+    /// Volume of the names beyond the cap.
+    other: SinkStats,
+}
+
+impl NameStats {
+    pub(crate) fn enable(&self) {
+        self.0.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, name: &Option<Arc<str>>, bytes: usize) {
+        if !self.0.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut by_name = self.0.by_name.lock().unwrap();
+        let ByName { names, other } = &mut *by_name;
+        let entry = if names.len() < MAX_STAT_NAMES || names.contains_key(name) { names.entry(name.clone()).or_default() } else { other };
+        entry.lines += 1;
+        entry.bytes += bytes as u64;
+    }
+
+    /// Volume per name, by decreasing number of bytes.
+    pub(crate) fn snapshot(&self) -> Vec<(Option<String>, SinkStats)> {
+        let by_name = self.0.by_name.lock().unwrap();
+        let other = (by_name.other.lines > 0).then(|| (Some("other".to_string()), by_name.other));
+        let mut stats = by_name.names.iter().map(|(name, stats)| (name.as_deref().map(String::from), *stats)).chain(other).collect::<Vec<_>>();
+        stats.sort_by(|(name_a, a), (name_b, b)| b.bytes.cmp(&a.bytes).then_with(|| name_a.cmp(name_b)));
+        stats
+    }
+}
+
+/// Maximum number of distinct messages counted by [`ErrorSummary`].
+const MAX_SUMMARY_MESSAGES: usize = 1024;
+
+/// Occurrences of the Error and Warn messages, reported when the configuration is dropped.
+/// Once [`MAX_SUMMARY_MESSAGES`] messages are counted, new messages are only counted as untracked.
+pub(crate) struct ErrorSummary {
+    top_n: usize,
+    messages: Mutex<HashMap<Level, HashMap<String, u64>>>,
+    untracked: AtomicU64,
+}
+
+impl ErrorSummary {
+    pub(crate) fn new(top_n: usize) -> Self {
+        Self { top_n, messages: Mutex::default(), untracked: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn record(&self, level: Level, message: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        let counted = messages.values().map(HashMap::len).sum::<usize>();
+        let counts = messages.entry(level).or_default();
+        if let Some(count) = counts.get_mut(message) {
+            *count += 1;
+        } else if counted < MAX_SUMMARY_MESSAGES {
+            counts.insert(message.into(), 1);
+        } else {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders the most frequent messages by decreasing count, e.g.
+    /// `most frequent errors and warnings:\n    1204x ERROR connection refused`.
+    /// Returns `None` if no message was counted.
+    pub(crate) fn report(&self) -> Option<String> {
+        let messages = self.messages.lock().unwrap();
+        let mut messages = messages.iter()
+            .flat_map(|(level, counts)| counts.iter().map(move |(message, count)| ((level, message), count)))
+            .collect::<Vec<_>>();
+        if messages.is_empty() {
+            return None;
+        }
+        messages.sort_by(|(key_a, a), (key_b, b)| b.cmp(a).then_with(|| key_a.cmp(key_b)));
+        let mut report = String::from("most frequent errors and warnings:");
+        for ((level, message), count) in messages.iter().take(self.top_n) {
+            report.push_str(&format!("\n    {count}x {level} {message}"));
+        }
+        let others = messages.iter().skip(self.top_n).map(|(_, count)| **count).sum::<u64>() + self.untracked.load(Ordering::Relaxed);
+        if others > 0 {
+            report.push_str(&format!("\n    {others}x other messages"));
+        }
+        Some(report)
+    }
+}
+
+/// Renders the summary of every metric, e.g. `metrics: requests=1523 (+210/s) queue_depth=7`,
+/// with counter rates computed over `elapsed`. Returns `None` if no value changed since the previous summary.
+fn summarize(elapsed: Duration) -> Option<String> {
+    let mut metrics = METRICS.lock().unwrap();
+    let mut changed = false;
+    let mut line = String::from("metrics:");
+    for metric in metrics.iter_mut() {
+        let value = metric.value.load(Ordering::Relaxed);
+        let delta = value - metric.reported;
+        changed |= delta != 0;
+        metric.reported = value;
+        line.push_str(&format!(" {}={value}", metric.name));
+        if metric.kind == MetricKind::Counter && !elapsed.is_zero() {
+            line.push_str(&format!(" (+{}/s)", (delta as f64 / elapsed.as_secs_f64()).round() as i64));
+        }
+    }
+    changed.then_some(line)
+}
+
+/// Logs the summary of every metric at Info level through the current configuration, if a value
+/// changed since the previous summary. Counter rates are computed over `elapsed`.
+///
+/// Called periodically by the reporter thread of [`report_metrics`], or manually.
+pub fn tick_metrics(elapsed: Duration) {
+    if let Some(line) = summarize(elapsed) {
+        crate::current_senders().send_regular(Level::Info, None, &line);
+    }
+}
+
+/// Starts a thread calling [`tick_metrics`] every `interval`, reporting to the global configuration.
+/// Only the first call starts a thread.
+pub fn report_metrics(interval: Duration) {
+    REPORTER.call_once(|| {
+        std::thread::spawn(move || {
+            let mut last_tick = Instant::now();
+            loop {
+                std::thread::sleep(interval);
+                tick_metrics(last_tick.elapsed());
+                last_tick = Instant::now();
+            }
+        });
+    });
+}
+
+#[test]
+fn test_metrics() {
+    let requests = counter("test_requests");
+    let queue_depth = gauge("test_queue_depth");
+    summarize(Duration::ZERO);
+    requests.inc(1500);
+    requests.clone().inc(23);
+    queue_depth.set(7);
+    assert_eq!(summarize(Duration::from_secs(2)).unwrap(), "metrics: test_requests=1523 (+762/s) test_queue_depth=7");
+    assert_eq!(summarize(Duration::from_secs(1)), None);
+    counter("test_requests").inc(210);
+    assert_eq!(summarize(Duration::from_secs(1)).unwrap(), "metrics: test_requests=1733 (+210/s) test_queue_depth=7");
+}
+
+#[test]
+fn test_error_summary() {
+    let summary = ErrorSummary::new(2);
+    assert_eq!(summary.report(), None);
+    for _ in 0..3 {
+        summary.record(Level::Error, "connection refused");
+    }
+    summary.record(Level::Warn, "slow query");
+    summary.record(Level::Warn, "slow query");
+    summary.record(Level::Error, "disk full");
+    assert_eq!(summary.report().unwrap(), "most frequent errors and warnings:\n    3x ERROR connection refused\n    2x WARN slow query\n    1x other messages");
+    for i in 0..MAX_SUMMARY_MESSAGES {
+        summary.record(Level::Warn, &format!("message {i}"));
+    }
+    assert!(summary.report().unwrap().ends_with(&format!("\n    {}x other messages", MAX_SUMMARY_MESSAGES + 1)));
+}
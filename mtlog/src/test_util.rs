@@ -0,0 +1,93 @@
+//! Helpers for testing code logging through mtlog, enabled by the `test-util` feature.
+
+use std::sync::{Arc, LazyLock, Mutex};
+use log::LevelFilter;
+use uuid::Uuid;
+use crate::{schedule::Schedule, log_writer::{LogWriter, MemoryWriter}, utils::{raise_max_level_scoped, spawn_log_thread, ChannelConfig, FormatOptions}, LogConfig, PostShutdown, GLOBAL_LOG_CONFIG, LOG_CONFIG};
+
+/// Memory writer whose lines stay readable by [`capture_logs`] once moved to the log thread.
+struct CaptureWriter(Arc<Mutex<MemoryWriter>>);
+
+impl LogWriter for CaptureWriter {
+    fn regular(&mut self, line: &str) {
+        self.0.lock().unwrap().regular(line);
+    }
+    fn progress(&mut self, line: &str, id: Uuid) {
+        self.0.lock().unwrap().progress(line, id);
+    }
+    fn finished(&mut self, id: Uuid) {
+        self.0.lock().unwrap().finished(id);
+    }
+}
+
+/// Runs `f` and returns the lines it logged on the current thread, at any level, as rendered in a log file.
+///
+/// The configuration of the thread is swapped for one writing to memory only during `f`, then restored,
+/// even if `f` panics: nothing reaches the other sinks and nothing leaks into the next tests.
+/// The logger is registered with the `log` facade on first use, so this can be called any number of
+/// times, whether or not a global configuration was initialized.
+///
+/// ```rust
+/// let lines = mtlog::test_util::capture_logs(|| log::warn!("disk almost full"));
+/// assert!(lines[0].ends_with("disk almost full"));
+/// ```
+pub fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+    /// Configuration of the thread before the capture.
+    struct Restore(Option<LogConfig>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LOG_CONFIG.with(|local_config| *local_config.borrow_mut() = self.0.take());
+        }
+    }
+    LazyLock::force(&GLOBAL_LOG_CONFIG);
+    let _raised = raise_max_level_scoped(LevelFilter::Trace);
+    let lines = Arc::new(Mutex::new(MemoryWriter::default()));
+    let sender = Arc::new(spawn_log_thread(CaptureWriter(lines.clone()), FormatOptions::default(), ChannelConfig::default()));
+    let config = LogConfig {
+        sender_file: None,
+        sender_trace: None,
+        sender_stdout: None,
+        lazy_stdout: None,
+        sender_progress: None,
+        sender_writers: vec![sender.clone()],
+        writer_options: Default::default(),
+        name: None,
+        level: LevelFilter::Trace,
+        middlewares: None,
+        samplers: Vec::new(),
+        escape_control_chars: false,
+        post_shutdown: PostShutdown::Discard,
+        clock: Default::default(),
+        shut_down: false,
+        file_schedule: Schedule::default(),
+        error_backtraces: false,
+        name_from_target: false,
+        trace_capture: false,
+        error_summary: None,
+    };
+    let previous = LOG_CONFIG.with(|local_config| local_config.borrow_mut().replace(config));
+    let restore = Restore(previous);
+    f();
+    drop(restore);
+    sender.flush();
+    let lines = std::mem::take(&mut lines.lock().unwrap().lines);
+    lines
+}
+
+#[test]
+fn test_capture_logs() {
+    let lines = capture_logs(|| {
+        log::info!("first");
+        log::trace!("second");
+    });
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("] first"));
+    assert!(lines[1].ends_with("] second"));
+    let lines = capture_logs(|| {
+        log::error!("outer");
+        assert!(capture_logs(|| log::error!("inner"))[0].ends_with("] inner"));
+    });
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].ends_with("] outer"));
+    assert!(LOG_CONFIG.with(|local_config| local_config.borrow().is_none()));
+}
@@ -0,0 +1,32 @@
+use log::Level;
+use mtlog::{current_senders, logger_config};
+use uuid::Uuid;
+
+#[test]
+fn test_current_senders() {
+    let path = "/tmp/test_current_senders.log";
+    std::fs::remove_file(path).ok();
+    logger_config()
+        .with_log_file(path).unwrap()
+        .no_stdout()
+        .with_progress_headers(true)
+        .with_name("scope")
+        .init_local();
+    let senders = current_senders();
+    let id = Uuid::new_v4();
+    senders.send_progress(id, "frame 1");
+    senders.send_regular(Level::Warn, None, "regular");
+    senders.send_regular(Level::Info, Some("other"), "renamed");
+    senders.send_regular(Level::Debug, None, "filtered");
+    senders.send_progress(id, "frame 2");
+    senders.finish_progress(id);
+    drop(senders);
+    // Replacing the local configuration closes the log file
+    logger_config().no_stdout().no_file().init_local();
+    let content = std::fs::read_to_string(path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(" scope INFO] frame 2"));
+    assert!(lines[1].ends_with(" scope WARN] regular"));
+    assert!(lines[2].ends_with(" other INFO] renamed"));
+}